@@ -0,0 +1,61 @@
+#import bevy_pbr::mesh_view_bind_group
+#import bevy_pbr::mesh_struct
+
+[[group(1), binding(0)]]
+var block_textures: texture_2d_array<f32>;
+[[group(1), binding(1)]]
+var block_sampler: sampler;
+
+struct BlockMaterial {
+    alpha_cutoff: f32;
+};
+[[group(1), binding(2)]]
+var<uniform> material: BlockMaterial;
+
+[[group(2), binding(0)]]
+var<uniform> mesh: Mesh;
+
+struct Vertex {
+    [[location(0)]] position: vec3<f32>;
+    [[location(1)]] normal: vec3<f32>;
+    [[location(2)]] uv: vec2<f32>;
+    [[location(3)]] layer: u32;
+    [[location(4)]] ambient_occlusion: f32;
+    [[location(5)]] light_color: vec3<f32>;
+};
+
+struct VertexOutput {
+    [[builtin(position)]] clip_position: vec4<f32>;
+    [[location(0)]] world_normal: vec3<f32>;
+    [[location(1)]] uv: vec2<f32>;
+    [[location(2), interpolate(flat)]] layer: u32;
+    [[location(3)]] ambient_occlusion: f32;
+    [[location(4)]] light_color: vec3<f32>;
+};
+
+[[stage(vertex)]]
+fn vertex(vertex: Vertex) -> VertexOutput {
+    var out: VertexOutput;
+    out.clip_position = view.view_proj * mesh.model * vec4<f32>(vertex.position, 1.0);
+    out.world_normal = mat3x3<f32>(
+        mesh.inverse_transpose_model[0].xyz,
+        mesh.inverse_transpose_model[1].xyz,
+        mesh.inverse_transpose_model[2].xyz
+    ) * vertex.normal;
+    out.uv = vertex.uv;
+    out.layer = vertex.layer;
+    out.ambient_occlusion = vertex.ambient_occlusion;
+    out.light_color = vertex.light_color;
+    return out;
+}
+
+[[stage(fragment)]]
+fn fragment(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    let color = textureSample(block_textures, block_sampler, in.uv, i32(in.layer));
+    if (color.a < material.alpha_cutoff) {
+        discard;
+    }
+
+    let occlusion = mix(0.35, 1.0, in.ambient_occlusion);
+    return vec4<f32>(color.rgb * in.light_color * occlusion, color.a);
+}
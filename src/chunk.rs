@@ -1,15 +1,95 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+use crate::fluid::Fluid;
 use crate::key::Key;
+use crate::light::MAX_LIGHT;
+use crate::tick::ScheduledTick;
 use bevy::prelude::*;
 
 pub const CHUNK_SIZE: usize = 16;
+pub const CHUNK_VOLUME: usize = CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE;
 
 #[derive(Component)]
 pub struct Chunk<'a> {
     pub position: IVec3,
-    pub blocks: [Option<Key<'a>>; CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE],
+    pub blocks: [Option<Key<'a>>; CHUNK_VOLUME],
+    /// Extra per block data whose meaning is up to the block, such as a fluid's level.
+    pub states: [u8; CHUNK_VOLUME],
+    pub sky_light: [u8; CHUNK_VOLUME],
+    /// Red, green and blue block light.
+    pub block_light: [[u8; 3]; CHUNK_VOLUME],
+    /// Ticks scheduled for blocks in this chunk, soonest first. The server saves
+    /// them with the chunk, so they carry on when it is loaded again.
+    pub scheduled_ticks: BinaryHeap<Reverse<ScheduledTick>>,
     pub has_changed: bool,
 }
 
+impl<'a> Chunk<'a> {
+    pub fn new(position: IVec3) -> Self {
+        Chunk {
+            position,
+            blocks: [None; CHUNK_VOLUME],
+            states: [0; CHUNK_VOLUME],
+            sky_light: [0; CHUNK_VOLUME],
+            block_light: [[0; 3]; CHUNK_VOLUME],
+            scheduled_ticks: BinaryHeap::new(),
+            has_changed: true,
+        }
+    }
+
+    pub fn block(&self, position: IVec3) -> Option<Key<'a>> {
+        self.blocks[chunk_index(position)]
+    }
+
+    pub fn state(&self, position: IVec3) -> u8 {
+        self.states[chunk_index(position)]
+    }
+}
+
+/// Index into `Chunk::blocks` for a chunk local position.
+pub fn chunk_index(position: IVec3) -> usize {
+    position.x as usize * CHUNK_SIZE * CHUNK_SIZE
+        + position.y as usize * CHUNK_SIZE
+        + position.z as usize
+}
+
 pub struct Block {
     pub solid: bool,
+    /// How much light is lost passing through this block, on top of the one level
+    /// lost per block travelled. `MAX_LIGHT` stops light completely.
+    pub light_opacity: u8,
+    /// Block light level emitted by this block, from `0` to `MAX_LIGHT`.
+    pub emission: u8,
+    /// Red, green and blue of the emitted light, each from `0.0` to `1.0`.
+    pub emission_color: Vec3,
+    /// Makes the block a fluid that flows into the empty cells around it.
+    pub fluid: Option<Fluid>,
+}
+
+impl Default for Block {
+    fn default() -> Self {
+        Block {
+            solid: false,
+            light_opacity: 0,
+            emission: 0,
+            emission_color: Vec3::ONE,
+            fluid: None,
+        }
+    }
+}
+
+impl Block {
+    /// The red, green and blue block light levels this block emits.
+    pub fn emitted_light(&self) -> [u8; 3] {
+        let level = self.emission.min(MAX_LIGHT) as f32;
+
+        self.emission_color
+            .to_array()
+            .map(|component| (component.clamp(0.0, 1.0) * level).round() as u8)
+    }
+
+    pub fn is_opaque(&self) -> bool {
+        self.light_opacity >= MAX_LIGHT
+    }
 }
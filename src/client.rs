@@ -0,0 +1,334 @@
+use std::net::TcpStream;
+
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+
+use crate::chunk::{chunk_index, Chunk};
+use crate::input::Settings;
+use crate::inventory::{Inventory, ItemStack, INVENTORY_SIZE};
+use crate::loopback::LoopbackClock;
+use crate::player::Player;
+use crate::prediction::Prediction;
+use crate::protocol::{
+    ClientMessage, Connection, ProtocolError, RegistryIds, ServerMessage, PROTOCOL_VERSION,
+};
+use crate::registry::Registry;
+use crate::remote_player::{RemotePlayerEvent, ServerClock, Snapshot};
+use crate::server::{start_local_server, CLIENT_TIMEOUT};
+use crate::tick::BlockWorld;
+use crate::utils::{block_to_chunk, block_to_local, chunk_to_block, local_position};
+use crate::world::{spawn_chunk, World};
+
+/// The connection to the server that owns the world.
+pub struct ServerConnection {
+    pub connection: Connection,
+    /// The server's block and item IDs, empty until it has welcomed us.
+    pub ids: RegistryIds,
+    /// When the server last sent anything, in seconds since startup.
+    pub last_heard: f64,
+    /// Block edits shown before the server has answered them.
+    pub prediction: Prediction,
+    pub server_clock: ServerClock,
+}
+
+/// Connects to the server in [`Settings`], or to a server started for this
+/// client alone if there is none, and says hello.
+pub fn connect_to_server(mut commands: Commands, time: Res<Time>, settings: Res<Settings>) {
+    let connection = match &settings.server_address {
+        Some(address) => match TcpStream::connect(address).and_then(Connection::new) {
+            Ok(connection) => {
+                info!("Connected to {}", address);
+                connection
+            }
+            Err(error) => {
+                error!("Could not connect to {}: {}", address, error);
+                return;
+            }
+        },
+        None => {
+            let clock = LoopbackClock::default();
+            match start_local_server(&clock, settings.simulated_latency) {
+                Ok(connection) => {
+                    commands.insert_resource(clock);
+                    connection
+                }
+                Err(error) => {
+                    error!("Could not start a local server: {}", error);
+                    return;
+                }
+            }
+        }
+    };
+
+    let mut connection = ServerConnection {
+        connection,
+        ids: RegistryIds::default(),
+        last_heard: time.seconds_since_startup(),
+        prediction: Prediction::default(),
+        server_clock: ServerClock::default(),
+    };
+    connection.connection.send(&ClientMessage::Hello {
+        version: PROTOCOL_VERSION,
+        name: settings.player_name.clone(),
+        view_radius: settings.view_radius,
+    });
+    commands.insert_resource(connection);
+}
+
+/// Keeps the clock of the loopback stream to a local server in step with the
+/// game.
+pub fn advance_loopback_clock(time: Res<Time>, clock: Option<Res<LoopbackClock>>) {
+    if let Some(clock) = clock {
+        clock.advance_to((time.seconds_since_startup() * 1000.0) as u64);
+    }
+}
+
+/// Applies what the server sent: chunks are spawned and unloaded, and block
+/// changes are relit and remeshed.
+#[allow(clippy::too_many_arguments)]
+pub fn receive_server_messages(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut connection: Option<ResMut<ServerConnection>>,
+    mut world: ResMut<World>,
+    registry: Res<Registry<'static>>,
+    mut chunks: Query<&mut Chunk<'static>>,
+    mut inventories: Query<&mut Inventory, With<Player>>,
+    mut remote_player_events: EventWriter<RemotePlayerEvent>,
+) {
+    let connection = match connection.as_mut() {
+        Some(connection) => connection,
+        None => return,
+    };
+
+    let messages = match connection.connection.receive::<ServerMessage>() {
+        Ok(messages) => messages,
+        Err(error) => {
+            error!("Lost the connection to the server: {}", error);
+            commands.remove_resource::<ServerConnection>();
+            return;
+        }
+    };
+
+    let now = time.seconds_since_startup();
+    if !messages.is_empty() {
+        connection.last_heard = now;
+    } else if now - connection.last_heard > CLIENT_TIMEOUT {
+        error!("Lost the connection to the server: timed out");
+        commands.remove_resource::<ServerConnection>();
+        return;
+    }
+
+    // Chunks received in this batch are not spawned until the end of it, so
+    // changes to them are made to the chunk directly.
+    let mut received: HashMap<IVec3, Chunk<'static>> = HashMap::default();
+    let mut changes = Vec::new();
+
+    let mut apply = |message: ServerMessage,
+                     connection: &mut ServerConnection|
+     -> Result<(), ProtocolError> {
+        match message {
+            ServerMessage::Welcome { blocks, items } => {
+                connection.ids = RegistryIds::from_welcome(blocks, items, &registry);
+            }
+            ServerMessage::Disconnect { reason } => {
+                error!("The server hung up: {}", reason);
+                commands.remove_resource::<ServerConnection>();
+            }
+            ServerMessage::ChunkData(data) => {
+                let chunk = data.to_chunk(&connection.ids.blocks)?;
+
+                if let Some(entity) = world.chunks.remove(&chunk.position) {
+                    commands.entity(entity).despawn_recursive();
+                }
+                received.insert(chunk.position, chunk);
+            }
+            ServerMessage::UnloadChunk { position } => {
+                received.remove(&position);
+                if let Some(entity) = world.chunks.remove(&position) {
+                    commands.entity(entity).despawn_recursive();
+                }
+            }
+            ServerMessage::BlockChange {
+                position,
+                block,
+                state,
+            } => {
+                let block = connection.ids.blocks.key(block)?;
+                if connection.prediction.server_change(position, block, state) {
+                    changes.push((position, block, state));
+                }
+            }
+            ServerMessage::BulkBlockChange {
+                chunk,
+                changes: entries,
+            } => {
+                for entry in entries {
+                    let block = connection.ids.blocks.key(entry.block)?;
+                    let position = chunk_to_block(chunk, local_position(entry.index));
+                    if connection
+                        .prediction
+                        .server_change(position, block, entry.state)
+                    {
+                        changes.push((position, block, entry.state));
+                    }
+                }
+            }
+            ServerMessage::GiveItems { stacks } => {
+                for (id, count) in stacks {
+                    let item = match connection.ids.items.key(id)? {
+                        Some(item) => item,
+                        None => continue,
+                    };
+
+                    for mut inventory in inventories.iter_mut() {
+                        inventory.insert(ItemStack { item, count }, registry.max_stack_size(item));
+                    }
+                }
+            }
+            ServerMessage::SetInventory { slots } => {
+                let mut stacks = Vec::with_capacity(slots.len());
+                for (id, count) in slots {
+                    let item = connection.ids.items.key(id)?;
+                    stacks.push(item.map(|item| ItemStack { item, count }));
+                }
+                stacks.resize(INVENTORY_SIZE, None);
+
+                for mut inventory in inventories.iter_mut() {
+                    inventory.slots = stacks.clone();
+                }
+            }
+            ServerMessage::KeepAlive { id } => {
+                connection.connection.send(&ClientMessage::KeepAlive { id });
+            }
+            ServerMessage::EditResult { sequence, accepted } => {
+                let resolution = connection.prediction.resolve(sequence, accepted);
+
+                if let Some(change) = resolution.restore {
+                    changes.push(change);
+                }
+                if let Some(stack) = resolution.refund {
+                    for mut inventory in inventories.iter_mut() {
+                        inventory.insert(stack, registry.max_stack_size(stack.item));
+                    }
+                }
+            }
+            ServerMessage::PlayerJoined { id, name } => {
+                remote_player_events.send(RemotePlayerEvent::Joined { id, name });
+            }
+            ServerMessage::PlayerLeft { id } => {
+                remote_player_events.send(RemotePlayerEvent::Left { id });
+            }
+            ServerMessage::PlayerMoved {
+                id,
+                time: server_time,
+                position,
+                pitch,
+                yaw,
+            } => {
+                let server_time = server_time as f64 / 1000.0;
+                connection.server_clock.observe(server_time, now);
+                remote_player_events.send(RemotePlayerEvent::Moved {
+                    id,
+                    snapshot: Snapshot {
+                        time: server_time,
+                        position,
+                        pitch,
+                        yaw,
+                    },
+                });
+            }
+        }
+
+        Ok(())
+    };
+
+    for message in messages {
+        if let Err(error) = apply(message, &mut *connection) {
+            error!(
+                "The server sent something this client cannot read: {}",
+                error
+            );
+            commands.remove_resource::<ServerConnection>();
+            return;
+        }
+    }
+
+    // Changes to chunks from this batch are written straight into them, the
+    // rest go through the world so they are relit.
+    let mut loaded_changes = Vec::new();
+    for (position, block, state) in changes {
+        match received.get_mut(&block_to_chunk(position)) {
+            Some(chunk) => {
+                let index = chunk_index(block_to_local(position));
+                chunk.blocks[index] = block;
+                chunk.states[index] = state;
+            }
+            None => loaded_changes.push((position, block, state)),
+        }
+    }
+
+    for (_, chunk) in received {
+        spawn_chunk(&mut commands, &mut world, chunk);
+    }
+
+    if loaded_changes.is_empty() {
+        return;
+    }
+
+    let mut block_world = BlockWorld::new(
+        chunks
+            .iter_mut()
+            .map(|chunk| (chunk.position, chunk))
+            .collect(),
+        &registry,
+        0,
+    );
+    for (position, block, state) in loaded_changes {
+        block_world.set_block(position, block, state);
+    }
+    block_world.relight();
+}
+
+/// Tells the server where the player is and where they are looking whenever
+/// either changes.
+pub fn send_player_movement(
+    mut last_position: Local<Option<Vec3>>,
+    mut last_look: Local<Option<(f32, f32)>>,
+    mut connection: Option<ResMut<ServerConnection>>,
+    players: Query<(&Transform, &Player)>,
+) {
+    let connection = match connection.as_mut() {
+        Some(connection) => connection,
+        None => return,
+    };
+
+    for (transform, player) in players.iter() {
+        if *last_position != Some(transform.translation) {
+            *last_position = Some(transform.translation);
+            connection.connection.send(&ClientMessage::PlayerPosition {
+                position: transform.translation,
+            });
+        }
+
+        if *last_look != Some((player.pitch, player.yaw)) {
+            *last_look = Some((player.pitch, player.yaw));
+            connection.connection.send(&ClientMessage::PlayerLook {
+                pitch: player.pitch,
+                yaw: player.yaw,
+            });
+        }
+    }
+}
+
+pub fn flush_server_connection(
+    mut commands: Commands,
+    mut connection: Option<ResMut<ServerConnection>>,
+) {
+    if let Some(connection) = connection.as_mut() {
+        if let Err(error) = connection.connection.flush() {
+            error!("Lost the connection to the server: {}", error);
+            commands.remove_resource::<ServerConnection>();
+        }
+    }
+}
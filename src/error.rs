@@ -0,0 +1,186 @@
+use std::fmt;
+
+use bevy::prelude::*;
+use bevy::render::render_resource::{Extent3d, FilterMode, TextureDimension, TextureFormat};
+
+use crate::game::AssetHandles;
+use crate::key::Key;
+
+/// What block textures that cannot be found are known as. Block models can use
+/// it to be drawn with the placeholder on purpose.
+pub const MISSING_TEXTURE: &str = "missing";
+
+/// Seconds a failure stays on screen after it last happened.
+const ERROR_DISPLAY_TIME: f64 = 10.0;
+
+/// Something that went wrong loading block assets or meshing chunks with them.
+/// The game carries on with a placeholder in place of whatever failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AssetError {
+    /// The game's own block textures folder is missing.
+    MissingFolder { path: String },
+    /// A resource pack has no block textures or models.
+    EmptyPack { pack: String },
+    /// A file could not be read or parsed. The same file from a lower pack is
+    /// used instead, if there is one.
+    FailedToLoad { path: String },
+    /// A file in the block textures folder is not an image.
+    NotAnImage { path: String },
+    /// The block textures could not be packed into an atlas, so every block is
+    /// drawn with the placeholder texture.
+    Atlas { reason: String },
+    /// The block textures differ in size or format, so chunks are drawn from the
+    /// atlas instead of a texture array, without light or ambient occlusion.
+    NoTextureArray,
+    /// A block model's texture is in none of the resource packs.
+    MissingTexture {
+        block: Key<'static>,
+        texture: String,
+    },
+    /// A block has no block model, and is drawn as an unknown block.
+    UnknownBlockModel { block: Key<'static> },
+    /// A chunk holds a block that is not registered, which is left out of its
+    /// mesh.
+    UnregisteredBlock { block: Key<'static> },
+    /// Chunks were meshed before the block texture atlas was built.
+    NoAtlas,
+}
+
+impl fmt::Display for AssetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AssetError::MissingFolder { path } => {
+                write!(f, "The block textures folder {} is missing", path)
+            }
+            AssetError::EmptyPack { pack } => {
+                write!(
+                    f,
+                    "The resource pack {} has no block textures or models",
+                    pack
+                )
+            }
+            AssetError::FailedToLoad { path } => write!(f, "Could not load {}", path),
+            AssetError::NotAnImage { path } => {
+                write!(f, "The block texture {} is not an image", path)
+            }
+            AssetError::Atlas { reason } => {
+                write!(f, "Could not build the block texture atlas: {}", reason)
+            }
+            AssetError::NoTextureArray => write!(
+                f,
+                "The block textures differ in size or format, so chunks are drawn from the texture atlas, which supports neither light nor ambient occlusion"
+            ),
+            AssetError::MissingTexture { block, texture } => {
+                write!(
+                    f,
+                    "The texture {} of the block {} is missing",
+                    texture, block
+                )
+            }
+            AssetError::UnknownBlockModel { block } => {
+                write!(f, "The block {} has no block model", block)
+            }
+            AssetError::UnregisteredBlock { block } => {
+                write!(f, "The block {} is not registered", block)
+            }
+            AssetError::NoAtlas => write!(f, "The block texture atlas is not built"),
+        }
+    }
+}
+
+impl std::error::Error for AssetError {}
+
+/// A magenta and black checkerboard of `size`, drawn in place of missing block
+/// textures.
+pub fn missing_texture(size: Extent3d) -> Image {
+    let tile = (size.width.max(size.height) / 4).max(1);
+
+    let mut data = Vec::with_capacity((size.width * size.height * 4) as usize);
+    for y in 0..size.height {
+        for x in 0..size.width {
+            if ((x / tile) ^ (y / tile)) & 1 == 0 {
+                data.extend_from_slice(&[255, 0, 255, 255]);
+            } else {
+                data.extend_from_slice(&[0, 0, 0, 255]);
+            }
+        }
+    }
+
+    let mut image = Image::new(
+        Extent3d {
+            width: size.width,
+            height: size.height,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        data,
+        TextureFormat::Rgba8UnormSrgb,
+    );
+    image.sampler_descriptor.mag_filter = FilterMode::Nearest;
+    image.sampler_descriptor.min_filter = FilterMode::Nearest;
+
+    image
+}
+
+/// Marks the text listing recent [`AssetError`]s.
+#[derive(Component)]
+pub struct ErrorText;
+
+pub fn create_error_text(mut commands: Commands, asset_handles: Res<AssetHandles>) {
+    commands
+        .spawn_bundle(TextBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: Rect {
+                    top: Val::Px(8.0),
+                    left: Val::Px(8.0),
+                    ..default()
+                },
+                ..default()
+            },
+            text: Text::with_section(
+                "",
+                TextStyle {
+                    font: asset_handles.font.clone(),
+                    font_size: 16.0,
+                    color: Color::rgb(1.0, 0.3, 0.3),
+                },
+                default(),
+            ),
+            ..default()
+        })
+        .insert(ErrorText);
+}
+
+/// Logs each [`AssetError`] and lists it on screen until it has not happened
+/// for ten seconds. An error that keeps happening is logged once.
+pub fn report_asset_errors(
+    time: Res<Time>,
+    mut recent: Local<Vec<(AssetError, f64)>>,
+    mut errors: EventReader<AssetError>,
+    mut texts: Query<&mut Text, With<ErrorText>>,
+) {
+    let now = time.seconds_since_startup();
+
+    for error in errors.iter() {
+        match recent.iter_mut().find(|(known, _)| known == error) {
+            Some((_, last)) => *last = now,
+            None => {
+                error!("{}", error);
+                recent.push((error.clone(), now));
+            }
+        }
+    }
+    recent.retain(|(_, last)| now - last < ERROR_DISPLAY_TIME);
+
+    let message = recent
+        .iter()
+        .map(|(error, _)| error.to_string())
+        .collect::<Vec<_>>()
+        .join("\n");
+    for mut text in texts.iter_mut() {
+        if text.sections[0].value != message {
+            text.sections[0].value = message.clone();
+        }
+    }
+}
@@ -0,0 +1,400 @@
+use bevy::prelude::*;
+
+use crate::key::Key;
+use crate::tick::BlockWorld;
+
+/// Set in a fluid's block state while it is fed from the cell above, which makes
+/// it fill its whole cell and spread as if it were a source.
+pub const FALLING: u8 = 0x10;
+
+/// The bits of a fluid's block state holding its level: `0` for a source, and one
+/// more for every block flowed sideways.
+const LEVEL_MASK: u8 = 0x0f;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Fluid {
+    /// How many blocks the fluid flows sideways from a source before it runs out.
+    pub flow_distance: u8,
+    /// World ticks between a cell changing and its neighbours flowing in response.
+    pub tick_delay: u64,
+}
+
+impl Fluid {
+    /// Height of the surface of a cell in `state`, as a fraction of the cell.
+    pub fn height(&self, state: u8) -> f32 {
+        if state & FALLING != 0 {
+            return 1.0;
+        }
+
+        let levels = self.flow_distance as f32 + 1.0;
+        0.875 * (levels - (state & LEVEL_MASK) as f32) / levels
+    }
+}
+
+/// The level a cell in `state` passes on to the cells it spreads into, less one.
+fn flow_level(state: u8) -> u8 {
+    if state & FALLING != 0 {
+        0
+    } else {
+        state & LEVEL_MASK
+    }
+}
+
+/// The four blocks beside `position` on the same level.
+fn horizontal_neighbours(position: IVec3) -> [IVec3; 4] {
+    [IVec3::X, IVec3::Z, -IVec3::X, -IVec3::Z].map(|direction| position + direction)
+}
+
+fn fluid_at(world: &BlockWorld, position: IVec3) -> Option<Fluid> {
+    world.block_data(position).and_then(|block| block.fluid)
+}
+
+fn is_empty(world: &BlockWorld, position: IVec3) -> bool {
+    world.is_loaded(position) && world.block(position).is_none()
+}
+
+/// Whether the fluid `key` at `position` pours into the cell below instead of
+/// spreading sideways.
+fn flows_down(world: &BlockWorld, key: Key<'static>, position: IVec3) -> bool {
+    let below = position - IVec3::Y;
+
+    is_empty(world, below) || world.block(below) == Some(key) && world.state(below) != 0
+}
+
+/// The changes one cell of fluid wants to make to itself and its neighbours,
+/// worked out before any of them is made.
+fn flow(world: &BlockWorld, position: IVec3) -> Vec<(IVec3, Option<(Key<'static>, u8)>)> {
+    let (key, fluid) = match (world.block(position), fluid_at(world, position)) {
+        (Some(key), Some(fluid)) => (key, fluid),
+        _ => return Vec::new(),
+    };
+
+    let mut changes = Vec::new();
+    let mut state = world.state(position);
+
+    // Flowing cells only last as long as something still feeds them.
+    if state != 0 {
+        let fed_state = if world.block(position + IVec3::Y) == Some(key) {
+            Some(FALLING)
+        } else {
+            horizontal_neighbours(position)
+                .into_iter()
+                .filter(|neighbour| {
+                    world.block(*neighbour) == Some(key) && !flows_down(world, key, *neighbour)
+                })
+                .map(|neighbour| flow_level(world.state(neighbour)) + 1)
+                .min()
+                .filter(|level| *level <= fluid.flow_distance)
+        };
+
+        match fed_state {
+            Some(fed_state) => {
+                if fed_state != state {
+                    changes.push((position, Some((key, fed_state))));
+                }
+                state = fed_state;
+            }
+            None => {
+                changes.push((position, None));
+                return changes;
+            }
+        }
+    }
+
+    if flows_down(world, key, position) {
+        changes.push((position - IVec3::Y, Some((key, FALLING))));
+        return changes;
+    }
+
+    let level = flow_level(state) + 1;
+    if level <= fluid.flow_distance {
+        for neighbour in horizontal_neighbours(position) {
+            if is_empty(world, neighbour) {
+                changes.push((neighbour, Some((key, level))));
+            }
+        }
+    }
+
+    changes
+}
+
+/// Scheduled tick of every fluid block. Flows the fluid at `position` once, and
+/// schedules any cell it flowed into to keep flowing.
+pub fn flow_fluid(world: &mut BlockWorld, position: IVec3) {
+    for (target, new) in flow(world, position) {
+        let changed = match new {
+            Some((block, state)) => world.set_block(target, Some(block), state),
+            None => world.set_block(target, None, 0),
+        };
+
+        if let (true, Some(fluid)) = (changed, fluid_at(world, target)) {
+            world.schedule(target, fluid.tick_delay);
+        }
+    }
+}
+
+/// Neighbour update of every fluid block, which may now flow somewhere new or
+/// have lost what fed it.
+pub fn update_fluid(world: &mut BlockWorld, position: IVec3, _source: IVec3) {
+    if let Some(fluid) = fluid_at(world, position) {
+        world.schedule(position, fluid.tick_delay);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::event::Events;
+    use bevy::ecs::system::SystemState;
+
+    use super::*;
+    use crate::chunk::{chunk_index, Block, Chunk, CHUNK_SIZE};
+    use crate::light::{light_chunks, MAX_LIGHT};
+    use crate::registry::Registry;
+    use crate::tick::{
+        tick_blocks, BlockBehaviour, BlockChanged, BlockUpdate, PendingBlockUpdates,
+    };
+    use crate::utils::{block_to_chunk, block_to_local};
+    use crate::world::WorldTick;
+
+    const STONE: Key<'static> = Key {
+        namespace: "test",
+        name: "stone",
+    };
+    const WATER: Key<'static> = Key {
+        namespace: "test",
+        name: "water",
+    };
+    const LAVA: Key<'static> = Key {
+        namespace: "test",
+        name: "lava",
+    };
+
+    fn registry() -> Registry<'static> {
+        let mut registry = Registry::default();
+        registry.blocks.insert(
+            STONE,
+            Block {
+                solid: true,
+                light_opacity: MAX_LIGHT,
+                ..default()
+            },
+        );
+        registry.blocks.insert(
+            WATER,
+            Block {
+                light_opacity: 2,
+                fluid: Some(Fluid {
+                    flow_distance: 3,
+                    tick_delay: 1,
+                }),
+                ..default()
+            },
+        );
+        registry.blocks.insert(
+            LAVA,
+            Block {
+                light_opacity: MAX_LIGHT,
+                emission: MAX_LIGHT,
+                fluid: Some(Fluid {
+                    flow_distance: 2,
+                    tick_delay: 2,
+                }),
+                ..default()
+            },
+        );
+
+        for fluid in [WATER, LAVA] {
+            registry.behaviours.insert(
+                fluid,
+                BlockBehaviour {
+                    scheduled_tick: Some(flow_fluid),
+                    neighbour_update: Some(update_fluid),
+                    ..default()
+                },
+            );
+        }
+
+        registry
+    }
+
+    fn run_system<Params>(world: &mut World, system: impl IntoSystem<(), (), Params>) {
+        let mut system = IntoSystem::into_system(system);
+        system.initialize(world);
+        system.run((), world);
+        system.apply_buffers(world);
+    }
+
+    /// One lit chunk with a stone floor at `y = 0` and the sky above.
+    fn floor() -> World {
+        let mut world = World::new();
+        world.insert_resource(registry());
+        world.insert_resource(crate::world::World::default());
+        world.init_resource::<WorldTick>();
+        world.init_resource::<PendingBlockUpdates>();
+        world.init_resource::<Events<BlockUpdate>>();
+        world.init_resource::<Events<BlockChanged>>();
+
+        let mut chunk = Chunk::new(IVec3::ZERO);
+        for x in 0..CHUNK_SIZE as i32 {
+            for z in 0..CHUNK_SIZE as i32 {
+                chunk.blocks[chunk_index(IVec3::new(x, 0, z))] = Some(STONE);
+            }
+        }
+        world.spawn().insert(chunk);
+        run_system(&mut world, light_chunks);
+
+        world
+    }
+
+    type EditParams<'w, 's> = (
+        Res<'w, Registry<'static>>,
+        Res<'w, WorldTick>,
+        ResMut<'w, PendingBlockUpdates>,
+        EventWriter<'w, 's, BlockChanged>,
+        Query<'w, 's, &'static mut Chunk<'static>>,
+    );
+
+    /// Changes the world the way a system would, with the changes reported and
+    /// relit afterwards.
+    fn edit(world: &mut World, edit: impl FnOnce(&mut BlockWorld)) {
+        let mut state: SystemState<EditParams> = SystemState::new(world);
+        let (registry, world_tick, mut pending_updates, mut block_changes, mut chunks) =
+            state.get_mut(world);
+
+        let mut block_world = BlockWorld::new(
+            chunks
+                .iter_mut()
+                .map(|chunk| (chunk.position, chunk))
+                .collect(),
+            &registry,
+            world_tick.tick,
+        );
+        edit(&mut block_world);
+        block_world.finish(&mut pending_updates, &mut block_changes);
+    }
+
+    /// Places a fluid source that starts flowing on the next tick.
+    fn place_source(world: &mut World, fluid: Key<'static>, position: IVec3) {
+        edit(world, |block_world| {
+            block_world.set_block(position, Some(fluid), 0);
+            block_world.schedule(position, 1);
+        });
+    }
+
+    fn step(world: &mut World, ticks: u64) {
+        for _ in 0..ticks {
+            let mut world_tick = world.resource_mut::<WorldTick>();
+            world_tick.tick += 1;
+            world_tick.elapsed = 1;
+
+            run_system(world, tick_blocks);
+        }
+    }
+
+    fn with_chunk<T>(
+        world: &mut World,
+        position: IVec3,
+        f: impl FnOnce(&Chunk<'static>, usize) -> T,
+    ) -> T {
+        let chunk = world
+            .query::<&Chunk<'static>>()
+            .iter(world)
+            .find(|chunk| chunk.position == block_to_chunk(position))
+            .unwrap();
+        f(chunk, chunk_index(block_to_local(position)))
+    }
+
+    fn block(world: &mut World, position: IVec3) -> Option<(Key<'static>, u8)> {
+        with_chunk(world, position, |chunk, index| {
+            chunk.blocks[index].map(|block| (block, chunk.states[index]))
+        })
+    }
+
+    fn sky_light(world: &mut World, position: IVec3) -> u8 {
+        with_chunk(world, position, |chunk, index| chunk.sky_light[index])
+    }
+
+    fn red_light(world: &mut World, position: IVec3) -> u8 {
+        with_chunk(world, position, |chunk, index| chunk.block_light[index][0])
+    }
+
+    #[test]
+    fn a_spring_spreads_out_into_a_pool() {
+        let mut world = floor();
+        place_source(&mut world, WATER, IVec3::new(8, 1, 8));
+        step(&mut world, 20);
+
+        assert_eq!(block(&mut world, IVec3::new(8, 1, 8)), Some((WATER, 0)));
+        assert_eq!(block(&mut world, IVec3::new(9, 1, 8)), Some((WATER, 1)));
+        assert_eq!(block(&mut world, IVec3::new(8, 1, 6)), Some((WATER, 2)));
+        assert_eq!(block(&mut world, IVec3::new(5, 1, 8)), Some((WATER, 3)));
+        assert_eq!(block(&mut world, IVec3::new(12, 1, 8)), None);
+        assert_eq!(block(&mut world, IVec3::new(9, 1, 9)), Some((WATER, 2)));
+        assert_eq!(block(&mut world, IVec3::new(10, 1, 9)), Some((WATER, 3)));
+        assert_eq!(block(&mut world, IVec3::new(10, 1, 10)), None);
+        assert_eq!(block(&mut world, IVec3::new(9, 2, 8)), None);
+    }
+
+    #[test]
+    fn falling_water_spreads_from_where_it_lands() {
+        let mut world = floor();
+        place_source(&mut world, WATER, IVec3::new(8, 5, 8));
+        step(&mut world, 30);
+
+        assert_eq!(block(&mut world, IVec3::new(9, 5, 8)), None);
+        for y in 1..5 {
+            assert_eq!(
+                block(&mut world, IVec3::new(8, y, 8)),
+                Some((WATER, FALLING))
+            );
+        }
+        assert_eq!(block(&mut world, IVec3::new(9, 1, 8)), Some((WATER, 1)));
+        assert_eq!(block(&mut world, IVec3::new(11, 1, 8)), Some((WATER, 3)));
+        assert_eq!(block(&mut world, IVec3::new(12, 1, 8)), None);
+    }
+
+    #[test]
+    fn water_dries_up_without_its_source() {
+        let mut world = floor();
+        let spring = IVec3::new(8, 1, 8);
+        place_source(&mut world, WATER, spring);
+        step(&mut world, 20);
+
+        edit(&mut world, |block_world| {
+            block_world.set_block(spring, None, 0);
+        });
+        step(&mut world, 50);
+
+        for x in 4..13 {
+            for z in 4..13 {
+                assert_eq!(block(&mut world, IVec3::new(x, 1, z)), None);
+            }
+        }
+    }
+
+    #[test]
+    fn water_dims_the_sky_light_under_it() {
+        let mut world = floor();
+        assert_eq!(sky_light(&mut world, IVec3::new(9, 1, 8)), MAX_LIGHT);
+
+        place_source(&mut world, WATER, IVec3::new(8, 1, 8));
+        step(&mut world, 20);
+
+        assert_eq!(sky_light(&mut world, IVec3::new(9, 1, 8)), MAX_LIGHT - 3);
+        assert_eq!(sky_light(&mut world, IVec3::new(12, 1, 8)), MAX_LIGHT);
+    }
+
+    #[test]
+    fn flowing_lava_gives_off_light() {
+        let mut world = floor();
+        place_source(&mut world, LAVA, IVec3::new(8, 1, 8));
+        assert_eq!(red_light(&mut world, IVec3::new(8, 1, 8)), MAX_LIGHT);
+        assert_eq!(red_light(&mut world, IVec3::new(13, 1, 8)), MAX_LIGHT - 5);
+
+        step(&mut world, 20);
+
+        assert_eq!(block(&mut world, IVec3::new(10, 1, 8)), Some((LAVA, 2)));
+        assert_eq!(red_light(&mut world, IVec3::new(10, 1, 8)), MAX_LIGHT);
+        assert_eq!(red_light(&mut world, IVec3::new(13, 1, 8)), MAX_LIGHT - 3);
+    }
+}
@@ -1,11 +1,31 @@
 use crate::chunk::Block;
-use crate::key::Key;
-use crate::render::{generate_block, BlockModel};
-use crate::world::{ChunkGenerator, World};
-use crate::{ClientRegistry, Registry};
-use bevy::asset::LoadState;
+use crate::fluid::{flow_fluid, update_fluid, Fluid};
+use crate::light::MAX_LIGHT;
+#[cfg(feature = "render")]
+use crate::material::{
+    assign_texture_layers, build_texture_array, BlockMaterialMode, PreferredMaterialMode,
+};
+use crate::registry::Registry;
+#[cfg(feature = "render")]
+use crate::render::{
+    generate_cube, generate_fluid, BlockAppearance, BlockModel, BlockTexture, RenderLayer,
+    UNKNOWN_BLOCK_MODEL,
+};
+use crate::tick::BlockBehaviour;
+#[cfg(feature = "render")]
+use crate::{
+    chunk::Chunk,
+    error::{missing_texture, AssetError, MISSING_TEXTURE},
+    input::Settings,
+    key::Key,
+    registry::ClientRegistry,
+    resource_pack::{BlockModelDefinition, MergedPacks, PackLoad},
+    world::ChunkMeshes,
+};
 use bevy::ecs::schedule::ShouldRun;
 use bevy::prelude::*;
+#[cfg(feature = "render")]
+use bevy::render::render_resource::Extent3d;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum GameState {
@@ -21,81 +41,748 @@ pub fn run_ingame(game_state: Res<State<GameState>>) -> ShouldRun {
     }
 }
 
+#[cfg(feature = "render")]
 #[derive(Default)]
 pub struct AssetHandles {
-    block_textures: Vec<HandleUntyped>,
+    /// Resource packs being loaded to replace the ones in use.
+    loading_packs: Option<PackLoad>,
+    /// The resource packs in use, once the first ones are loaded.
+    pub packs: Option<MergedPacks>,
     pub block_texture_atlas: Handle<TextureAtlas>,
+    pub block_texture_array: Handle<Image>,
+    /// Drawn for blocks whose texture is missing.
+    placeholder_texture: Handle<Image>,
+    pub font: Handle<Font>,
 }
 
+/// Seconds between looks through the resource packs in use for files that were
+/// added or failed to load.
+#[cfg(feature = "render")]
+pub const PACK_SCAN_INTERVAL: f32 = 1.0;
+
+/// Where [`load_assets`] finds the assets, relative to the asset folder.
+#[cfg(feature = "render")]
+#[derive(Debug, Clone)]
+pub struct AssetPaths {
+    /// The folder in each resource pack every block texture is loaded from.
+    pub block_textures: &'static str,
+    pub font: &'static str,
+}
+
+#[cfg(feature = "render")]
+impl Default for AssetPaths {
+    fn default() -> Self {
+        AssetPaths {
+            block_textures: "blocks",
+            font: "fonts/DejaVuSans-Bold.ttf",
+        }
+    }
+}
+
+/// Registers Defaria's own blocks, under the `defaria` namespace.
 pub fn load_blocks(mut registry: ResMut<Registry<'static>>) {
-    registry.blocks.insert(
-        Key {
-            namespace: "defaria",
-            name: "arrow",
+    let mut defaria = registry.for_mod("defaria");
+
+    defaria.block(
+        "arrow",
+        Block {
+            solid: true,
+            light_opacity: MAX_LIGHT,
+            ..default()
+        },
+    );
+
+    defaria.block(
+        "lamp",
+        Block {
+            solid: true,
+            light_opacity: MAX_LIGHT,
+            emission: 14,
+            emission_color: Vec3::new(1.0, 0.85, 0.6),
+            ..default()
+        },
+    );
+
+    defaria.block(
+        "glass",
+        Block {
+            solid: true,
+            light_opacity: 0,
+            ..default()
+        },
+    );
+    // Glass shatters when broken.
+    defaria.drops("glass", Vec::new());
+
+    defaria.block(
+        "leaves",
+        Block {
+            solid: true,
+            light_opacity: 1,
+            ..default()
+        },
+    );
+
+    defaria.block(
+        "water",
+        Block {
+            light_opacity: 2,
+            fluid: Some(Fluid {
+                flow_distance: 7,
+                tick_delay: 5,
+            }),
+            ..default()
+        },
+    );
+    defaria.behaviour(
+        "water",
+        BlockBehaviour {
+            scheduled_tick: Some(flow_fluid),
+            neighbour_update: Some(update_fluid),
+            ..default()
+        },
+    );
+
+    defaria.block(
+        "lava",
+        Block {
+            light_opacity: MAX_LIGHT,
+            emission: MAX_LIGHT,
+            emission_color: Vec3::new(1.0, 0.55, 0.2),
+            fluid: Some(Fluid {
+                flow_distance: 3,
+                tick_delay: 30,
+            }),
+            ..default()
+        },
+    );
+    defaria.behaviour(
+        "lava",
+        BlockBehaviour {
+            scheduled_tick: Some(flow_fluid),
+            neighbour_update: Some(update_fluid),
+            ..default()
         },
-        Block { solid: true },
     );
 }
 
+#[cfg(feature = "render")]
 pub fn load_block_models(mut client_registry: ResMut<ClientRegistry<'static>>) {
-    let mesh_generator = |world: &World, position: IVec3, texture: Rect<f32>| {
-        generate_block(
-            position,
-            [
-                Some(texture),
-                Some(texture),
-                Some(texture),
-                Some(texture),
-                Some(texture),
-                Some(texture),
-            ],
-        )
-    };
+    let mut defaria = client_registry.for_mod("defaria");
 
-    client_registry.block_models.insert(
-        Key {
-            namespace: "defaria",
-            name: "arrow",
-        },
+    defaria.block_model(
+        "arrow",
         BlockModel {
             texture: "blocks/arrow.png",
-            generate_mesh: mesh_generator,
+            layer: RenderLayer::Opaque,
+            generate_mesh: generate_cube,
+        },
+    );
+
+    defaria.block_model(
+        "lamp",
+        BlockModel {
+            texture: "blocks/lamp.png",
+            layer: RenderLayer::Opaque,
+            generate_mesh: generate_cube,
+        },
+    );
+
+    defaria.block_model(
+        "glass",
+        BlockModel {
+            texture: "blocks/glass.png",
+            layer: RenderLayer::Translucent,
+            generate_mesh: generate_cube,
+        },
+    );
+
+    defaria.block_model(
+        "leaves",
+        BlockModel {
+            texture: "blocks/leaves.png",
+            layer: RenderLayer::Cutout,
+            generate_mesh: generate_cube,
+        },
+    );
+
+    defaria.block_model(
+        "water",
+        BlockModel {
+            texture: "blocks/water.png",
+            layer: RenderLayer::Translucent,
+            generate_mesh: generate_fluid,
+        },
+    );
+
+    defaria.block_model(
+        "lava",
+        BlockModel {
+            texture: "blocks/lava.png",
+            layer: RenderLayer::Cutout,
+            generate_mesh: generate_fluid,
         },
     );
 }
 
-pub fn load_assets(mut asset_handles: ResMut<AssetHandles>, asset_server: Res<AssetServer>) {
-    asset_handles.block_textures = asset_server
-        .load_folder("blocks")
-        .expect("Could not find block textures folder.");
+#[cfg(feature = "render")]
+pub fn load_assets(
+    mut asset_handles: ResMut<AssetHandles>,
+    asset_server: Res<AssetServer>,
+    paths: Res<AssetPaths>,
+) {
+    asset_handles.font = asset_server.load(paths.font);
+}
+
+/// Spawns the camera the loading errors, name tags and other UI are drawn with.
+#[cfg(feature = "render")]
+pub fn create_ui_camera(mut commands: Commands) {
+    commands.spawn_bundle(UiCameraBundle::default());
 }
 
+/// Starts loading the resource packs in the [`Settings`] whenever they differ
+/// from the ones in use or being loaded.
+#[cfg(feature = "render")]
+pub fn load_resource_packs(
+    settings: Option<Res<Settings>>,
+    mut asset_handles: ResMut<AssetHandles>,
+    asset_server: Res<AssetServer>,
+    paths: Res<AssetPaths>,
+    mut error_events: EventWriter<AssetError>,
+) {
+    let settings = match settings {
+        Some(settings) if settings.is_changed() => settings,
+        _ => return,
+    };
+
+    let requested = match (&asset_handles.loading_packs, &asset_handles.packs) {
+        (Some(loading_packs), _) => Some(loading_packs.packs()),
+        (None, Some(packs)) => Some(packs.packs()),
+        (None, None) => None,
+    };
+    if requested == Some(settings.resource_packs.as_slice()) {
+        return;
+    }
+
+    info!("Loading resource packs {:?}", settings.resource_packs);
+    let mut errors = Vec::new();
+    asset_handles.loading_packs = Some(PackLoad::start(
+        &asset_server,
+        &settings.resource_packs,
+        paths.block_textures,
+        &mut errors,
+    ));
+    error_events.send_batch(errors.into_iter());
+}
+
+/// Loads the resource packs in use again when files are added to them. Looking
+/// through them also retries files that failed to load, which are not watched
+/// for changes, so fixing one reloads it.
+#[cfg(feature = "render")]
+pub fn scan_resource_packs(
+    time: Res<Time>,
+    mut since_scan: Local<f32>,
+    mut asset_handles: ResMut<AssetHandles>,
+    asset_server: Res<AssetServer>,
+    paths: Res<AssetPaths>,
+) {
+    *since_scan += time.delta_seconds();
+    if *since_scan < PACK_SCAN_INTERVAL || asset_handles.loading_packs.is_some() {
+        return;
+    }
+    *since_scan = 0.0;
+
+    let packs = match &asset_handles.packs {
+        Some(packs) => packs,
+        None => return,
+    };
+
+    // Whatever is wrong with the packs was reported when they were loaded.
+    let scan = PackLoad::start(
+        &asset_server,
+        packs.packs(),
+        paths.block_textures,
+        &mut Vec::new(),
+    );
+    if scan.handles().any(|handle| !packs.contains(handle.id)) {
+        info!("Files were added to the resource packs, loading them again");
+        asset_handles.loading_packs = Some(scan);
+    }
+}
+
+/// Once resource packs are loaded, or a texture or model definition in the ones
+/// in use changes on disk, lays out the block textures and works out how each
+/// block is drawn with them, then has every chunk meshed again. Anything that
+/// cannot be found is drawn with the placeholder texture, or as an unknown
+/// block.
+#[cfg(feature = "render")]
+#[allow(clippy::too_many_arguments)]
 pub fn check_assets(
     mut commands: Commands,
     mut game_state: ResMut<State<GameState>>,
     mut asset_handles: ResMut<AssetHandles>,
     asset_server: Res<AssetServer>,
+    paths: Res<AssetPaths>,
+    registry: Res<Registry<'static>>,
+    mut client_registry: ResMut<ClientRegistry<'static>>,
+    preferred_material_mode: Res<PreferredMaterialMode>,
+    mut material_mode: ResMut<BlockMaterialMode>,
     mut texture_atlases: ResMut<Assets<TextureAtlas>>,
     mut textures: ResMut<Assets<Image>>,
+    definitions: Res<Assets<BlockModelDefinition>>,
+    mut texture_events: EventReader<AssetEvent<Image>>,
+    mut definition_events: EventReader<AssetEvent<BlockModelDefinition>>,
+    mut error_events: EventWriter<AssetError>,
+    mut chunks: Query<(Entity, &mut Chunk<'static>, Option<&ChunkMeshes>)>,
 ) {
-    if let LoadState::Loaded = asset_server
-        .get_group_load_state(asset_handles.block_textures.iter().map(|handle| handle.id))
-    {
-        let mut texture_atlas_builder = TextureAtlasBuilder::default();
-        for handle in &asset_handles.block_textures {
-            let handle = handle.clone().typed();
-            let texture = textures
-                .get(&handle)
-                .expect("Atlas texture is not an image.");
-            texture_atlas_builder.add_texture(handle, texture);
+    // A file that failed to load is created once it is fixed.
+    let mut modified = Vec::new();
+    for event in texture_events.iter() {
+        if let AssetEvent::Created { handle } | AssetEvent::Modified { handle } = event {
+            modified.push(handle.id);
+        }
+    }
+    for event in definition_events.iter() {
+        if let AssetEvent::Created { handle } | AssetEvent::Modified { handle } = event {
+            modified.push(handle.id);
         }
+    }
+
+    let mut errors = Vec::new();
+    let packs = match (
+        asset_handles.loading_packs.take(),
+        asset_handles.packs.take(),
+    ) {
+        (Some(loading_packs), _) if loading_packs.is_finished(&asset_server) => {
+            loading_packs.merge(&asset_server, &mut errors)
+        }
+        (None, Some(packs)) if modified.iter().any(|id| packs.contains(*id)) => {
+            info!("Block textures or models changed, reloading them");
+            packs.merge_again(&asset_server, &mut errors)
+        }
+        (loading_packs, packs) => {
+            asset_handles.loading_packs = loading_packs;
+            asset_handles.packs = packs;
+            return;
+        }
+    };
+
+    // Everything laid out for the previous textures is replaced.
+    if let Some(texture_atlas) = texture_atlases.remove(&asset_handles.block_texture_atlas) {
+        textures.remove(&texture_atlas.texture);
+    }
+    textures.remove(&asset_handles.block_texture_array);
+    textures.remove(&asset_handles.placeholder_texture);
+
+    let block_textures: Vec<(&str, Handle<Image>)> = packs
+        .textures(paths.block_textures)
+        .filter(|(path, handle)| {
+            let is_image = textures.contains(handle);
+            if !is_image {
+                errors.push(AssetError::NotAnImage {
+                    path: path.to_string(),
+                });
+            }
+            is_image
+        })
+        .collect();
+
+    // The placeholder matches the other textures, so they still stack into a
+    // texture array.
+    let placeholder_size = block_textures
+        .first()
+        .and_then(|(_, handle)| textures.get(handle))
+        .map_or(
+            Extent3d {
+                width: 16,
+                height: 16,
+                depth_or_array_layers: 1,
+            },
+            |texture| texture.texture_descriptor.size,
+        );
+    let placeholder = missing_texture(placeholder_size);
+    let placeholder_handle = textures.add(placeholder.clone());
+    asset_handles.placeholder_texture = placeholder_handle.clone();
+
+    let mut texture_atlas_builder = TextureAtlasBuilder::default();
+    texture_atlas_builder.add_texture(placeholder_handle.clone(), &placeholder);
+    for (_, handle) in &block_textures {
+        if let Some(texture) = textures.get(handle) {
+            texture_atlas_builder.add_texture(handle.clone(), texture);
+        }
+    }
+
+    let texture_atlas = texture_atlas_builder
+        .finish(&mut textures)
+        .unwrap_or_else(|error| {
+            errors.push(AssetError::Atlas {
+                reason: error.to_string(),
+            });
+            TextureAtlas::from_grid(
+                placeholder_handle.clone(),
+                Vec2::new(
+                    placeholder_size.width as f32,
+                    placeholder_size.height as f32,
+                ),
+                1,
+                1,
+            )
+        });
+    asset_handles.block_texture_atlas = texture_atlases.add(texture_atlas.clone());
+
+    // Each block with the name of its texture, or `MISSING_TEXTURE` for the
+    // placeholder, the texture itself and the layer it is drawn in.
+    let mut blocks: Vec<(Key<'static>, &str, Handle<Image>, RenderLayer)> = Vec::new();
+    for key in registry.blocks.keys() {
+        let block_model = client_registry.block_models.get(key).unwrap_or_else(|| {
+            errors.push(AssetError::UnknownBlockModel { block: *key });
+            &UNKNOWN_BLOCK_MODEL
+        });
+
+        let definition = packs
+            .model_definition(*key)
+            .and_then(|handle| definitions.get(&handle));
+        let texture = definition
+            .and_then(|definition| definition.texture.as_deref())
+            .unwrap_or(block_model.texture);
+        let layer = definition
+            .and_then(|definition| definition.layer)
+            .unwrap_or(block_model.layer);
+
+        let handle = match packs
+            .texture(texture)
+            .filter(|handle| textures.contains(handle))
+        {
+            Some(handle) => handle,
+            None => {
+                if texture != MISSING_TEXTURE {
+                    errors.push(AssetError::MissingTexture {
+                        block: *key,
+                        texture: texture.to_string(),
+                    });
+                }
+                blocks.push((*key, MISSING_TEXTURE, placeholder_handle.clone(), layer));
+                continue;
+            }
+        };
+
+        blocks.push((*key, texture, handle, layer));
+    }
+
+    let texture_layers = assign_texture_layers(blocks.iter().map(|(_, texture, _, _)| *texture));
+    let mut layer_textures: Vec<(&str, u32)> = texture_layers.clone().into_iter().collect();
+    layer_textures.sort_unstable_by_key(|(_, layer)| *layer);
+
+    let texture_array = layer_textures
+        .iter()
+        .map(|(texture, _)| {
+            blocks
+                .iter()
+                .find(|(_, name, _, _)| name == texture)
+                .and_then(|(_, _, handle, _)| textures.get(handle))
+        })
+        .collect::<Option<Vec<_>>>()
+        .and_then(|layers| build_texture_array(&layers));
+
+    *material_mode = match texture_array {
+        Some(texture_array) => {
+            asset_handles.block_texture_array = textures.add(texture_array);
+            preferred_material_mode.0
+        }
+        None => {
+            if preferred_material_mode.0 == BlockMaterialMode::TextureArray {
+                errors.push(AssetError::NoTextureArray);
+            }
+            asset_handles.block_texture_array = Handle::default();
+            BlockMaterialMode::Atlas
+        }
+    };
+
+    client_registry.appearances = blocks
+        .iter()
+        .map(|(key, texture, handle, layer)| {
+            let texture = match *material_mode {
+                BlockMaterialMode::Atlas => {
+                    // The atlas holds only the placeholder if the textures did
+                    // not fit.
+                    let index = texture_atlas
+                        .get_texture_index(handle)
+                        .or_else(|| texture_atlas.get_texture_index(&placeholder_handle))
+                        .unwrap_or(0);
+                    let texture_position = texture_atlas.textures[index];
+
+                    BlockTexture {
+                        uv: Rect {
+                            top: texture_position.min.y / texture_atlas.size.y,
+                            left: texture_position.min.x / texture_atlas.size.x,
+                            right: texture_position.max.x / texture_atlas.size.x,
+                            bottom: texture_position.max.y / texture_atlas.size.y,
+                        },
+                        layer: 0,
+                    }
+                }
+                BlockMaterialMode::TextureArray => BlockTexture {
+                    uv: Rect {
+                        top: 0.0,
+                        left: 0.0,
+                        right: 1.0,
+                        bottom: 1.0,
+                    },
+                    layer: texture_layers[texture],
+                },
+            };
+
+            (
+                *key,
+                BlockAppearance {
+                    texture,
+                    layer: *layer,
+                },
+            )
+        })
+        .collect();
+
+    error_events.send_batch(errors.into_iter());
+
+    // Layers drawn with the previous packs' textures are replaced when the
+    // chunks are meshed again.
+    for (entity, mut chunk, chunk_meshes) in chunks.iter_mut() {
+        if let Some(chunk_meshes) = chunk_meshes {
+            for (layer_entity, _) in chunk_meshes.layers.values() {
+                commands.entity(*layer_entity).despawn();
+            }
+            commands.entity(entity).remove::<ChunkMeshes>();
+        }
+        chunk.has_changed = true;
+    }
+
+    asset_handles.packs = Some(packs);
+
+    if *game_state.current() == GameState::Loading {
+        if let Err(error) = game_state.set(GameState::Ingame) {
+            warn!("Could not start the game: {}", error);
+        }
+    }
+}
+
+#[cfg(all(test, feature = "render"))]
+mod tests {
+    use std::path::Path;
+    use std::time::Duration;
+
+    use bevy::asset::{AssetPlugin, AssetServerSettings};
+    use bevy::ecs::event::Events;
+    use bevy::utils::HashMap;
+
+    use super::*;
+    use crate::resource_pack::BlockModelDefinitionLoader;
+    use crate::utils::temp_dir;
+
+    fn write_definition(asset_folder: &Path, pack: &str, block: &str, contents: &str) {
+        let models = asset_folder.join(pack).join("models/defaria");
+        std::fs::create_dir_all(&models).unwrap();
+        std::fs::write(models.join(format!("{}.model.ron", block)), contents).unwrap();
+    }
+
+    /// A resource pack drawing arrows in `layer`.
+    fn write_pack(asset_folder: &Path, pack: &str, layer: &str) {
+        write_definition(
+            asset_folder,
+            pack,
+            "arrow",
+            &format!("(layer: Some({}))", layer),
+        );
+    }
+
+    /// Loads the packs in the settings from `asset_folder`, without textures
+    /// or a renderer.
+    fn app(asset_folder: &Path, resource_packs: &[&str]) -> App {
+        let mut app = App::new();
+        app.insert_resource(AssetServerSettings {
+            asset_folder: asset_folder.to_string_lossy().into_owned(),
+            ..default()
+        })
+        .add_plugins(MinimalPlugins)
+        .add_plugin(AssetPlugin)
+        .add_asset::<Image>()
+        .add_asset::<TextureAtlas>()
+        .add_asset::<BlockModelDefinition>()
+        .init_asset_loader::<BlockModelDefinitionLoader>()
+        .add_event::<AssetError>()
+        .add_state(GameState::Loading)
+        .insert_resource(Registry::default())
+        .insert_resource(ClientRegistry::default())
+        .insert_resource(AssetHandles::default())
+        .insert_resource(AssetPaths::default())
+        .insert_resource(PreferredMaterialMode(BlockMaterialMode::Atlas))
+        .insert_resource(BlockMaterialMode::Atlas)
+        .insert_resource(Settings {
+            resource_packs: resource_packs.iter().map(|pack| pack.to_string()).collect(),
+            ..default()
+        })
+        .add_startup_system(load_blocks)
+        .add_startup_system(load_block_models)
+        .add_system_to_stage(
+            CoreStage::PreUpdate,
+            load_resource_packs.before(check_assets),
+        )
+        .add_system_to_stage(
+            CoreStage::PreUpdate,
+            scan_resource_packs.before(check_assets),
+        )
+        .add_system_to_stage(CoreStage::PreUpdate, check_assets);
+        app
+    }
+
+    /// Runs frames until the packs in use are `packs`, as files load in the
+    /// background.
+    fn update_until_using(app: &mut App, packs: &[&str]) {
+        for _ in 0..500 {
+            app.update();
+
+            let asset_handles = app.world.resource::<AssetHandles>();
+            if let Some(merged) = &asset_handles.packs {
+                if merged.packs().iter().eq(packs) {
+                    return;
+                }
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("never switched to {:?}", packs);
+    }
+
+    /// Runs frames until `done`, as files load and the packs are looked through
+    /// in the background.
+    fn update_until(app: &mut App, done: impl Fn(&App) -> bool) {
+        for _ in 0..500 {
+            app.update();
+            if done(app) {
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        panic!("gave up waiting");
+    }
+
+    fn layer(app: &App, block: &str) -> RenderLayer {
+        let registry = app.world.resource::<Registry<'static>>();
+        let key = registry.block_key(&format!("defaria:{}", block)).unwrap();
+        app.world.resource::<ClientRegistry<'static>>().appearances[&key].layer
+    }
+
+    fn arrow_layer(app: &App) -> RenderLayer {
+        layer(app, "arrow")
+    }
+
+    #[test]
+    fn switching_packs_remeshes_every_chunk() {
+        let asset_folder = temp_dir("switch-packs");
+        write_pack(&asset_folder, "cutout", "Cutout");
+        write_pack(&asset_folder, "translucent", "Translucent");
+
+        let mut app = app(&asset_folder, &["cutout"]);
+        update_until_using(&mut app, &["cutout"]);
+        assert_eq!(
+            *app.world.resource::<State<GameState>>().current(),
+            GameState::Ingame
+        );
+        assert_eq!(arrow_layer(&app), RenderLayer::Cutout);
+
+        // A chunk meshed with the first packs.
+        let layer = app.world.spawn().id();
+        let mut chunk = Chunk::new(IVec3::ZERO);
+        chunk.has_changed = false;
+        let chunk = app
+            .world
+            .spawn()
+            .insert(chunk)
+            .insert(ChunkMeshes {
+                layers: HashMap::from_iter([(RenderLayer::Cutout, (layer, Handle::default()))]),
+            })
+            .id();
+
+        app.world.resource_mut::<Settings>().resource_packs = vec!["translucent".to_string()];
+        update_until_using(&mut app, &["translucent"]);
+
+        assert_eq!(arrow_layer(&app), RenderLayer::Translucent);
+        assert!(app.world.get_entity(layer).is_none());
+        assert!(app.world.get::<ChunkMeshes>(chunk).is_none());
+        assert!(app.world.get::<Chunk<'static>>(chunk).unwrap().has_changed);
+
+        std::fs::remove_dir_all(&asset_folder).unwrap();
+    }
+
+    #[test]
+    fn reloading_replaces_the_previous_layout() {
+        let asset_folder = temp_dir("reload-layout");
+        write_pack(&asset_folder, "cutout", "Cutout");
+
+        let mut app = app(&asset_folder, &["cutout"]);
+        update_until_using(&mut app, &["cutout"]);
+        let images = app.world.resource::<Assets<Image>>().len();
+        let atlas = app
+            .world
+            .resource::<AssetHandles>()
+            .block_texture_atlas
+            .clone();
+
+        for _ in 0..3 {
+            let definition = {
+                let registry = app.world.resource::<Registry<'static>>();
+                let arrow = registry.block_key("defaria:arrow").unwrap();
+                let asset_handles = app.world.resource::<AssetHandles>();
+                asset_handles
+                    .packs
+                    .as_ref()
+                    .unwrap()
+                    .model_definition(arrow)
+                    .unwrap()
+            };
+            app.world
+                .resource_mut::<Events<AssetEvent<BlockModelDefinition>>>()
+                .send(AssetEvent::Modified { handle: definition });
+            app.update();
+        }
+
+        assert_ne!(
+            app.world.resource::<AssetHandles>().block_texture_atlas,
+            atlas
+        );
+        assert_eq!(app.world.resource::<Assets<TextureAtlas>>().len(), 1);
+        assert_eq!(app.world.resource::<Assets<Image>>().len(), images);
+
+        std::fs::remove_dir_all(&asset_folder).unwrap();
+    }
+
+    #[test]
+    fn fixed_definitions_are_loaded() {
+        let asset_folder = temp_dir("fix-definition");
+        write_definition(&asset_folder, "pack", "arrow", "(layer: Some(Sideways))");
+
+        let mut app = app(&asset_folder, &["pack"]);
+        update_until_using(&mut app, &["pack"]);
+        assert_eq!(arrow_layer(&app), RenderLayer::Opaque);
+
+        write_pack(&asset_folder, "pack", "Cutout");
+        update_until(&mut app, |app| arrow_layer(app) == RenderLayer::Cutout);
+
+        std::fs::remove_dir_all(&asset_folder).unwrap();
+    }
+
+    #[test]
+    fn added_definitions_are_loaded() {
+        let asset_folder = temp_dir("add-definition");
+        write_pack(&asset_folder, "pack", "Cutout");
 
-        let texture_atlas = texture_atlas_builder.finish(&mut textures).unwrap();
-        asset_handles.block_texture_atlas = texture_atlases.add(texture_atlas.clone());
+        let mut app = app(&asset_folder, &["pack"]);
+        update_until_using(&mut app, &["pack"]);
+        assert_eq!(layer(&app, "lamp"), RenderLayer::Opaque);
 
-        commands.insert_resource(World::default());
-        commands.insert_resource(ChunkGenerator { radius: 4 });
+        write_definition(&asset_folder, "pack", "lamp", "(layer: Some(Translucent))");
+        update_until(&mut app, |app| {
+            layer(app, "lamp") == RenderLayer::Translucent
+        });
 
-        game_state.set(GameState::Ingame).unwrap();
+        std::fs::remove_dir_all(&asset_folder).unwrap();
     }
 }
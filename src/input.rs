@@ -0,0 +1,656 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::time::SystemTime;
+
+use bevy::ecs::system::SystemParam;
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "render")]
+use crate::game::AssetHandles;
+
+pub const SETTINGS_PATH: &str = "settings.ron";
+/// Seconds between looks at [`SETTINGS_PATH`] for changes made while playing.
+pub const SETTINGS_CHECK_INTERVAL: f32 = 1.0;
+
+/// Something the player can do, independent of the input that triggers it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Action {
+    Forward,
+    Back,
+    Left,
+    Right,
+    Jump,
+    Sneak,
+    Sprint,
+    Break,
+    Place,
+    ReleaseCursor,
+    ToggleFly,
+    HotbarNext,
+    HotbarPrevious,
+    /// Starts binding another action to a new input.
+    Rebind,
+}
+
+impl Action {
+    pub const ALL: [Action; 14] = [
+        Action::Forward,
+        Action::Back,
+        Action::Left,
+        Action::Right,
+        Action::Jump,
+        Action::Sneak,
+        Action::Sprint,
+        Action::Break,
+        Action::Place,
+        Action::ReleaseCursor,
+        Action::ToggleFly,
+        Action::HotbarNext,
+        Action::HotbarPrevious,
+        Action::Rebind,
+    ];
+}
+
+/// A single input that can trigger an [`Action`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Binding {
+    Key(KeyCode),
+    Mouse(MouseButton),
+    /// A button on any connected gamepad.
+    Gamepad(GamepadButtonType),
+}
+
+/// Which inputs trigger each action. An action can have any number of bindings,
+/// and is active while any one of them is.
+pub struct InputMap {
+    bindings: HashMap<Action, Vec<Binding>>,
+}
+
+impl Default for InputMap {
+    fn default() -> Self {
+        let mut input_map = InputMap {
+            bindings: HashMap::default(),
+        };
+
+        for (action, binding) in [
+            (Action::Forward, Binding::Key(KeyCode::W)),
+            (Action::Back, Binding::Key(KeyCode::S)),
+            (Action::Left, Binding::Key(KeyCode::A)),
+            (Action::Right, Binding::Key(KeyCode::D)),
+            (Action::Jump, Binding::Key(KeyCode::Space)),
+            (Action::Jump, Binding::Gamepad(GamepadButtonType::South)),
+            (Action::Sneak, Binding::Key(KeyCode::LShift)),
+            (Action::Sneak, Binding::Gamepad(GamepadButtonType::East)),
+            (Action::Sprint, Binding::Key(KeyCode::LControl)),
+            (
+                Action::Sprint,
+                Binding::Gamepad(GamepadButtonType::LeftThumb),
+            ),
+            (Action::Break, Binding::Mouse(MouseButton::Left)),
+            (
+                Action::Break,
+                Binding::Gamepad(GamepadButtonType::RightTrigger2),
+            ),
+            (Action::Place, Binding::Mouse(MouseButton::Right)),
+            (
+                Action::Place,
+                Binding::Gamepad(GamepadButtonType::LeftTrigger2),
+            ),
+            (Action::ReleaseCursor, Binding::Key(KeyCode::Escape)),
+            (
+                Action::ReleaseCursor,
+                Binding::Gamepad(GamepadButtonType::Select),
+            ),
+            (Action::ToggleFly, Binding::Key(KeyCode::F)),
+            (
+                Action::ToggleFly,
+                Binding::Gamepad(GamepadButtonType::North),
+            ),
+            (
+                Action::HotbarNext,
+                Binding::Gamepad(GamepadButtonType::RightTrigger),
+            ),
+            (
+                Action::HotbarPrevious,
+                Binding::Gamepad(GamepadButtonType::LeftTrigger),
+            ),
+            (Action::Rebind, Binding::Key(KeyCode::F1)),
+        ] {
+            input_map.bind(action, binding);
+        }
+
+        input_map
+    }
+}
+
+impl InputMap {
+    /// The default bindings, with those of each action in `bindings` replaced.
+    pub fn with_bindings(bindings: &BTreeMap<Action, Vec<Binding>>) -> Self {
+        let mut input_map = InputMap::default();
+
+        for (action, bindings) in bindings {
+            input_map.bindings.insert(*action, bindings.clone());
+        }
+
+        input_map
+    }
+
+    pub fn bindings(&self, action: Action) -> &[Binding] {
+        self.bindings.get(&action).map_or(&[], Vec::as_slice)
+    }
+
+    /// Adds `binding` to the inputs that trigger `action`.
+    pub fn bind(&mut self, action: Action, binding: Binding) {
+        let bindings = self.bindings.entry(action).or_default();
+
+        if !bindings.contains(&binding) {
+            bindings.push(binding);
+        }
+    }
+
+    /// Replaces the bindings of `action` that use the same kind of device as
+    /// `binding`, so rebinding a key leaves the gamepad binding alone.
+    pub fn rebind(&mut self, action: Action, binding: Binding) {
+        let bindings = self.bindings.entry(action).or_default();
+
+        bindings.retain(|old| std::mem::discriminant(old) != std::mem::discriminant(&binding));
+        bindings.push(binding);
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Stick {
+    /// Moves the player.
+    Left,
+    /// Turns the camera.
+    Right,
+}
+
+/// The gamepad whose sticks drive the player: whichever one was touched last.
+#[derive(Default)]
+pub struct ActiveGamepad(pub Option<Gamepad>);
+
+/// Hands control to any gamepad as soon as one of its buttons or sticks is used,
+/// and lets go of it when it disconnects.
+pub fn track_active_gamepad(
+    settings: Res<Settings>,
+    mut active_gamepad: ResMut<ActiveGamepad>,
+    mut gamepad_events: EventReader<GamepadEvent>,
+) {
+    for GamepadEvent(gamepad, event_type) in gamepad_events.iter() {
+        match event_type {
+            GamepadEventType::ButtonChanged(_, value) if *value > 0.5 => {
+                active_gamepad.0 = Some(*gamepad);
+            }
+            GamepadEventType::AxisChanged(_, value) if value.abs() > settings.stick_dead_zone => {
+                active_gamepad.0 = Some(*gamepad);
+            }
+            GamepadEventType::Disconnected if active_gamepad.0 == Some(*gamepad) => {
+                active_gamepad.0 = None;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Shapes a raw stick position, each axis from `-1.0` to `1.0`.
+///
+/// Anything inside the circular `dead_zone` reads as centred, and the rest of the
+/// range is stretched back to start from zero, so there is no jump at its edge.
+/// The distance from the centre is then raised to `exponent`, which above `1.0`
+/// gives finer control near the centre while still reaching full speed.
+pub fn stick_response(stick: Vec2, dead_zone: f32, exponent: f32) -> Vec2 {
+    let magnitude = stick.length().min(1.0);
+
+    if magnitude <= dead_zone || dead_zone >= 1.0 {
+        return Vec2::ZERO;
+    }
+
+    let scaled = ((magnitude - dead_zone) / (1.0 - dead_zone)).powf(exponent);
+
+    stick.normalize() * scaled
+}
+
+/// Reads actions through the [`InputMap`] from every input device.
+#[derive(SystemParam)]
+pub struct Actions<'w, 's> {
+    input_map: Res<'w, InputMap>,
+    settings: Res<'w, Settings>,
+    keyboard_input: Res<'w, Input<KeyCode>>,
+    mouse_input: Res<'w, Input<MouseButton>>,
+    gamepad_input: Res<'w, Input<GamepadButton>>,
+    gamepad_axes: Res<'w, Axis<GamepadAxis>>,
+    gamepads: Res<'w, Gamepads>,
+    active_gamepad: Res<'w, ActiveGamepad>,
+    #[system_param(ignore)]
+    _marker: std::marker::PhantomData<&'s ()>,
+}
+
+impl<'w, 's> Actions<'w, 's> {
+    /// Whether any binding of `action` is held down.
+    pub fn pressed(&self, action: Action) -> bool {
+        self.input_map
+            .bindings(action)
+            .iter()
+            .any(|binding| match *binding {
+                Binding::Key(key) => self.keyboard_input.pressed(key),
+                Binding::Mouse(button) => self.mouse_input.pressed(button),
+                Binding::Gamepad(button) => self
+                    .gamepads
+                    .iter()
+                    .any(|gamepad| self.gamepad_input.pressed(GamepadButton(*gamepad, button))),
+            })
+    }
+
+    /// Whether any binding of `action` started being held down this frame.
+    pub fn just_pressed(&self, action: Action) -> bool {
+        self.input_map
+            .bindings(action)
+            .iter()
+            .any(|binding| match *binding {
+                Binding::Key(key) => self.keyboard_input.just_pressed(key),
+                Binding::Mouse(button) => self.mouse_input.just_pressed(button),
+                Binding::Gamepad(button) => self.gamepads.iter().any(|gamepad| {
+                    self.gamepad_input
+                        .just_pressed(GamepadButton(*gamepad, button))
+                }),
+            })
+    }
+
+    /// Where a stick of the active gamepad is pushed, after the dead zone and
+    /// response curve from [`Settings`]. Up and right are positive.
+    pub fn stick(&self, stick: Stick) -> Vec2 {
+        let gamepad = match self.active_gamepad.0 {
+            Some(gamepad) => gamepad,
+            None => return Vec2::ZERO,
+        };
+
+        let (x, y) = match stick {
+            Stick::Left => (GamepadAxisType::LeftStickX, GamepadAxisType::LeftStickY),
+            Stick::Right => (GamepadAxisType::RightStickX, GamepadAxisType::RightStickY),
+        };
+        let axis = |axis_type| {
+            self.gamepad_axes
+                .get(GamepadAxis(gamepad, axis_type))
+                .unwrap_or(0.0)
+        };
+
+        stick_response(
+            Vec2::new(axis(x), axis(y)),
+            self.settings.stick_dead_zone,
+            self.settings.stick_response_exponent,
+        )
+    }
+}
+
+/// Asks for an action to be bound to the next key or button the player presses.
+pub struct RebindAction(pub Action);
+
+/// How far the player is through binding an action to a new input.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum Rebinding {
+    #[default]
+    Idle,
+    /// Waiting for an input of the action to rebind, after [`Action::Rebind`].
+    ChoosingAction,
+    /// Waiting for the new input of this action.
+    ChoosingBinding(Action),
+}
+
+/// Rebuilds the [`InputMap`] from the bindings in the [`Settings`] whenever they
+/// change.
+pub fn apply_bindings(settings: Option<Res<Settings>>, mut input_map: ResMut<InputMap>) {
+    if let Some(settings) = settings.filter(|settings| settings.is_changed()) {
+        *input_map = InputMap::with_bindings(&settings.bindings);
+    }
+}
+
+/// After [`Action::Rebind`], asks to rebind whichever action the player uses
+/// next. Using [`Action::Rebind`] again instead cancels.
+pub fn choose_rebind_action(
+    actions: Actions,
+    mut rebinding: ResMut<Rebinding>,
+    mut rebind_events: EventWriter<RebindAction>,
+) {
+    match *rebinding {
+        Rebinding::Idle if actions.just_pressed(Action::Rebind) => {
+            *rebinding = Rebinding::ChoosingAction;
+        }
+        Rebinding::ChoosingAction if actions.just_pressed(Action::Rebind) => {
+            *rebinding = Rebinding::Idle;
+        }
+        Rebinding::ChoosingAction => {
+            if let Some(action) = Action::ALL
+                .into_iter()
+                .find(|action| actions.just_pressed(*action))
+            {
+                rebind_events.send(RebindAction(action));
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Binds the first input pressed after a [`RebindAction`] to its action, and
+/// saves the new bindings to [`SETTINGS_PATH`].
+#[allow(clippy::too_many_arguments)]
+pub fn rebind_actions(
+    mut rebinding: ResMut<Rebinding>,
+    mut rebind_events: EventReader<RebindAction>,
+    mut input_map: ResMut<InputMap>,
+    mut settings: ResMut<Settings>,
+    keyboard_input: Res<Input<KeyCode>>,
+    mouse_input: Res<Input<MouseButton>>,
+    gamepad_input: Res<Input<GamepadButton>>,
+) {
+    if let Some(RebindAction(action)) = rebind_events.iter().last() {
+        *rebinding = Rebinding::ChoosingBinding(*action);
+        return;
+    }
+
+    let action = match *rebinding {
+        Rebinding::ChoosingBinding(action) => action,
+        _ => return,
+    };
+
+    let binding = keyboard_input
+        .get_just_pressed()
+        .next()
+        .map(|key| Binding::Key(*key))
+        .or_else(|| {
+            mouse_input
+                .get_just_pressed()
+                .next()
+                .map(|button| Binding::Mouse(*button))
+        })
+        .or_else(|| {
+            gamepad_input
+                .get_just_pressed()
+                .next()
+                .map(|GamepadButton(_, button)| Binding::Gamepad(*button))
+        });
+
+    if let Some(binding) = binding {
+        input_map.rebind(action, binding);
+        settings
+            .bindings
+            .insert(action, input_map.bindings(action).to_vec());
+        if let Err(error) = settings.save(SETTINGS_PATH) {
+            warn!("Could not save {}: {}", SETTINGS_PATH, error);
+        }
+        *rebinding = Rebinding::Idle;
+    }
+}
+
+/// Marks the text telling the player what to press while rebinding.
+#[cfg(feature = "render")]
+#[derive(Component)]
+pub struct RebindPrompt;
+
+#[cfg(feature = "render")]
+pub fn create_rebind_prompt(mut commands: Commands, asset_handles: Res<AssetHandles>) {
+    commands
+        .spawn_bundle(TextBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                position: Rect {
+                    bottom: Val::Px(8.0),
+                    left: Val::Px(8.0),
+                    ..default()
+                },
+                ..default()
+            },
+            text: Text::with_section(
+                "",
+                TextStyle {
+                    font: asset_handles.font.clone(),
+                    font_size: 16.0,
+                    color: Color::WHITE,
+                },
+                default(),
+            ),
+            ..default()
+        })
+        .insert(RebindPrompt);
+}
+
+#[cfg(feature = "render")]
+pub fn show_rebind_prompt(
+    rebinding: Res<Rebinding>,
+    mut texts: Query<&mut Text, With<RebindPrompt>>,
+) {
+    if !rebinding.is_changed() {
+        return;
+    }
+
+    let message = match *rebinding {
+        Rebinding::Idle => String::new(),
+        Rebinding::ChoosingAction => "Press the input of the action to rebind".to_string(),
+        Rebinding::ChoosingBinding(action) => format!("Press the new input for {:?}", action),
+    };
+    for mut text in texts.iter_mut() {
+        text.sections[0].value = message.clone();
+    }
+}
+
+/// Player preferences read from [`SETTINGS_PATH`]. Missing fields keep their
+/// defaults.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// Degrees the camera turns per pixel of mouse movement.
+    pub mouse_sensitivity: f32,
+    /// Blocks per second the player walks and flies at. Sprinting, crouching and
+    /// flying fast are scaled from this.
+    pub movement_speed: f32,
+    /// Degrees per second the camera turns with the right stick fully pushed.
+    pub gamepad_look_speed: f32,
+    /// How far a stick has to be pushed, from `0.0` to `1.0`, before it counts.
+    pub stick_dead_zone: f32,
+    /// Shape of the stick response curve. `1.0` is linear.
+    pub stick_response_exponent: f32,
+    /// The name other players see.
+    pub player_name: String,
+    /// How far out, in chunks, to load the world around the player.
+    pub view_radius: u32,
+    /// The `host:port` of the server to play on. Without one, a server is started
+    /// for this game alone.
+    pub server_address: Option<String>,
+    /// Milliseconds of delay added each way between this game and the server
+    /// started for it, to try out how it plays over a slow connection.
+    pub simulated_latency: u64,
+    /// Resource pack folders in the asset folder, lowest first, laid over the
+    /// game's own block textures and models. Changing these while playing
+    /// switches packs.
+    pub resource_packs: Vec<String>,
+    /// Inputs replacing the default bindings of their actions. Rebinding an
+    /// action in game saves it here.
+    pub bindings: BTreeMap<Action, Vec<Binding>>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            mouse_sensitivity: 0.06,
+            movement_speed: 6.0,
+            gamepad_look_speed: 180.0,
+            stick_dead_zone: 0.15,
+            stick_response_exponent: 2.0,
+            player_name: "Player".to_string(),
+            view_radius: 4,
+            server_address: None,
+            simulated_latency: 0,
+            resource_packs: Vec::new(),
+            bindings: BTreeMap::new(),
+        }
+    }
+}
+
+impl Settings {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path).map_err(|error| error.to_string())?;
+
+        ron::from_str(&contents).map_err(|error| error.to_string())
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), String> {
+        let contents =
+            ron::ser::to_string_pretty(self, default()).map_err(|error| error.to_string())?;
+
+        std::fs::write(path, contents).map_err(|error| error.to_string())
+    }
+}
+
+pub fn load_settings(mut commands: Commands) {
+    let settings = match Settings::load(SETTINGS_PATH) {
+        Ok(settings) => settings,
+        Err(error) if Path::new(SETTINGS_PATH).exists() => {
+            warn!(
+                "Could not parse {}, using defaults: {}",
+                SETTINGS_PATH, error
+            );
+            Settings::default()
+        }
+        Err(_) => Settings::default(),
+    };
+
+    commands.insert_resource(settings);
+}
+
+/// Reads [`SETTINGS_PATH`] again whenever it changes on disk, so resource packs
+/// and bindings edited there apply straight away. A file that no longer parses
+/// is ignored until it is fixed.
+pub fn reload_settings(
+    time: Res<Time>,
+    mut since_check: Local<f32>,
+    mut last_modified: Local<Option<SystemTime>>,
+    settings: Option<ResMut<Settings>>,
+) {
+    *since_check += time.delta_seconds();
+    if *since_check < SETTINGS_CHECK_INTERVAL {
+        return;
+    }
+    *since_check = 0.0;
+
+    let modified = std::fs::metadata(SETTINGS_PATH)
+        .and_then(|metadata| metadata.modified())
+        .ok();
+    if modified == *last_modified {
+        return;
+    }
+    // The first look only notes the file, which was just loaded.
+    let first_check = last_modified.is_none();
+    *last_modified = modified;
+
+    let mut settings = match settings {
+        Some(settings) if !first_check && modified.is_some() => settings,
+        _ => return,
+    };
+    match Settings::load(SETTINGS_PATH) {
+        Ok(loaded) => {
+            info!("{} changed, applying it", SETTINGS_PATH);
+            *settings = loaded;
+        }
+        Err(error) => warn!("Could not parse {}: {}", SETTINGS_PATH, error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEAD_ZONE: f32 = 0.2;
+
+    #[test]
+    fn the_dead_zone_reads_as_centred() {
+        for stick in [
+            Vec2::ZERO,
+            Vec2::new(0.1, 0.0),
+            Vec2::new(0.0, -DEAD_ZONE),
+            Vec2::new(0.14, 0.14),
+        ] {
+            assert_eq!(stick_response(stick, DEAD_ZONE, 2.0), Vec2::ZERO);
+        }
+    }
+
+    #[test]
+    fn output_starts_from_zero_past_the_dead_zone() {
+        for exponent in [1.0, 2.0, 3.0] {
+            let response = stick_response(Vec2::new(DEAD_ZONE + 0.001, 0.0), DEAD_ZONE, exponent);
+            assert!(response.x > 0.0 && response.x < 0.01);
+            assert_eq!(response.y, 0.0);
+        }
+    }
+
+    #[test]
+    fn full_deflection_is_full_output() {
+        for exponent in [1.0, 2.0, 3.0] {
+            let response = stick_response(Vec2::new(0.0, -1.0), DEAD_ZONE, exponent);
+            assert!((response - Vec2::new(0.0, -1.0)).length() < 1e-6);
+
+            // Corners of a square stick gate reach past the unit circle.
+            let response = stick_response(Vec2::new(1.0, 1.0), DEAD_ZONE, exponent);
+            assert!((response.length() - 1.0).abs() < 1e-6);
+            assert!((response.x - response.y).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn saved_bindings_replace_the_defaults_of_their_actions() {
+        let jump = vec![Binding::Key(KeyCode::J)];
+        let input_map = InputMap::with_bindings(&BTreeMap::from([(Action::Jump, jump.clone())]));
+
+        assert_eq!(input_map.bindings(Action::Jump), jump.as_slice());
+        assert_eq!(
+            input_map.bindings(Action::Forward),
+            InputMap::default().bindings(Action::Forward)
+        );
+    }
+
+    #[test]
+    fn rebinding_a_key_keeps_the_gamepad_binding() {
+        let mut input_map = InputMap::default();
+        input_map.rebind(Action::Jump, Binding::Key(KeyCode::J));
+
+        assert_eq!(
+            input_map.bindings(Action::Jump),
+            [
+                Binding::Gamepad(GamepadButtonType::South),
+                Binding::Key(KeyCode::J)
+            ]
+        );
+    }
+
+    #[test]
+    fn bindings_round_trip_through_the_settings() {
+        let mut settings = Settings::default();
+        settings.bindings.insert(
+            Action::Break,
+            vec![
+                Binding::Mouse(MouseButton::Middle),
+                Binding::Gamepad(GamepadButtonType::RightTrigger),
+            ],
+        );
+
+        let contents = ron::ser::to_string_pretty(&settings, default()).unwrap();
+        let loaded: Settings = ron::from_str(&contents).unwrap();
+        assert_eq!(loaded.bindings, settings.bindings);
+
+        // Settings from before bindings were saved keep the defaults.
+        let loaded: Settings = ron::from_str("(view_radius: 6)").unwrap();
+        assert!(loaded.bindings.is_empty());
+    }
+
+    #[test]
+    fn the_response_curve_follows_the_exponent() {
+        // Halfway between the dead zone and the edge.
+        let halfway = Vec2::new(0.6, 0.0);
+
+        for exponent in [1.0, 2.0, 3.0] {
+            let response = stick_response(halfway, DEAD_ZONE, exponent);
+            assert!((response.x - 0.5f32.powf(exponent)).abs() < 1e-6);
+        }
+    }
+}
@@ -0,0 +1,425 @@
+use bevy::input::mouse::MouseWheel;
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::input::{Action, Actions};
+use crate::key::Key;
+use crate::registry::Registry;
+
+pub const HOTBAR_SIZE: usize = 9;
+/// Hotbar slots first, then the rest of the inventory.
+pub const INVENTORY_SIZE: usize = 36;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ItemStack {
+    pub item: Key<'static>,
+    pub count: u32,
+}
+
+#[derive(Component, Debug, Clone, PartialEq, Eq)]
+pub struct Inventory {
+    pub slots: Vec<Option<ItemStack>>,
+    /// Index of the selected hotbar slot.
+    pub selected: usize,
+}
+
+impl Default for Inventory {
+    fn default() -> Self {
+        Inventory {
+            slots: vec![None; INVENTORY_SIZE],
+            selected: 0,
+        }
+    }
+}
+
+impl Inventory {
+    pub fn selected_stack(&self) -> Option<ItemStack> {
+        self.slots[self.selected]
+    }
+
+    /// Selects the hotbar slot `offset` slots along, wrapping around at the ends.
+    pub fn scroll_selection(&mut self, offset: i32) {
+        self.selected = (self.selected as i32 + offset).rem_euclid(HOTBAR_SIZE as i32) as usize;
+    }
+
+    /// Adds a stack of an item that stacks up to `max_stack_size`, topping up
+    /// stacks of the same item before filling empty slots. Returns whatever did
+    /// not fit.
+    pub fn insert(&mut self, stack: ItemStack, max_stack_size: u32) -> Option<ItemStack> {
+        let mut remaining = stack.count;
+
+        for slot in self.slots.iter_mut().flatten() {
+            if slot.item == stack.item {
+                let moved = remaining.min(max_stack_size.saturating_sub(slot.count));
+                slot.count += moved;
+                remaining -= moved;
+            }
+        }
+
+        for slot in self.slots.iter_mut().filter(|slot| slot.is_none()) {
+            if remaining == 0 {
+                break;
+            }
+
+            let moved = remaining.min(max_stack_size);
+            *slot = Some(ItemStack {
+                item: stack.item,
+                count: moved,
+            });
+            remaining -= moved;
+        }
+
+        (remaining > 0).then_some(ItemStack {
+            item: stack.item,
+            count: remaining,
+        })
+    }
+
+    /// Takes up to `count` of `item` out of the inventory, from the last slots
+    /// first. Returns how many were taken.
+    pub fn remove(&mut self, item: Key, count: u32) -> u32 {
+        let mut removed = 0;
+
+        for slot in self.slots.iter_mut().rev() {
+            if removed == count {
+                break;
+            }
+
+            if let Some(stack) = slot {
+                if stack.item == item {
+                    let taken = (count - removed).min(stack.count);
+                    stack.count -= taken;
+                    removed += taken;
+
+                    if stack.count == 0 {
+                        *slot = None;
+                    }
+                }
+            }
+        }
+
+        removed
+    }
+
+    /// Takes up to `count` items out of one slot.
+    pub fn take(&mut self, slot: usize, count: u32) -> Option<ItemStack> {
+        let stack = self.slots.get_mut(slot)?.as_mut()?;
+        let taken = count.min(stack.count);
+        let item = stack.item;
+
+        stack.count -= taken;
+        if stack.count == 0 {
+            self.slots[slot] = None;
+        }
+
+        (taken > 0).then_some(ItemStack { item, count: taken })
+    }
+
+    /// Takes the larger half of a slot's stack out of it.
+    pub fn split(&mut self, slot: usize) -> Option<ItemStack> {
+        let count = self.slots.get(slot)?.as_ref()?.count;
+
+        self.take(slot, count.div_ceil(2))
+    }
+
+    /// Moves as much of the stack in `from` as fits onto `to`, with stacks of the
+    /// item holding up to `max_stack_size`. Different items swap places instead.
+    pub fn merge(&mut self, from: usize, to: usize, max_stack_size: u32) {
+        if from == to || from >= self.slots.len() || to >= self.slots.len() {
+            return;
+        }
+
+        match (self.slots[from], self.slots[to]) {
+            (Some(source), Some(target)) if source.item == target.item => {
+                let moved = source
+                    .count
+                    .min(max_stack_size.saturating_sub(target.count));
+                self.take(from, moved);
+                if let Some(target) = &mut self.slots[to] {
+                    target.count += moved;
+                }
+            }
+            _ => self.slots.swap(from, to),
+        }
+    }
+
+    pub fn to_save(&self) -> InventorySave {
+        InventorySave {
+            selected: self.selected,
+            slots: self
+                .slots
+                .iter()
+                .map(|slot| slot.map(|stack| (stack.item.to_string(), stack.count)))
+                .collect(),
+        }
+    }
+
+    /// Rebuilds a saved inventory, looking items up by their saved key. Items
+    /// that are no longer registered are dropped.
+    pub fn from_save(save: &InventorySave, registry: &Registry<'static>) -> Self {
+        let mut inventory = Inventory {
+            selected: save.selected.min(HOTBAR_SIZE - 1),
+            ..default()
+        };
+
+        for (slot, saved) in inventory.slots.iter_mut().zip(&save.slots) {
+            *slot = saved.as_ref().and_then(|(item, count)| {
+                let key = registry.item_key(item)?;
+
+                Some(ItemStack {
+                    item: key,
+                    count: (*count).min(registry.max_stack_size(key)),
+                })
+            });
+        }
+
+        inventory
+    }
+}
+
+/// An [`Inventory`] as written to the player save, with items as
+/// `namespace:name` strings.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct InventorySave {
+    pub selected: usize,
+    pub slots: Vec<Option<(String, u32)>>,
+}
+
+/// A full stack of every registered item, for a player without a save.
+pub fn starting_inventory(registry: &Registry<'static>) -> Inventory {
+    let mut keys: Vec<_> = registry.items.keys().copied().collect();
+    keys.sort_by_key(|key| key.to_string());
+
+    let mut inventory = Inventory::default();
+    for item in keys {
+        let max_stack_size = registry.max_stack_size(item);
+        inventory.insert(
+            ItemStack {
+                item,
+                count: max_stack_size,
+            },
+            max_stack_size,
+        );
+    }
+
+    inventory
+}
+
+/// Picks the hotbar slot with the number keys, the scroll wheel, or the hotbar
+/// actions.
+pub fn select_hotbar_slot(
+    keyboard_input: Res<Input<KeyCode>>,
+    actions: Actions,
+    mut mouse_wheel: EventReader<MouseWheel>,
+    mut inventories: Query<&mut Inventory>,
+) {
+    const NUMBER_KEYS: [KeyCode; HOTBAR_SIZE] = [
+        KeyCode::Key1,
+        KeyCode::Key2,
+        KeyCode::Key3,
+        KeyCode::Key4,
+        KeyCode::Key5,
+        KeyCode::Key6,
+        KeyCode::Key7,
+        KeyCode::Key8,
+        KeyCode::Key9,
+    ];
+
+    let mut scroll = 0.0;
+    for event in mouse_wheel.iter() {
+        scroll += event.y;
+    }
+
+    let mut offset = -(scroll.signum() as i32) * (scroll != 0.0) as i32;
+    if actions.just_pressed(Action::HotbarNext) {
+        offset += 1;
+    }
+    if actions.just_pressed(Action::HotbarPrevious) {
+        offset -= 1;
+    }
+
+    let number = NUMBER_KEYS
+        .iter()
+        .position(|key| keyboard_input.just_pressed(*key));
+
+    if number.is_none() && offset == 0 {
+        return;
+    }
+
+    for mut inventory in inventories.iter_mut() {
+        if let Some(number) = number {
+            inventory.selected = number;
+        }
+        inventory.scroll_selection(offset);
+
+        match inventory.selected_stack() {
+            Some(stack) => info!("Holding {} x{}", stack.item, stack.count),
+            None => info!("Holding nothing"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::item::Item;
+
+    const STONE: Key<'static> = Key {
+        namespace: "test",
+        name: "stone",
+    };
+    const PEARL: Key<'static> = Key {
+        namespace: "test",
+        name: "pearl",
+    };
+
+    fn stack(item: Key<'static>, count: u32) -> ItemStack {
+        ItemStack { item, count }
+    }
+
+    fn registry() -> Registry<'static> {
+        let mut registry = Registry::default();
+        registry.items.insert(STONE, Item::default());
+        registry.items.insert(
+            PEARL,
+            Item {
+                max_stack_size: 16,
+                ..default()
+            },
+        );
+        registry
+    }
+
+    #[test]
+    fn inserting_tops_up_stacks_before_empty_slots() {
+        let mut inventory = Inventory::default();
+        inventory.slots[3] = Some(stack(STONE, 60));
+
+        assert_eq!(inventory.insert(stack(STONE, 10), 64), None);
+        assert_eq!(inventory.slots[3], Some(stack(STONE, 64)));
+        assert_eq!(inventory.slots[0], Some(stack(STONE, 6)));
+
+        assert_eq!(inventory.insert(stack(PEARL, 40), 16), None);
+        assert_eq!(inventory.slots[1], Some(stack(PEARL, 16)));
+        assert_eq!(inventory.slots[2], Some(stack(PEARL, 16)));
+        assert_eq!(inventory.slots[4], Some(stack(PEARL, 8)));
+    }
+
+    #[test]
+    fn inserting_into_a_full_inventory_gives_back_the_rest() {
+        let mut inventory = Inventory::default();
+        for slot in &mut inventory.slots {
+            *slot = Some(stack(PEARL, 15));
+        }
+
+        assert_eq!(
+            inventory.insert(stack(PEARL, 50), 16),
+            Some(stack(PEARL, 14))
+        );
+        assert!(inventory
+            .slots
+            .iter()
+            .all(|slot| *slot == Some(stack(PEARL, 16))));
+        assert_eq!(inventory.insert(stack(STONE, 5), 64), Some(stack(STONE, 5)));
+    }
+
+    #[test]
+    fn removing_takes_from_the_last_slots_first() {
+        let mut inventory = Inventory::default();
+        inventory.slots[0] = Some(stack(STONE, 10));
+        inventory.slots[5] = Some(stack(STONE, 4));
+        inventory.slots[6] = Some(stack(PEARL, 4));
+
+        assert_eq!(inventory.remove(STONE, 6), 6);
+        assert_eq!(inventory.slots[5], None);
+        assert_eq!(inventory.slots[0], Some(stack(STONE, 8)));
+        assert_eq!(inventory.slots[6], Some(stack(PEARL, 4)));
+
+        assert_eq!(inventory.remove(STONE, 20), 8);
+        assert_eq!(inventory.slots[0], None);
+        assert_eq!(inventory.remove(STONE, 1), 0);
+    }
+
+    #[test]
+    fn splitting_takes_the_larger_half() {
+        let mut inventory = Inventory::default();
+        inventory.slots[2] = Some(stack(STONE, 7));
+
+        assert_eq!(inventory.split(2), Some(stack(STONE, 4)));
+        assert_eq!(inventory.slots[2], Some(stack(STONE, 3)));
+
+        inventory.slots[3] = Some(stack(STONE, 1));
+        assert_eq!(inventory.split(3), Some(stack(STONE, 1)));
+        assert_eq!(inventory.slots[3], None);
+
+        assert_eq!(inventory.split(3), None);
+        assert_eq!(inventory.split(INVENTORY_SIZE), None);
+    }
+
+    #[test]
+    fn merging_fills_up_to_the_max_stack_size() {
+        let mut inventory = Inventory::default();
+        inventory.slots[0] = Some(stack(PEARL, 10));
+        inventory.slots[1] = Some(stack(PEARL, 12));
+
+        inventory.merge(0, 1, 16);
+        assert_eq!(inventory.slots[0], Some(stack(PEARL, 6)));
+        assert_eq!(inventory.slots[1], Some(stack(PEARL, 16)));
+
+        inventory.merge(1, 2, 16);
+        assert_eq!(inventory.slots[1], None);
+        assert_eq!(inventory.slots[2], Some(stack(PEARL, 16)));
+
+        inventory.merge(0, 2, 16);
+        assert_eq!(inventory.slots[0], Some(stack(PEARL, 6)));
+    }
+
+    #[test]
+    fn merging_different_items_swaps_them() {
+        let mut inventory = Inventory::default();
+        inventory.slots[0] = Some(stack(PEARL, 3));
+        inventory.slots[1] = Some(stack(STONE, 5));
+
+        inventory.merge(0, 1, 64);
+        assert_eq!(inventory.slots[0], Some(stack(STONE, 5)));
+        assert_eq!(inventory.slots[1], Some(stack(PEARL, 3)));
+
+        inventory.merge(0, INVENTORY_SIZE, 64);
+        assert_eq!(inventory.slots[0], Some(stack(STONE, 5)));
+    }
+
+    #[test]
+    fn saves_round_trip() {
+        let registry = registry();
+        let mut inventory = Inventory::default();
+        inventory.slots[0] = Some(stack(STONE, 64));
+        inventory.slots[8] = Some(stack(PEARL, 16));
+        inventory.slots[35] = Some(stack(STONE, 1));
+        inventory.selected = 8;
+
+        let save = inventory.to_save();
+        let save: InventorySave = ron::from_str(&ron::to_string(&save).unwrap()).unwrap();
+        assert_eq!(Inventory::from_save(&save, &registry), inventory);
+    }
+
+    #[test]
+    fn loading_drops_unknown_items_and_clamps_stacks() {
+        let registry = registry();
+        let save = InventorySave {
+            selected: 20,
+            slots: vec![
+                Some(("test:pearl".to_string(), 99)),
+                Some(("test:gone".to_string(), 1)),
+                None,
+                Some(("test:stone".to_string(), 3)),
+            ],
+        };
+
+        let inventory = Inventory::from_save(&save, &registry);
+        assert_eq!(inventory.selected, HOTBAR_SIZE - 1);
+        assert_eq!(inventory.slots.len(), INVENTORY_SIZE);
+        assert_eq!(inventory.slots[0], Some(stack(PEARL, 16)));
+        assert_eq!(inventory.slots[1], None);
+        assert_eq!(inventory.slots[3], Some(stack(STONE, 3)));
+    }
+}
@@ -0,0 +1,146 @@
+use crate::inventory::ItemStack;
+use crate::key::Key;
+use crate::registry::Registry;
+
+pub const DEFAULT_MAX_STACK_SIZE: u32 = 64;
+
+/// Something that can be held in an inventory.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Item<'a> {
+    /// How many of the item fit in one inventory slot.
+    pub max_stack_size: u32,
+    /// The block placed when the item is used on the world.
+    pub places: Option<Key<'a>>,
+}
+
+impl<'a> Default for Item<'a> {
+    fn default() -> Self {
+        Item {
+            max_stack_size: DEFAULT_MAX_STACK_SIZE,
+            places: None,
+        }
+    }
+}
+
+/// Registers an item placing each block that can be placed and has no item of
+/// its own yet, under the block's key, and returns their keys. Fluids cannot be
+/// placed.
+pub fn derive_block_items(registry: &mut Registry<'static>) -> Vec<Key<'static>> {
+    let placeable: Vec<Key<'static>> = registry
+        .blocks
+        .iter()
+        .filter(|(key, block)| block.fluid.is_none() && !registry.items.contains_key(*key))
+        .map(|(key, _)| *key)
+        .collect();
+
+    for key in &placeable {
+        registry.items.insert(
+            *key,
+            Item {
+                places: Some(*key),
+                ..Default::default()
+            },
+        );
+    }
+
+    placeable
+}
+
+impl Registry<'static> {
+    pub fn max_stack_size(&self, item: Key) -> u32 {
+        self.items
+            .get(&item)
+            .map_or(DEFAULT_MAX_STACK_SIZE, |item| item.max_stack_size)
+    }
+
+    /// What breaking `block` gives back: its registered drops, or otherwise one
+    /// of the item with the same key, if there is one.
+    pub fn drops(&self, block: Key<'static>) -> Vec<ItemStack> {
+        match self.drops.get(&block) {
+            Some(drops) => drops.clone(),
+            None if self.items.contains_key(&block) => vec![ItemStack {
+                item: block,
+                count: 1,
+            }],
+            None => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::Block;
+    use crate::fluid::Fluid;
+
+    const STONE: Key<'static> = Key {
+        namespace: "test",
+        name: "stone",
+    };
+    const GLASS: Key<'static> = Key {
+        namespace: "test",
+        name: "glass",
+    };
+    const WATER: Key<'static> = Key {
+        namespace: "test",
+        name: "water",
+    };
+
+    fn registry() -> Registry<'static> {
+        let mut registry = Registry::default();
+        registry.blocks.insert(STONE, Block::default());
+        registry.blocks.insert(GLASS, Block::default());
+        registry.blocks.insert(
+            WATER,
+            Block {
+                fluid: Some(Fluid {
+                    flow_distance: 7,
+                    tick_delay: 5,
+                }),
+                ..Default::default()
+            },
+        );
+        registry
+    }
+
+    #[test]
+    fn placeable_blocks_get_an_item() {
+        let mut registry = registry();
+        let mut derived = derive_block_items(&mut registry);
+        derived.sort_by_key(|key| key.to_string());
+
+        assert_eq!(derived, vec![GLASS, STONE]);
+        assert_eq!(registry.items[&STONE].places, Some(STONE));
+        assert!(!registry.items.contains_key(&WATER));
+    }
+
+    #[test]
+    fn blocks_with_an_item_keep_it() {
+        let mut registry = registry();
+        let item = Item {
+            max_stack_size: 16,
+            places: Some(STONE),
+        };
+        registry.items.insert(STONE, item);
+
+        assert_eq!(derive_block_items(&mut registry), vec![GLASS]);
+        assert_eq!(registry.items[&STONE], item);
+    }
+
+    #[test]
+    fn registered_drops_replace_the_block_itself() {
+        let mut registry = registry();
+        derive_block_items(&mut registry);
+        registry.drops.insert(GLASS, Vec::new());
+
+        assert_eq!(
+            registry.drops(STONE),
+            vec![ItemStack {
+                item: STONE,
+                count: 1
+            }]
+        );
+        assert!(registry.drops(GLASS).is_empty());
+        assert!(registry.drops(WATER).is_empty());
+    }
+}
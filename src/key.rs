@@ -1,5 +1,20 @@
+use std::fmt;
+
 #[derive(PartialEq, Eq, Hash, Copy, Clone)]
 pub struct Key<'a> {
     pub namespace: &'a str,
     pub name: &'a str,
 }
+
+/// Formats as `namespace:name`, the form keys are saved in.
+impl<'a> fmt::Display for Key<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.namespace, self.name)
+    }
+}
+
+impl<'a> fmt::Debug for Key<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
@@ -0,0 +1,75 @@
+//! Defaria, a block game built on Bevy.
+//!
+//! The game is put together from the plugins in [`plugins`]. A headless server
+//! or a tool only needs [`plugins::CorePlugin`], which holds the block and item
+//! [`registry::Registry`] and the [`world::World`] of [`chunk::Chunk`]s. The
+//! client adds loading, rendering, the player controller and the connection to
+//! a server on top.
+//!
+//! # Features
+//!
+//! - `render`: meshing chunks, block materials, the texture atlas and the
+//!   client, which all need Bevy's renderer.
+//! - `window`: a window and gamepad input to play in, and assets reloaded as
+//!   they change on disk, on top of `render`. Enabled by default.
+//!
+//! Without `window`, the `defaria` binary can only run a dedicated server.
+
+/// Chunks of blocks and the properties of each block.
+pub mod chunk;
+/// Talking to a server and keeping the local world in step with it.
+#[cfg(feature = "render")]
+pub mod client;
+/// Failures loading block assets and meshing chunks, and what is drawn instead.
+#[cfg(feature = "render")]
+pub mod error;
+/// Water and lava flowing from their sources.
+pub mod fluid;
+/// Game states and the built-in blocks, items and assets.
+pub mod game;
+/// Actions, their key and gamepad bindings, and player settings.
+pub mod input;
+/// Stacks of items and the player's inventory.
+pub mod inventory;
+/// Items, including the ones derived from placeable blocks.
+pub mod item;
+/// Namespaced identifiers for registered content.
+pub mod key;
+/// Sky and block light spreading through chunks.
+pub mod light;
+/// In-memory connections with simulated latency.
+pub mod loopback;
+/// The materials chunk meshes are drawn with.
+#[cfg(feature = "render")]
+pub mod material;
+/// Registering content from mods, each under its own namespace.
+pub mod modding;
+/// Bounding boxes, collisions and raycasts against blocks.
+pub mod physics;
+/// The local player and how they move and edit blocks.
+pub mod player;
+/// The plugins the game is assembled from.
+pub mod plugins;
+/// Showing block edits before the server has answered them.
+pub mod prediction;
+/// The binary protocol between clients and the server.
+pub mod protocol;
+/// Everything registered: blocks, items, drops and how blocks are drawn.
+pub mod registry;
+/// Other players, as the server reports them.
+#[cfg(feature = "render")]
+pub mod remote_player;
+/// Building meshes from chunks.
+#[cfg(feature = "render")]
+pub mod render;
+/// Stacks of resource packs overriding block textures and models.
+#[cfg(feature = "render")]
+pub mod resource_pack;
+/// The server that owns the world, and the dedicated server binary mode.
+pub mod server;
+/// Block updates, scheduled ticks and random ticks.
+pub mod tick;
+/// Converting between world, chunk and local block positions.
+pub mod utils;
+/// The loaded chunks, world time and chunk generation.
+pub mod world;
@@ -0,0 +1,567 @@
+use std::collections::VecDeque;
+use std::ops::DerefMut;
+
+use bevy::prelude::*;
+use bevy::utils::{HashMap, HashSet};
+
+use crate::chunk::{chunk_index, Block, Chunk, CHUNK_SIZE};
+use crate::registry::Registry;
+use crate::utils::{block_neighbours, block_to_chunk, block_to_local, chunk_to_block};
+
+pub const MAX_LIGHT: u8 = 15;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum LightChannel {
+    /// Light coming down from the sky, which travels straight down without fading.
+    Sky,
+    /// The red, green and blue parts of light emitted by blocks, which spread
+    /// independently so differently coloured lights mix.
+    Red,
+    Green,
+    Blue,
+}
+
+const LIGHT_CHANNELS: [LightChannel; 4] = [
+    LightChannel::Sky,
+    LightChannel::Red,
+    LightChannel::Green,
+    LightChannel::Blue,
+];
+
+impl LightChannel {
+    fn level(self, chunk: &Chunk, index: usize) -> u8 {
+        match self {
+            LightChannel::Sky => chunk.sky_light[index],
+            LightChannel::Red => chunk.block_light[index][0],
+            LightChannel::Green => chunk.block_light[index][1],
+            LightChannel::Blue => chunk.block_light[index][2],
+        }
+    }
+
+    fn level_mut<'c>(self, chunk: &'c mut Chunk, index: usize) -> &'c mut u8 {
+        match self {
+            LightChannel::Sky => &mut chunk.sky_light[index],
+            LightChannel::Red => &mut chunk.block_light[index][0],
+            LightChannel::Green => &mut chunk.block_light[index][1],
+            LightChannel::Blue => &mut chunk.block_light[index][2],
+        }
+    }
+}
+
+/// The loaded chunks the light engine floods light through, keyed by chunk position.
+///
+/// Cells in chunks that are not loaded do not carry light, except that any
+/// transparent cell below an unloaded one is treated as open to the sky. Every
+/// operation is a breadth first flood fill over the six face neighbours in a fixed
+/// order, so the result only depends on the blocks and the order of calls.
+pub struct LightWorld<'r, C> {
+    pub chunks: HashMap<IVec3, C>,
+    registry: &'r Registry<'static>,
+    changed_chunks: HashSet<IVec3>,
+}
+
+impl<'r, C: DerefMut<Target = Chunk<'static>>> LightWorld<'r, C> {
+    pub fn new(chunks: HashMap<IVec3, C>, registry: &'r Registry<'static>) -> Self {
+        LightWorld {
+            chunks,
+            registry,
+            changed_chunks: HashSet::default(),
+        }
+    }
+
+    /// Chunks whose meshes can see a cell that changed light since this was created.
+    pub fn changed_chunks(&self) -> &HashSet<IVec3> {
+        &self.changed_chunks
+    }
+
+    pub fn light(&self, channel: LightChannel, position: IVec3) -> u8 {
+        match self.chunks.get(&block_to_chunk(position)) {
+            Some(chunk) => channel.level(chunk, chunk_index(block_to_local(position))),
+            None => 0,
+        }
+    }
+
+    /// Lights a chunk that was just loaded, and relights the neighbouring cells
+    /// that can now see into it.
+    pub fn light_chunk(&mut self, chunk_position: IVec3) {
+        if !self.chunks.contains_key(&chunk_position) {
+            return;
+        }
+
+        // The top of the chunk below used to be open to the sky through this one.
+        let below = chunk_position - IVec3::Y;
+        let mut sky_queue = if self.chunks.contains_key(&below) {
+            let top_layer: Vec<IVec3> = chunk_layer(below, IVec3::Y)
+                .filter(|position| self.light(LightChannel::Sky, *position) > 0)
+                .collect();
+            self.remove(LightChannel::Sky, top_layer)
+        } else {
+            VecDeque::new()
+        };
+
+        for channel in LIGHT_CHANNELS {
+            let mut queue = match channel {
+                LightChannel::Sky => std::mem::take(&mut sky_queue),
+                _ => VecDeque::new(),
+            };
+
+            for x in 0..CHUNK_SIZE as i32 {
+                for y in 0..CHUNK_SIZE as i32 {
+                    for z in 0..CHUNK_SIZE as i32 {
+                        let position = chunk_to_block(chunk_position, IVec3::new(x, y, z));
+                        let source = self.source(channel, position);
+
+                        if source > 0 {
+                            self.set_light(channel, position, source);
+                            queue.push_back(position);
+                        }
+                    }
+                }
+            }
+
+            for direction in [
+                IVec3::X,
+                -IVec3::X,
+                IVec3::Y,
+                -IVec3::Y,
+                IVec3::Z,
+                -IVec3::Z,
+            ] {
+                for position in chunk_layer(chunk_position, direction) {
+                    let neighbour = position + direction;
+
+                    if self.light(channel, neighbour) > 0 {
+                        queue.push_back(neighbour);
+                    }
+                }
+            }
+
+            self.propagate(channel, queue);
+        }
+    }
+
+    /// Relights the world around a block that was just replaced.
+    pub fn update_block(&mut self, position: IVec3) {
+        if !self.chunks.contains_key(&block_to_chunk(position)) {
+            return;
+        }
+
+        for channel in LIGHT_CHANNELS {
+            let mut queue = self.remove(channel, vec![position]);
+
+            for neighbour in block_neighbours(position) {
+                if self.light(channel, neighbour) > 0 {
+                    queue.push_back(neighbour);
+                }
+            }
+
+            self.propagate(channel, queue);
+        }
+    }
+
+    fn is_loaded(&self, position: IVec3) -> bool {
+        self.chunks.contains_key(&block_to_chunk(position))
+    }
+
+    fn block(&self, position: IVec3) -> Option<&'r Block> {
+        self.chunks
+            .get(&block_to_chunk(position))
+            .and_then(|chunk| chunk.block(block_to_local(position)))
+            .and_then(|block| self.registry.blocks.get(&block))
+    }
+
+    fn opacity(&self, position: IVec3) -> u8 {
+        self.block(position)
+            .map_or(0, |block| block.light_opacity.min(MAX_LIGHT))
+    }
+
+    /// The light a cell produces by itself, before any propagation.
+    fn source(&self, channel: LightChannel, position: IVec3) -> u8 {
+        let block = self.block(position);
+
+        match channel {
+            LightChannel::Sky => {
+                if self.is_loaded(position + IVec3::Y) {
+                    0
+                } else {
+                    MAX_LIGHT - self.opacity(position)
+                }
+            }
+            LightChannel::Red => block.map_or(0, |block| block.emitted_light()[0]),
+            LightChannel::Green => block.map_or(0, |block| block.emitted_light()[1]),
+            LightChannel::Blue => block.map_or(0, |block| block.emitted_light()[2]),
+        }
+    }
+
+    fn set_light(&mut self, channel: LightChannel, position: IVec3, level: u8) {
+        let chunk_position = block_to_chunk(position);
+
+        if let Some(chunk) = self.chunks.get_mut(&chunk_position) {
+            let light = channel.level_mut(chunk, chunk_index(block_to_local(position)));
+
+            if *light == level {
+                return;
+            }
+            *light = level;
+
+            self.changed_chunks.insert(chunk_position);
+            for neighbour in block_neighbours(position) {
+                self.changed_chunks.insert(block_to_chunk(neighbour));
+            }
+        }
+    }
+
+    /// The level `from` passes on to its neighbour `to`.
+    fn propagated_level(&self, channel: LightChannel, from: IVec3, to: IVec3, level: u8) -> u8 {
+        let opacity = self.opacity(to);
+
+        if channel == LightChannel::Sky && level == MAX_LIGHT && to.y < from.y && opacity == 0 {
+            MAX_LIGHT
+        } else {
+            level.saturating_sub(1 + opacity)
+        }
+    }
+
+    fn propagate(&mut self, channel: LightChannel, mut queue: VecDeque<IVec3>) {
+        while let Some(position) = queue.pop_front() {
+            let level = self.light(channel, position);
+
+            for neighbour in block_neighbours(position) {
+                if !self.is_loaded(neighbour) {
+                    continue;
+                }
+
+                let new_level = self.propagated_level(channel, position, neighbour, level);
+                if self.light(channel, neighbour) < new_level {
+                    self.set_light(channel, neighbour, new_level);
+                    queue.push_back(neighbour);
+                }
+            }
+        }
+    }
+
+    /// Darkens every cell that got its light through `seeds`, and returns the cells
+    /// the light has to be flooded back in from: brighter cells on the border of the
+    /// darkened area and light sources inside it.
+    fn remove(&mut self, channel: LightChannel, seeds: Vec<IVec3>) -> VecDeque<IVec3> {
+        let mut removal_queue = VecDeque::new();
+        let mut darkened = Vec::new();
+        let mut relight_queue = VecDeque::new();
+
+        for position in seeds {
+            let level = self.light(channel, position);
+            self.set_light(channel, position, 0);
+            removal_queue.push_back((position, level));
+            darkened.push(position);
+        }
+
+        while let Some((position, level)) = removal_queue.pop_front() {
+            for neighbour in block_neighbours(position) {
+                let neighbour_level = self.light(channel, neighbour);
+                if neighbour_level == 0 {
+                    continue;
+                }
+
+                let expected = self.propagated_level(channel, position, neighbour, level);
+                if neighbour_level <= expected {
+                    self.set_light(channel, neighbour, 0);
+                    removal_queue.push_back((neighbour, neighbour_level));
+                    darkened.push(neighbour);
+                } else {
+                    relight_queue.push_back(neighbour);
+                }
+            }
+        }
+
+        for position in darkened {
+            let source = self.source(channel, position);
+
+            if source > 0 {
+                self.set_light(channel, position, source);
+                relight_queue.push_back(position);
+            }
+        }
+
+        relight_queue
+    }
+}
+
+/// The cells of a chunk's outermost layer on the side facing `direction`.
+fn chunk_layer(chunk_position: IVec3, direction: IVec3) -> impl Iterator<Item = IVec3> {
+    let last = CHUNK_SIZE as i32 - 1;
+
+    (0..CHUNK_SIZE as i32).flat_map(move |a| {
+        (0..CHUNK_SIZE as i32).map(move |b| {
+            let local = match (direction.x, direction.y, direction.z) {
+                (1, _, _) => IVec3::new(last, a, b),
+                (-1, _, _) => IVec3::new(0, a, b),
+                (_, 1, _) => IVec3::new(a, last, b),
+                (_, -1, _) => IVec3::new(a, 0, b),
+                (_, _, 1) => IVec3::new(a, b, last),
+                _ => IVec3::new(a, b, 0),
+            };
+
+            chunk_to_block(chunk_position, local)
+        })
+    })
+}
+
+/// Lights newly loaded chunks and marks every chunk whose lighting changed for
+/// remeshing.
+pub fn light_chunks(registry: Res<Registry<'static>>, mut chunks: Query<&mut Chunk<'static>>) {
+    let mut added: Vec<IVec3> = chunks
+        .iter_mut()
+        .filter(|chunk| chunk.is_added())
+        .map(|chunk| chunk.position)
+        .collect();
+
+    if added.is_empty() {
+        return;
+    }
+
+    added.sort_unstable_by_key(|position| position.to_array());
+
+    let mut light_world = LightWorld::new(
+        chunks
+            .iter_mut()
+            .map(|chunk| (chunk.position, chunk))
+            .collect(),
+        &registry,
+    );
+
+    for position in added {
+        light_world.light_chunk(position);
+    }
+
+    let changed_chunks: Vec<IVec3> = light_world.changed_chunks().iter().copied().collect();
+    for position in changed_chunks {
+        if let Some(chunk) = light_world.chunks.get_mut(&position) {
+            chunk.has_changed = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::key::Key;
+
+    const STONE: Key<'static> = Key {
+        namespace: "test",
+        name: "stone",
+    };
+    const LAMP: Key<'static> = Key {
+        namespace: "test",
+        name: "lamp",
+    };
+
+    fn registry() -> Registry<'static> {
+        let mut registry = Registry::default();
+        registry.blocks.insert(
+            STONE,
+            Block {
+                solid: true,
+                light_opacity: MAX_LIGHT,
+                ..default()
+            },
+        );
+        registry.blocks.insert(
+            LAMP,
+            Block {
+                solid: true,
+                light_opacity: MAX_LIGHT,
+                emission: 14,
+                emission_color: Vec3::new(1.0, 0.5, 0.0),
+                ..default()
+            },
+        );
+        registry
+    }
+
+    fn light_world<'r>(
+        registry: &'r Registry<'static>,
+        chunk_positions: &[IVec3],
+    ) -> LightWorld<'r, Box<Chunk<'static>>> {
+        LightWorld::new(
+            chunk_positions
+                .iter()
+                .map(|position| (*position, Box::new(Chunk::new(*position))))
+                .collect(),
+            registry,
+        )
+    }
+
+    fn set_block(
+        light_world: &mut LightWorld<Box<Chunk<'static>>>,
+        position: IVec3,
+        block: Option<Key<'static>>,
+    ) {
+        let chunk = light_world
+            .chunks
+            .get_mut(&block_to_chunk(position))
+            .unwrap();
+        chunk.blocks[chunk_index(block_to_local(position))] = block;
+    }
+
+    #[test]
+    fn sky_light_falls_straight_down() {
+        let registry = registry();
+        let mut light_world = light_world(&registry, &[IVec3::ZERO]);
+        set_block(&mut light_world, IVec3::new(5, 8, 5), Some(STONE));
+        light_world.light_chunk(IVec3::ZERO);
+
+        for y in 9..16 {
+            assert_eq!(
+                light_world.light(LightChannel::Sky, IVec3::new(5, y, 5)),
+                15
+            );
+        }
+        assert_eq!(light_world.light(LightChannel::Sky, IVec3::new(5, 8, 5)), 0);
+        // Under the stone, light only comes in from the side.
+        for y in 0..8 {
+            assert_eq!(
+                light_world.light(LightChannel::Sky, IVec3::new(5, y, 5)),
+                14
+            );
+        }
+        assert_eq!(
+            light_world.light(LightChannel::Sky, IVec3::new(0, 0, 0)),
+            15
+        );
+    }
+
+    #[test]
+    fn a_roof_shades_everything_below() {
+        let registry = registry();
+        let mut light_world = light_world(&registry, &[IVec3::ZERO]);
+        for x in 0..CHUNK_SIZE as i32 {
+            for z in 0..CHUNK_SIZE as i32 {
+                set_block(&mut light_world, IVec3::new(x, 12, z), Some(STONE));
+            }
+        }
+        light_world.light_chunk(IVec3::ZERO);
+
+        assert_eq!(
+            light_world.light(LightChannel::Sky, IVec3::new(3, 13, 3)),
+            15
+        );
+        assert_eq!(
+            light_world.light(LightChannel::Sky, IVec3::new(3, 11, 3)),
+            0
+        );
+        assert_eq!(light_world.light(LightChannel::Sky, IVec3::new(3, 0, 3)), 0);
+    }
+
+    #[test]
+    fn emitted_light_fades_with_distance() {
+        let registry = registry();
+        let mut light_world = light_world(&registry, &[IVec3::ZERO]);
+        let lamp = IVec3::new(8, 8, 8);
+        set_block(&mut light_world, lamp, Some(LAMP));
+        light_world.light_chunk(IVec3::ZERO);
+
+        assert_eq!(light_world.light(LightChannel::Red, lamp), 14);
+        assert_eq!(light_world.light(LightChannel::Green, lamp), 7);
+        assert_eq!(light_world.light(LightChannel::Blue, lamp), 0);
+
+        for distance in 1..8 {
+            let position = lamp + IVec3::new(distance, 0, 0);
+            assert_eq!(
+                light_world.light(LightChannel::Red, position),
+                14 - distance as u8
+            );
+        }
+        assert_eq!(
+            light_world.light(LightChannel::Red, lamp + IVec3::new(2, -3, 1)),
+            8
+        );
+        assert_eq!(
+            light_world.light(LightChannel::Green, lamp + IVec3::new(2, -3, 1)),
+            1
+        );
+        assert_eq!(
+            light_world.light(LightChannel::Green, lamp + IVec3::new(0, 7, 0)),
+            0
+        );
+    }
+
+    #[test]
+    fn removed_light_goes_dark() {
+        let registry = registry();
+        let mut light_world = light_world(&registry, &[IVec3::ZERO]);
+        let lamp = IVec3::new(8, 8, 8);
+        set_block(&mut light_world, lamp, Some(LAMP));
+        light_world.light_chunk(IVec3::ZERO);
+
+        set_block(&mut light_world, lamp, None);
+        light_world.update_block(lamp);
+
+        for x in 0..CHUNK_SIZE as i32 {
+            for y in 0..CHUNK_SIZE as i32 {
+                for z in 0..CHUNK_SIZE as i32 {
+                    let position = IVec3::new(x, y, z);
+                    assert_eq!(light_world.light(LightChannel::Red, position), 0);
+                    assert_eq!(light_world.light(LightChannel::Green, position), 0);
+                }
+            }
+        }
+        assert!(light_world.changed_chunks().contains(&IVec3::ZERO));
+    }
+
+    #[test]
+    fn blocking_and_unblocking_the_sky() {
+        let registry = registry();
+        let mut light_world = light_world(&registry, &[IVec3::ZERO]);
+        light_world.light_chunk(IVec3::ZERO);
+        let roof = IVec3::new(5, 15, 5);
+
+        set_block(&mut light_world, roof, Some(STONE));
+        light_world.update_block(roof);
+        assert_eq!(light_world.light(LightChannel::Sky, roof), 0);
+        assert_eq!(
+            light_world.light(LightChannel::Sky, IVec3::new(5, 3, 5)),
+            14
+        );
+
+        set_block(&mut light_world, roof, None);
+        light_world.update_block(roof);
+        for y in 0..16 {
+            assert_eq!(
+                light_world.light(LightChannel::Sky, IVec3::new(5, y, 5)),
+                15
+            );
+        }
+    }
+
+    #[test]
+    fn light_crosses_chunk_borders() {
+        let registry = registry();
+        let mut light_world = light_world(&registry, &[IVec3::ZERO]);
+        let lamp = IVec3::new(14, 8, 8);
+        set_block(&mut light_world, lamp, Some(LAMP));
+        light_world.light_chunk(IVec3::ZERO);
+
+        // The neighbouring chunk loads after the lamp is lit.
+        let next = IVec3::X;
+        light_world.chunks.insert(next, Box::new(Chunk::new(next)));
+        light_world.light_chunk(next);
+
+        assert_eq!(
+            light_world.light(LightChannel::Red, IVec3::new(16, 8, 8)),
+            12
+        );
+        assert_eq!(
+            light_world.light(LightChannel::Red, IVec3::new(20, 8, 8)),
+            8
+        );
+        assert!(light_world.changed_chunks().contains(&next));
+
+        // Taking the lamp away darkens both chunks.
+        set_block(&mut light_world, lamp, None);
+        light_world.update_block(lamp);
+        assert_eq!(
+            light_world.light(LightChannel::Red, IVec3::new(20, 8, 8)),
+            0
+        );
+    }
+}
@@ -0,0 +1,116 @@
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// The time loopback streams deliver by, in milliseconds. It only moves when
+/// advanced, so tests can step through latency exactly.
+#[derive(Debug, Clone, Default)]
+pub struct LoopbackClock(Arc<AtomicU64>);
+
+impl LoopbackClock {
+    pub fn now(&self) -> u64 {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    pub fn advance(&self, milliseconds: u64) {
+        self.0.fetch_add(milliseconds, Ordering::SeqCst);
+    }
+
+    /// Moves the clock forward to `milliseconds`, if it is not there already.
+    pub fn advance_to(&self, milliseconds: u64) {
+        self.0.fetch_max(milliseconds, Ordering::SeqCst);
+    }
+}
+
+/// Bytes travelling one way, each write stamped with when it arrives.
+#[derive(Default)]
+struct Pipe {
+    writes: VecDeque<(u64, Vec<u8>)>,
+    closed: bool,
+}
+
+/// One end of an in-memory [`Transport`](crate::protocol::Transport) that holds
+/// every write back for a fixed latency before the other end can read it.
+pub struct LoopbackStream {
+    clock: LoopbackClock,
+    latency: u64,
+    incoming: Arc<Mutex<Pipe>>,
+    outgoing: Arc<Mutex<Pipe>>,
+}
+
+/// Two connected ends of a loopback stream, each delivering `latency`
+/// milliseconds of `clock` after the write.
+pub fn loopback_pair(clock: &LoopbackClock, latency: u64) -> (LoopbackStream, LoopbackStream) {
+    let there = Arc::new(Mutex::new(Pipe::default()));
+    let back = Arc::new(Mutex::new(Pipe::default()));
+
+    (
+        LoopbackStream {
+            clock: clock.clone(),
+            latency,
+            incoming: back.clone(),
+            outgoing: there.clone(),
+        },
+        LoopbackStream {
+            clock: clock.clone(),
+            latency,
+            incoming: there,
+            outgoing: back,
+        },
+    )
+}
+
+impl Read for LoopbackStream {
+    fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let now = self.clock.now();
+        let mut pipe = self.incoming.lock().unwrap();
+
+        let mut read = 0;
+        while read < buffer.len() {
+            let bytes = match pipe.writes.front_mut() {
+                Some((arrival, bytes)) if *arrival <= now => bytes,
+                _ => break,
+            };
+
+            let length = bytes.len().min(buffer.len() - read);
+            buffer[read..read + length].copy_from_slice(&bytes[..length]);
+            bytes.drain(..length);
+            read += length;
+
+            if bytes.is_empty() {
+                pipe.writes.pop_front();
+            }
+        }
+
+        if read > 0 || (pipe.closed && pipe.writes.is_empty()) {
+            Ok(read)
+        } else {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+}
+
+impl Write for LoopbackStream {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+        let mut pipe = self.outgoing.lock().unwrap();
+        if pipe.closed {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+
+        pipe.writes
+            .push_back((self.clock.now() + self.latency, bytes.to_vec()));
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for LoopbackStream {
+    fn drop(&mut self) {
+        self.incoming.lock().unwrap().closed = true;
+        self.outgoing.lock().unwrap().closed = true;
+    }
+}
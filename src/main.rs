@@ -1,29 +1,21 @@
-extern crate core;
+#[cfg(feature = "window")]
+use bevy::{asset::AssetServerSettings, prelude::*};
+#[cfg(feature = "window")]
+use defaria::plugins::{ClientPlugin, CorePlugin, LoadingPlugin, PlayerPlugin, RenderPlugin};
+use defaria::server::run_dedicated_server;
 
-use bevy::input::mouse::MouseMotion;
-use bevy::prelude::*;
-use bevy::render::camera::Camera3d;
-use game::run_ingame;
-use registry::ClientRegistry;
-use world::{build_chunks, generate_chunks};
-
-use crate::game::{
-    check_assets, load_assets, load_block_models, load_blocks, AssetHandles, GameState,
-};
-use crate::player::{create_player, grab_mouse, manage_mouse, move_camera, rotate_camera};
-use crate::registry::Registry;
-use crate::world::create_world;
+fn main() {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("server") {
+        run_dedicated_server(&args[1..]);
+        return;
+    }
 
-mod chunk;
-mod game;
-mod key;
-mod player;
-mod registry;
-mod render;
-mod utils;
-mod world;
+    run_client();
+}
 
-fn main() {
+#[cfg(feature = "window")]
+fn run_client() {
     App::new()
         .insert_resource(Msaa { samples: 1 })
         .insert_resource(ClearColor(Color::rgb(0.0, 0.8, 0.9)))
@@ -32,32 +24,22 @@ fn main() {
             height: 600.0,
             ..default()
         })
-        .insert_resource(AssetHandles::default())
-        .insert_resource(Registry::default())
-        .insert_resource(ClientRegistry::default())
+        // Block textures and models are reloaded as they are edited.
+        .insert_resource(AssetServerSettings {
+            watch_for_changes: true,
+            ..default()
+        })
         .add_plugins(DefaultPlugins)
-        .add_state(GameState::Loading)
-        .add_system_set(
-            SystemSet::on_enter(GameState::Loading)
-                .with_system(load_assets)
-                .with_system(load_blocks)
-                .with_system(load_block_models),
-        )
-        .add_system_set(SystemSet::on_update(GameState::Loading).with_system(check_assets))
-        .add_system_set(
-            SystemSet::on_enter(GameState::Ingame)
-                .with_system(grab_mouse)
-                .with_system(create_player)
-                .with_system(create_world),
-        )
-        .add_system_set(
-            SystemSet::new()
-                .with_run_criteria(run_ingame)
-                .with_system(manage_mouse)
-                .with_system(rotate_camera)
-                .with_system(move_camera)
-                .with_system(generate_chunks)
-                .with_system(build_chunks),
-        )
+        .add_plugin(CorePlugin::default())
+        .add_plugin(LoadingPlugin::default())
+        .add_plugin(RenderPlugin::default())
+        .add_plugin(PlayerPlugin::default())
+        .add_plugin(ClientPlugin)
         .run();
 }
+
+#[cfg(not(feature = "window"))]
+fn run_client() {
+    eprintln!("This build of Defaria has no window. Run `defaria server` for a dedicated server.");
+    std::process::exit(2);
+}
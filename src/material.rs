@@ -0,0 +1,349 @@
+use bevy::{
+    ecs::system::{lifetimeless::SRes, SystemParamItem},
+    pbr::MaterialPipeline,
+    prelude::*,
+    reflect::TypeUuid,
+    render::{
+        mesh::{MeshVertexAttribute, MeshVertexBufferLayout},
+        render_asset::{PrepareAssetError, RenderAsset, RenderAssets},
+        render_resource::{
+            std140::{AsStd140, Std140},
+            AddressMode, BindGroup, BindGroupDescriptor, BindGroupEntry, BindGroupLayout,
+            BindGroupLayoutDescriptor, BindGroupLayoutEntry, BindingResource, BindingType, Buffer,
+            BufferBindingType, BufferInitDescriptor, BufferSize, BufferUsages, Extent3d,
+            FilterMode, RenderPipelineDescriptor, SamplerBindingType, ShaderStages,
+            SpecializedMeshPipelineError, TextureDimension, TextureSampleType,
+            TextureViewDimension, VertexFormat,
+        },
+        renderer::RenderDevice,
+    },
+    utils::HashMap,
+};
+
+/// Index of the texture array layer a vertex samples from.
+pub const ATTRIBUTE_TEXTURE_LAYER: MeshVertexAttribute =
+    MeshVertexAttribute::new("Vertex_TextureLayer", 1150476461, VertexFormat::Uint32);
+
+/// Per vertex ambient occlusion, from `0.0` when fully occluded to `1.0`.
+pub const ATTRIBUTE_AMBIENT_OCCLUSION: MeshVertexAttribute =
+    MeshVertexAttribute::new("Vertex_AmbientOcclusion", 1150476462, VertexFormat::Float32);
+
+/// Colour of the sky and block light reaching a vertex.
+pub const ATTRIBUTE_LIGHT_COLOR: MeshVertexAttribute =
+    MeshVertexAttribute::new("Vertex_LightColor", 1150476463, VertexFormat::Float32x3);
+
+/// Which material chunk meshes are rendered with.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum BlockMaterialMode {
+    /// A `StandardMaterial` sampling the packed texture atlas. It has no use for
+    /// the ambient occlusion and light colour of each vertex, so chunks are drawn
+    /// evenly lit.
+    Atlas,
+    /// A [`BlockMaterial`] sampling one texture array layer per block texture.
+    #[default]
+    TextureArray,
+}
+
+/// The material chunk meshes are meant to be rendered with. The
+/// [`BlockMaterialMode`] in use falls back to the atlas whenever the block
+/// textures cannot be stacked into a texture array, and comes back to this once
+/// they can.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct PreferredMaterialMode(pub BlockMaterialMode);
+
+#[derive(Debug, Clone, TypeUuid)]
+#[uuid = "f6b4e34b-255f-48b9-9617-0f0bab5e243a"]
+pub struct BlockMaterial {
+    pub texture_array: Handle<Image>,
+    pub alpha_mode: AlphaMode,
+}
+
+#[derive(Clone, AsStd140)]
+struct BlockMaterialUniform {
+    /// Pixels less opaque than this are discarded.
+    alpha_cutoff: f32,
+}
+
+#[derive(Clone)]
+pub struct GpuBlockMaterial {
+    _buffer: Buffer,
+    bind_group: BindGroup,
+    alpha_mode: AlphaMode,
+}
+
+impl RenderAsset for BlockMaterial {
+    type ExtractedAsset = BlockMaterial;
+    type PreparedAsset = GpuBlockMaterial;
+    type Param = (
+        SRes<RenderDevice>,
+        SRes<MaterialPipeline<Self>>,
+        SRes<RenderAssets<Image>>,
+    );
+
+    fn extract_asset(&self) -> Self::ExtractedAsset {
+        self.clone()
+    }
+
+    fn prepare_asset(
+        material: Self::ExtractedAsset,
+        (render_device, material_pipeline, gpu_images): &mut SystemParamItem<Self::Param>,
+    ) -> Result<Self::PreparedAsset, PrepareAssetError<Self::ExtractedAsset>> {
+        let gpu_image = if let Some(gpu_image) = gpu_images.get(&material.texture_array) {
+            gpu_image
+        } else {
+            return Err(PrepareAssetError::RetryNextUpdate(material));
+        };
+
+        let uniform = BlockMaterialUniform {
+            alpha_cutoff: match material.alpha_mode {
+                AlphaMode::Mask(alpha_cutoff) => alpha_cutoff,
+                AlphaMode::Opaque | AlphaMode::Blend => 0.0,
+            },
+        };
+        let buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
+            contents: uniform.as_std140().as_bytes(),
+            label: Some("block_material_uniform_buffer"),
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+        });
+
+        let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: BindingResource::TextureView(&gpu_image.texture_view),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: BindingResource::Sampler(&gpu_image.sampler),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: buffer.as_entire_binding(),
+                },
+            ],
+            label: Some("block_material_bind_group"),
+            layout: &material_pipeline.material_layout,
+        });
+
+        Ok(GpuBlockMaterial {
+            _buffer: buffer,
+            bind_group,
+            alpha_mode: material.alpha_mode,
+        })
+    }
+}
+
+impl Material for BlockMaterial {
+    fn vertex_shader(asset_server: &AssetServer) -> Option<Handle<Shader>> {
+        Some(asset_server.load("shaders/block.wgsl"))
+    }
+
+    fn fragment_shader(asset_server: &AssetServer) -> Option<Handle<Shader>> {
+        Some(asset_server.load("shaders/block.wgsl"))
+    }
+
+    fn bind_group(render_asset: &<Self as RenderAsset>::PreparedAsset) -> &BindGroup {
+        &render_asset.bind_group
+    }
+
+    fn alpha_mode(render_asset: &<Self as RenderAsset>::PreparedAsset) -> AlphaMode {
+        render_asset.alpha_mode
+    }
+
+    fn bind_group_layout(render_device: &RenderDevice) -> BindGroupLayout {
+        render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            entries: &[
+                BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Texture {
+                        multisampled: false,
+                        sample_type: TextureSampleType::Float { filterable: true },
+                        view_dimension: TextureViewDimension::D2Array,
+                    },
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Sampler(SamplerBindingType::Filtering),
+                    count: None,
+                },
+                BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: ShaderStages::FRAGMENT,
+                    ty: BindingType::Buffer {
+                        ty: BufferBindingType::Uniform,
+                        has_dynamic_offset: false,
+                        min_binding_size: BufferSize::new(
+                            BlockMaterialUniform::std140_size_static() as u64,
+                        ),
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("block_material_layout"),
+        })
+    }
+
+    fn specialize(
+        _pipeline: &MaterialPipeline<Self>,
+        descriptor: &mut RenderPipelineDescriptor,
+        layout: &MeshVertexBufferLayout,
+    ) -> Result<(), SpecializedMeshPipelineError> {
+        let vertex_layout = layout.get_layout(&[
+            Mesh::ATTRIBUTE_POSITION.at_shader_location(0),
+            Mesh::ATTRIBUTE_NORMAL.at_shader_location(1),
+            Mesh::ATTRIBUTE_UV_0.at_shader_location(2),
+            ATTRIBUTE_TEXTURE_LAYER.at_shader_location(3),
+            ATTRIBUTE_AMBIENT_OCCLUSION.at_shader_location(4),
+            ATTRIBUTE_LIGHT_COLOR.at_shader_location(5),
+        ])?;
+        descriptor.vertex.buffers = vec![vertex_layout];
+        Ok(())
+    }
+}
+
+/// Assigns one texture array layer to every distinct texture path.
+///
+/// Paths are sorted before they are numbered, so the same set of textures always
+/// ends up in the same layers regardless of the order models were registered in.
+pub fn assign_texture_layers<'a>(
+    textures: impl IntoIterator<Item = &'a str>,
+) -> HashMap<&'a str, u32> {
+    let mut textures: Vec<&str> = textures.into_iter().collect();
+    textures.sort_unstable();
+    textures.dedup();
+
+    textures
+        .into_iter()
+        .enumerate()
+        .map(|(layer, texture)| (texture, layer as u32))
+        .collect()
+}
+
+/// Stacks equally sized images into a single 2D texture array, in layer order.
+/// Returns `None` if there are no images or they differ in size or format.
+///
+/// A texture with a single layer gets a 2D view instead of an array view, so the
+/// last layer is repeated until there are at least two.
+pub fn build_texture_array(layers: &[&Image]) -> Option<Image> {
+    let first = layers.first()?;
+    let size = first.texture_descriptor.size;
+    let format = first.texture_descriptor.format;
+    let layer_count = layers.len().max(2);
+
+    let mut data = Vec::with_capacity(first.data.len() * layer_count);
+    for i in 0..layer_count {
+        let layer = layers[i.min(layers.len() - 1)];
+
+        if layer.texture_descriptor.size != size || layer.texture_descriptor.format != format {
+            return None;
+        }
+
+        data.extend_from_slice(&layer.data);
+    }
+
+    let mut image = Image::new(
+        Extent3d {
+            width: size.width,
+            height: size.height * layer_count as u32,
+            depth_or_array_layers: 1,
+        },
+        TextureDimension::D2,
+        data,
+        format,
+    );
+    image.reinterpret_stacked_2d_as_array(layer_count as u32);
+
+    image.sampler_descriptor.address_mode_u = AddressMode::Repeat;
+    image.sampler_descriptor.address_mode_v = AddressMode::Repeat;
+    image.sampler_descriptor.mag_filter = FilterMode::Nearest;
+    image.sampler_descriptor.min_filter = FilterMode::Nearest;
+
+    Some(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::render::render_resource::TextureFormat;
+
+    use super::*;
+
+    fn image(size: u32, value: u8, format: TextureFormat) -> Image {
+        let pixel_size = format.describe().block_size as usize;
+
+        Image::new(
+            Extent3d {
+                width: size,
+                height: size,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            vec![value; (size * size) as usize * pixel_size],
+            format,
+        )
+    }
+
+    #[test]
+    fn texture_layers_are_numbered_in_path_order() {
+        let layers = assign_texture_layers(["b.png", "a.png", "c.png", "a.png"]);
+
+        assert_eq!(layers.len(), 3);
+        assert_eq!(layers["a.png"], 0);
+        assert_eq!(layers["b.png"], 1);
+        assert_eq!(layers["c.png"], 2);
+        assert_eq!(assign_texture_layers(["c.png", "a.png", "b.png"]), layers);
+    }
+
+    #[test]
+    fn textures_stack_in_layer_order() {
+        let format = TextureFormat::Rgba8UnormSrgb;
+        let layers = [
+            image(4, 1, format),
+            image(4, 2, format),
+            image(4, 3, format),
+        ];
+        let array = build_texture_array(&layers.iter().collect::<Vec<_>>()).unwrap();
+
+        assert_eq!(
+            array.texture_descriptor.size,
+            Extent3d {
+                width: 4,
+                height: 4,
+                depth_or_array_layers: 3,
+            }
+        );
+        assert_eq!(array.texture_descriptor.format, format);
+
+        let layer_bytes = 4 * 4 * 4;
+        assert_eq!(array.data.len(), 3 * layer_bytes);
+        for (layer, value) in [1, 2, 3].into_iter().enumerate() {
+            assert!(array.data[layer * layer_bytes..(layer + 1) * layer_bytes]
+                .iter()
+                .all(|byte| *byte == value));
+        }
+    }
+
+    #[test]
+    fn single_textures_are_repeated_into_an_array() {
+        let layer = image(8, 5, TextureFormat::Rgba8UnormSrgb);
+        let array = build_texture_array(&[&layer]).unwrap();
+
+        assert_eq!(array.texture_descriptor.size.depth_or_array_layers, 2);
+        assert_eq!(array.data.len(), 2 * layer.data.len());
+    }
+
+    #[test]
+    fn mismatched_textures_do_not_stack() {
+        let format = TextureFormat::Rgba8UnormSrgb;
+
+        assert!(build_texture_array(&[]).is_none());
+        assert!(build_texture_array(&[&image(4, 0, format), &image(8, 0, format)]).is_none());
+        assert!(build_texture_array(&[
+            &image(4, 0, format),
+            &image(4, 0, TextureFormat::Rgba8Unorm),
+        ])
+        .is_none());
+    }
+}
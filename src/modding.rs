@@ -0,0 +1,551 @@
+use std::fmt;
+
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+
+use crate::chunk::Block;
+use crate::inventory::ItemStack;
+use crate::item::{derive_block_items, Item};
+use crate::key::Key;
+#[cfg(feature = "render")]
+use crate::registry::ClientRegistry;
+use crate::registry::Registry;
+#[cfg(feature = "render")]
+use crate::render::BlockModel;
+use crate::tick::BlockBehaviour;
+
+/// The startup stages content is registered in, one after the other and before
+/// [`StartupStage::Startup`], so everything is in before anything is loaded.
+///
+/// A mod is a plugin adding systems to these stages, added after the
+/// [`CorePlugin`](crate::plugins::CorePlugin) that creates them:
+///
+/// ```no_run
+/// # use bevy::prelude::*;
+/// # use defaria::{chunk::Block, key::Key, modding::RegistryStage};
+/// # use defaria::{plugins::CorePlugin, registry::Registry};
+/// fn register_rubies(mut registry: ResMut<Registry<'static>>) {
+///     let mut rubies = registry.for_mod("rubies");
+///     rubies.block("ruby_ore", Block { solid: true, ..default() });
+/// }
+///
+/// fn unbreakable_glass(mut registry: ResMut<Registry<'static>>) {
+///     let glass = Key { namespace: "defaria", name: "glass" };
+///     registry.for_mod("rubies").override_drops(glass, vec![]);
+/// }
+///
+/// App::new()
+///     .add_plugin(CorePlugin::default())
+///     .add_startup_system_to_stage(RegistryStage::Register, register_rubies)
+///     .add_startup_system_to_stage(RegistryStage::Override, unbreakable_glass)
+///     .run();
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash, StageLabel)]
+pub enum RegistryStage {
+    /// Mods register their own content, under their own namespace.
+    Register,
+    /// Mods override content other mods registered.
+    Override,
+    /// Entries derived from others are added, and what went wrong and what each
+    /// mod contributed is reported.
+    Finish,
+}
+
+/// The kinds of entry in the registries.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum EntryKind {
+    Block,
+    Behaviour,
+    Item,
+    Drops,
+    BlockModel,
+}
+
+impl fmt::Display for EntryKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EntryKind::Block => write!(f, "block"),
+            EntryKind::Behaviour => write!(f, "block behaviour"),
+            EntryKind::Item => write!(f, "item"),
+            EntryKind::Drops => write!(f, "drops"),
+            EntryKind::BlockModel => write!(f, "block model"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RegistryError {
+    /// A mod registered a key that is already taken. The first registration
+    /// is kept.
+    Duplicate {
+        kind: EntryKind,
+        key: Key<'static>,
+        registered_by: &'static str,
+        by: &'static str,
+    },
+    /// A mod overrode an entry nobody registered.
+    NothingToOverride {
+        kind: EntryKind,
+        key: Key<'static>,
+        by: &'static str,
+    },
+}
+
+impl fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RegistryError::Duplicate {
+                kind,
+                key,
+                registered_by,
+                by,
+            } => write!(
+                f,
+                "{} registered the {} {}, which {} already registered",
+                by, kind, key, registered_by
+            ),
+            RegistryError::NothingToOverride { kind, key, by } => {
+                write!(
+                    f,
+                    "{} overrode the {} {}, which nobody registered",
+                    by, kind, key
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for RegistryError {}
+
+/// The mod that registered an entry, and the mods that overrode it since in the
+/// order they did.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Contribution {
+    pub registered_by: &'static str,
+    pub overridden_by: Vec<&'static str>,
+}
+
+impl Contribution {
+    /// The mod whose version of the entry is in use.
+    pub fn current(&self) -> &'static str {
+        self.overridden_by
+            .last()
+            .copied()
+            .unwrap_or(self.registered_by)
+    }
+}
+
+/// Which mods every registry entry came from, and the mistakes made registering
+/// them.
+#[derive(Debug, Default)]
+pub struct Contributions {
+    entries: HashMap<(EntryKind, Key<'static>), Contribution>,
+    pub errors: Vec<RegistryError>,
+}
+
+impl Contributions {
+    pub fn get(&self, kind: EntryKind, key: Key<'static>) -> Option<&Contribution> {
+        self.entries.get(&(kind, key))
+    }
+
+    /// Every entry with where it came from, ordered by kind and key.
+    pub fn list(&self) -> Vec<(EntryKind, Key<'static>, &Contribution)> {
+        let mut list: Vec<_> = self
+            .entries
+            .iter()
+            .map(|((kind, key), contribution)| (*kind, *key, contribution))
+            .collect();
+        list.sort_by_key(|(kind, key, _)| (*kind, key.to_string()));
+        list
+    }
+
+    /// Credits an entry derived from another one to the mod whose version of
+    /// that one is in use.
+    pub fn derive(&mut self, kind: EntryKind, key: Key<'static>, from: (EntryKind, Key<'static>)) {
+        if let Some(registered_by) = self.entries.get(&from).map(Contribution::current) {
+            self.entries.insert(
+                (kind, key),
+                Contribution {
+                    registered_by,
+                    overridden_by: Vec::new(),
+                },
+            );
+        }
+    }
+
+    /// Puts `value` into `entries` for `by`, as a new entry or an override of an
+    /// existing one, or records why it cannot.
+    fn insert<T>(
+        &mut self,
+        entries: &mut HashMap<Key<'static>, T>,
+        kind: EntryKind,
+        key: Key<'static>,
+        value: T,
+        by: &'static str,
+        overriding: bool,
+    ) {
+        match (self.entries.get_mut(&(kind, key)), overriding) {
+            (Some(contribution), true) => {
+                contribution.overridden_by.push(by);
+                entries.insert(key, value);
+            }
+            (Some(contribution), false) => self.errors.push(RegistryError::Duplicate {
+                kind,
+                key,
+                registered_by: contribution.registered_by,
+                by,
+            }),
+            (None, true) => self
+                .errors
+                .push(RegistryError::NothingToOverride { kind, key, by }),
+            (None, false) => {
+                self.entries.insert(
+                    (kind, key),
+                    Contribution {
+                        registered_by: by,
+                        overridden_by: Vec::new(),
+                    },
+                );
+                entries.insert(key, value);
+            }
+        }
+    }
+
+    /// Logs the errors, then what each mod contributed.
+    fn report(&self) {
+        for error in &self.errors {
+            error!("{}", error);
+        }
+
+        let mut counts: HashMap<&'static str, usize> = HashMap::default();
+        for (kind, key, contribution) in self.list() {
+            debug!("{} {} from {}", kind, key, contribution.current());
+            *counts.entry(contribution.registered_by).or_default() += 1;
+        }
+
+        let mut counts: Vec<_> = counts.into_iter().collect();
+        counts.sort_unstable();
+        for (namespace, count) in counts {
+            info!("{} registered {} entries", namespace, count);
+        }
+    }
+}
+
+/// Registers the content of one mod, keyed under its namespace.
+pub struct ModRegistrar<'r> {
+    namespace: &'static str,
+    registry: &'r mut Registry<'static>,
+}
+
+impl Registry<'static> {
+    /// Registers content as the mod `namespace`.
+    pub fn for_mod(&mut self, namespace: &'static str) -> ModRegistrar<'_> {
+        ModRegistrar {
+            namespace,
+            registry: self,
+        }
+    }
+}
+
+impl<'r> ModRegistrar<'r> {
+    /// The key `name` has in this mod.
+    pub fn key(&self, name: &'static str) -> Key<'static> {
+        Key {
+            namespace: self.namespace,
+            name,
+        }
+    }
+
+    pub fn block(&mut self, name: &'static str, block: Block) {
+        let key = self.key(name);
+        let registry = &mut *self.registry;
+        registry.contributions.insert(
+            &mut registry.blocks,
+            EntryKind::Block,
+            key,
+            block,
+            self.namespace,
+            false,
+        );
+    }
+
+    pub fn behaviour(&mut self, name: &'static str, behaviour: BlockBehaviour) {
+        let key = self.key(name);
+        let registry = &mut *self.registry;
+        registry.contributions.insert(
+            &mut registry.behaviours,
+            EntryKind::Behaviour,
+            key,
+            behaviour,
+            self.namespace,
+            false,
+        );
+    }
+
+    pub fn item(&mut self, name: &'static str, item: Item<'static>) {
+        let key = self.key(name);
+        let registry = &mut *self.registry;
+        registry.contributions.insert(
+            &mut registry.items,
+            EntryKind::Item,
+            key,
+            item,
+            self.namespace,
+            false,
+        );
+    }
+
+    /// What breaking the block `name` gives back, instead of its own item.
+    pub fn drops(&mut self, name: &'static str, drops: Vec<ItemStack>) {
+        let key = self.key(name);
+        let registry = &mut *self.registry;
+        registry.contributions.insert(
+            &mut registry.drops,
+            EntryKind::Drops,
+            key,
+            drops,
+            self.namespace,
+            false,
+        );
+    }
+
+    /// Replaces a block another mod registered.
+    pub fn override_block(&mut self, key: Key<'static>, block: Block) {
+        let registry = &mut *self.registry;
+        registry.contributions.insert(
+            &mut registry.blocks,
+            EntryKind::Block,
+            key,
+            block,
+            self.namespace,
+            true,
+        );
+    }
+
+    pub fn override_behaviour(&mut self, key: Key<'static>, behaviour: BlockBehaviour) {
+        let registry = &mut *self.registry;
+        registry.contributions.insert(
+            &mut registry.behaviours,
+            EntryKind::Behaviour,
+            key,
+            behaviour,
+            self.namespace,
+            true,
+        );
+    }
+
+    pub fn override_item(&mut self, key: Key<'static>, item: Item<'static>) {
+        let registry = &mut *self.registry;
+        registry.contributions.insert(
+            &mut registry.items,
+            EntryKind::Item,
+            key,
+            item,
+            self.namespace,
+            true,
+        );
+    }
+
+    pub fn override_drops(&mut self, key: Key<'static>, drops: Vec<ItemStack>) {
+        let registry = &mut *self.registry;
+        registry.contributions.insert(
+            &mut registry.drops,
+            EntryKind::Drops,
+            key,
+            drops,
+            self.namespace,
+            true,
+        );
+    }
+}
+
+/// Registers the block models of one mod, keyed under its namespace.
+#[cfg(feature = "render")]
+pub struct ModelRegistrar<'r> {
+    namespace: &'static str,
+    registry: &'r mut ClientRegistry<'static>,
+}
+
+#[cfg(feature = "render")]
+impl ClientRegistry<'static> {
+    /// Registers block models as the mod `namespace`.
+    pub fn for_mod(&mut self, namespace: &'static str) -> ModelRegistrar<'_> {
+        ModelRegistrar {
+            namespace,
+            registry: self,
+        }
+    }
+}
+
+#[cfg(feature = "render")]
+impl<'r> ModelRegistrar<'r> {
+    pub fn block_model(&mut self, name: &'static str, model: BlockModel<'static>) {
+        let key = Key {
+            namespace: self.namespace,
+            name,
+        };
+        let registry = &mut *self.registry;
+        registry.contributions.insert(
+            &mut registry.block_models,
+            EntryKind::BlockModel,
+            key,
+            model,
+            self.namespace,
+            false,
+        );
+    }
+
+    /// Replaces how a block another mod registered is drawn.
+    pub fn override_block_model(&mut self, key: Key<'static>, model: BlockModel<'static>) {
+        let registry = &mut *self.registry;
+        registry.contributions.insert(
+            &mut registry.block_models,
+            EntryKind::BlockModel,
+            key,
+            model,
+            self.namespace,
+            true,
+        );
+    }
+}
+
+/// Derives an item for each placeable block without one, then reports what was
+/// registered.
+pub fn finish_registration(mut registry: ResMut<Registry<'static>>) {
+    let derived = derive_block_items(&mut registry);
+    for key in derived {
+        registry
+            .contributions
+            .derive(EntryKind::Item, key, (EntryKind::Block, key));
+    }
+
+    registry.contributions.report();
+}
+
+/// Reports which block models were registered. Blocks without one are reported
+/// once the block textures are laid out.
+#[cfg(feature = "render")]
+pub fn finish_model_registration(client_registry: Res<ClientRegistry<'static>>) {
+    client_registry.contributions.report();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::plugins::CorePlugin;
+
+    const ORE: Key<'static> = Key {
+        namespace: "rubies",
+        name: "ruby_ore",
+    };
+
+    fn ore(emission: u8) -> Block {
+        Block {
+            solid: true,
+            emission,
+            ..default()
+        }
+    }
+
+    #[test]
+    fn registering_a_taken_key_keeps_the_first() {
+        let mut registry = Registry::default();
+        registry.for_mod("rubies").block("ruby_ore", ore(1));
+        registry.for_mod("rubies").block("ruby_ore", ore(2));
+
+        assert_eq!(registry.blocks[&ORE].emission, 1);
+        assert_eq!(
+            registry.contributions.errors,
+            [RegistryError::Duplicate {
+                kind: EntryKind::Block,
+                key: ORE,
+                registered_by: "rubies",
+                by: "rubies",
+            }]
+        );
+    }
+
+    #[test]
+    fn a_mod_overrides_another() {
+        let mut registry = Registry::default();
+        registry.for_mod("rubies").block("ruby_ore", ore(1));
+        registry.for_mod("glowing").override_block(ORE, ore(9));
+
+        assert_eq!(registry.blocks[&ORE].emission, 9);
+        assert!(registry.contributions.errors.is_empty());
+    }
+
+    #[test]
+    fn overriding_nothing_is_an_error() {
+        let mut registry = Registry::default();
+        registry.for_mod("glowing").override_block(ORE, ore(9));
+
+        assert!(registry.blocks.is_empty());
+        assert!(registry.contributions.get(EntryKind::Block, ORE).is_none());
+        assert_eq!(
+            registry.contributions.errors,
+            [RegistryError::NothingToOverride {
+                kind: EntryKind::Block,
+                key: ORE,
+                by: "glowing",
+            }]
+        );
+    }
+
+    #[test]
+    fn contributions_name_who_registered_and_overrode_each_entry() {
+        let mut registry = Registry::default();
+        registry.for_mod("rubies").block("ruby_ore", ore(1));
+        registry.for_mod("rubies").drops("ruby_ore", Vec::new());
+        registry.for_mod("glowing").override_block(ORE, ore(9));
+        registry.for_mod("brighter").override_block(ORE, ore(12));
+
+        let contribution = registry.contributions.get(EntryKind::Block, ORE).unwrap();
+        assert_eq!(contribution.registered_by, "rubies");
+        assert_eq!(contribution.overridden_by, ["glowing", "brighter"]);
+        assert_eq!(contribution.current(), "brighter");
+
+        let list: Vec<_> = registry
+            .contributions
+            .list()
+            .into_iter()
+            .map(|(kind, key, contribution)| (kind, key, contribution.current()))
+            .collect();
+        assert_eq!(
+            list,
+            [
+                (EntryKind::Block, ORE, "brighter"),
+                (EntryKind::Drops, ORE, "rubies"),
+            ]
+        );
+    }
+
+    fn register_ore(mut registry: ResMut<Registry<'static>>) {
+        registry.for_mod("rubies").block("ruby_ore", ore(1));
+    }
+
+    fn override_ore(mut registry: ResMut<Registry<'static>>) {
+        registry.for_mod("glowing").override_block(ORE, ore(9));
+    }
+
+    #[test]
+    fn overrides_run_after_registration_and_items_are_derived_last() {
+        let mut app = App::new();
+        app.add_plugin(CorePlugin {
+            builtin_content: false,
+            simulate: false,
+        })
+        // Added in the opposite order to the stages they run in.
+        .add_startup_system_to_stage(RegistryStage::Override, override_ore)
+        .add_startup_system_to_stage(RegistryStage::Register, register_ore);
+        app.update();
+
+        let registry = app.world.resource::<Registry<'static>>();
+        assert!(registry.contributions.errors.is_empty());
+        assert_eq!(registry.blocks[&ORE].emission, 9);
+        assert_eq!(registry.items[&ORE].places, Some(ORE));
+        // The derived item is credited to the mod whose block is in use.
+        let item = registry.contributions.get(EntryKind::Item, ORE).unwrap();
+        assert_eq!(item.current(), "glowing");
+    }
+}
@@ -0,0 +1,463 @@
+use bevy::prelude::*;
+
+/// Distance kept between touching boxes so rounding never lets them overlap.
+const EPSILON: f32 = 1e-5;
+
+/// An axis aligned box in world space.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct BoundingBox {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl BoundingBox {
+    pub fn new(min: Vec3, max: Vec3) -> Self {
+        BoundingBox { min, max }
+    }
+
+    /// The box filling the block at `position`.
+    pub fn block(position: IVec3) -> Self {
+        let min = position.as_vec3();
+
+        BoundingBox::new(min, min + Vec3::ONE)
+    }
+
+    pub fn translate(self, offset: Vec3) -> Self {
+        BoundingBox::new(self.min + offset, self.max + offset)
+    }
+
+    pub fn overlaps(self, other: BoundingBox) -> bool {
+        (0..3).all(|axis| self.min[axis] < other.max[axis] && other.min[axis] < self.max[axis])
+    }
+
+    /// Whether any solid block overlaps this box.
+    fn overlaps_solid(self, is_solid: &impl Fn(IVec3) -> bool) -> bool {
+        let min = (self.min + Vec3::splat(EPSILON)).floor().as_ivec3();
+        let max = (self.max - Vec3::splat(EPSILON)).ceil().as_ivec3();
+
+        (min.x..max.x)
+            .flat_map(|x| {
+                (min.y..max.y).flat_map(move |y| (min.z..max.z).map(move |z| IVec3::new(x, y, z)))
+            })
+            .any(is_solid)
+    }
+
+    /// Every block cell that overlaps this box or the space it sweeps through
+    /// while moving by `motion`.
+    fn swept_blocks(self, motion: Vec3) -> impl Iterator<Item = IVec3> {
+        let min = self.min.min(self.min + motion).floor().as_ivec3();
+        let max = self.max.max(self.max + motion).ceil().as_ivec3();
+
+        (min.x..max.x).flat_map(move |x| {
+            (min.y..max.y).flat_map(move |y| (min.z..max.z).map(move |z| IVec3::new(x, y, z)))
+        })
+    }
+
+    /// How far this box can move along `axis`, up to `distance`, before it hits
+    /// `other`. Boxes that do not overlap on the other two axes never collide.
+    fn clip(self, other: BoundingBox, axis: usize, distance: f32) -> f32 {
+        for other_axis in 0..3 {
+            if other_axis != axis
+                && (self.max[other_axis] <= other.min[other_axis] + EPSILON
+                    || self.min[other_axis] >= other.max[other_axis] - EPSILON)
+            {
+                return distance;
+            }
+        }
+
+        if distance > 0.0 && self.max[axis] <= other.min[axis] + EPSILON {
+            distance
+                .min(other.min[axis] - self.max[axis] - EPSILON)
+                .max(0.0)
+        } else if distance < 0.0 && self.min[axis] >= other.max[axis] - EPSILON {
+            distance
+                .max(other.max[axis] - self.min[axis] + EPSILON)
+                .min(0.0)
+        } else {
+            distance
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Collision {
+    /// How far the box actually moved.
+    pub motion: Vec3,
+    /// Which of the x, y and z axes the box was stopped on.
+    pub collided: [bool; 3],
+    /// Whether the box ended up resting on top of a solid block.
+    pub on_ground: bool,
+}
+
+/// Moves `bounding_box` by `motion` one axis at a time, vertical first, stopping
+/// at the first solid block on each axis.
+pub fn sweep(
+    bounding_box: BoundingBox,
+    motion: Vec3,
+    is_solid: &impl Fn(IVec3) -> bool,
+) -> Collision {
+    let solid_boxes: Vec<BoundingBox> = bounding_box
+        .swept_blocks(motion)
+        .filter(|position| is_solid(*position))
+        .map(BoundingBox::block)
+        .collect();
+
+    let mut moved = bounding_box;
+    let mut actual = Vec3::ZERO;
+
+    for axis in [1, 0, 2] {
+        let distance = solid_boxes.iter().fold(motion[axis], |distance, solid| {
+            moved.clip(*solid, axis, distance)
+        });
+
+        actual[axis] = distance;
+        let mut offset = Vec3::ZERO;
+        offset[axis] = distance;
+        moved = moved.translate(offset);
+    }
+
+    let collided = [
+        actual.x != motion.x,
+        actual.y != motion.y,
+        actual.z != motion.z,
+    ];
+
+    Collision {
+        motion: actual,
+        collided,
+        on_ground: collided[1] && motion.y < 0.0,
+    }
+}
+
+/// Moves a box standing on the ground by `motion`, climbing onto ledges up to
+/// `step_height` high when walking into them would otherwise stop it.
+pub fn move_and_collide(
+    bounding_box: BoundingBox,
+    motion: Vec3,
+    step_height: f32,
+    is_solid: &impl Fn(IVec3) -> bool,
+) -> Collision {
+    let collision = sweep(bounding_box, motion, is_solid);
+
+    let blocked_sideways = collision.collided[0] || collision.collided[2];
+    if !blocked_sideways || !collision.on_ground || step_height <= 0.0 {
+        return collision;
+    }
+
+    // Lift the box, walk, then settle back down onto whatever it walked onto.
+    let up = sweep(bounding_box, Vec3::Y * step_height, is_solid).motion;
+    let lifted = bounding_box.translate(up);
+    let across = sweep(lifted, Vec3::new(motion.x, 0.0, motion.z), is_solid).motion;
+    let down = sweep(lifted.translate(across), -up, is_solid).motion;
+
+    let stepped = up + across + down;
+    if horizontal_length(stepped) <= horizontal_length(collision.motion) {
+        return collision;
+    }
+
+    Collision {
+        motion: stepped,
+        collided: [stepped.x != motion.x, true, stepped.z != motion.z],
+        on_ground: true,
+    }
+}
+
+fn horizontal_length(motion: Vec3) -> f32 {
+    Vec2::new(motion.x, motion.z).length()
+}
+
+/// Shortens the horizontal part of `motion` so a box standing on the ground never
+/// moves somewhere with nothing solid within `drop` below it, the way crouching
+/// stops the player at the edge of a block.
+pub fn keep_on_edge(
+    bounding_box: BoundingBox,
+    motion: Vec3,
+    drop: f32,
+    is_solid: &impl Fn(IVec3) -> bool,
+) -> Vec3 {
+    const STEP: f32 = 0.05;
+
+    let has_ground = |x: f32, z: f32| {
+        let moved = bounding_box.translate(Vec3::new(x, 0.0, z));
+        let below = BoundingBox::new(
+            moved.min - Vec3::Y * drop,
+            Vec3::new(moved.max.x, moved.min.y, moved.max.z),
+        );
+
+        below.overlaps_solid(is_solid)
+    };
+    let shrink = |distance: f32| {
+        if distance.abs() <= STEP {
+            0.0
+        } else {
+            distance - STEP * distance.signum()
+        }
+    };
+
+    let mut x = motion.x;
+    while x != 0.0 && !has_ground(x, 0.0) {
+        x = shrink(x);
+    }
+
+    let mut z = motion.z;
+    while z != 0.0 && !has_ground(0.0, z) {
+        z = shrink(z);
+    }
+
+    while x != 0.0 && z != 0.0 && !has_ground(x, z) {
+        x = shrink(x);
+        z = shrink(z);
+    }
+
+    Vec3::new(x, motion.y, z)
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RaycastHit {
+    /// The block the ray hit.
+    pub position: IVec3,
+    /// Outwards normal of the face the ray entered through, zero if the ray
+    /// started inside the block.
+    pub normal: IVec3,
+}
+
+/// Walks the blocks along a ray from `origin` in `direction`, returning the first
+/// one within `max_distance` that `is_target` accepts.
+pub fn raycast(
+    origin: Vec3,
+    direction: Vec3,
+    max_distance: f32,
+    is_target: &impl Fn(IVec3) -> bool,
+) -> Option<RaycastHit> {
+    let direction = direction.normalize_or_zero();
+    if direction == Vec3::ZERO {
+        return None;
+    }
+
+    let mut position = origin.floor().as_ivec3();
+    let mut normal = IVec3::ZERO;
+
+    let step = direction.signum().as_ivec3();
+    // Distance along the ray between crossing two boundaries on each axis, and
+    // to the first boundary crossed on each axis.
+    let delta = direction.recip().abs();
+    let mut next = Vec3::ZERO;
+    for axis in 0..3 {
+        next[axis] = if direction[axis] > 0.0 {
+            (position[axis] as f32 + 1.0 - origin[axis]) * delta[axis]
+        } else if direction[axis] < 0.0 {
+            (origin[axis] - position[axis] as f32) * delta[axis]
+        } else {
+            f32::INFINITY
+        };
+    }
+
+    let mut distance = 0.0;
+    while distance <= max_distance {
+        if is_target(position) {
+            return Some(RaycastHit { position, normal });
+        }
+
+        let axis = if next.x < next.y && next.x < next.z {
+            0
+        } else if next.y < next.z {
+            1
+        } else {
+            2
+        };
+
+        distance = next[axis];
+        next[axis] += delta[axis];
+        position[axis] += step[axis];
+        normal = IVec3::ZERO;
+        normal[axis] = -step[axis];
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::player::CROUCH_DROP;
+
+    /// A player sized box with its feet at `feet`.
+    fn player(feet: Vec3) -> BoundingBox {
+        BoundingBox::new(
+            feet - Vec3::new(0.3, 0.0, 0.3),
+            feet + Vec3::new(0.3, 1.8, 0.3),
+        )
+    }
+
+    fn floor(position: IVec3) -> bool {
+        position.y <= 0
+    }
+
+    fn assert_near(actual: Vec3, expected: Vec3) {
+        assert!(
+            (actual - expected).abs().max_element() < 1e-3,
+            "{} is not {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn falling_boxes_land_on_the_ground() {
+        let collision = sweep(player(Vec3::new(0.5, 1.5, 0.5)), -Vec3::Y, &floor);
+
+        assert_near(collision.motion, Vec3::new(0.0, -0.5, 0.0));
+        assert_eq!(collision.collided, [false, true, false]);
+        assert!(collision.on_ground);
+    }
+
+    #[test]
+    fn boxes_slide_along_walls() {
+        let wall = |position: IVec3| floor(position) || position.x == 2;
+        let collision = sweep(
+            player(Vec3::new(1.5, 1.0, 0.5)),
+            Vec3::new(1.0, 0.0, 1.0),
+            &wall,
+        );
+
+        assert_near(collision.motion, Vec3::new(0.2, 0.0, 1.0));
+        assert_eq!(collision.collided, [true, false, false]);
+        assert!(!collision.on_ground);
+    }
+
+    #[test]
+    fn ceilings_stop_jumps() {
+        let ceiling = |position: IVec3| floor(position) || position.y == 3;
+        let collision = sweep(player(Vec3::new(0.5, 1.0, 0.5)), Vec3::Y, &ceiling);
+
+        assert_near(collision.motion, Vec3::new(0.0, 0.2, 0.0));
+        assert_eq!(collision.collided, [false, true, false]);
+        assert!(!collision.on_ground);
+    }
+
+    #[test]
+    fn walking_climbs_one_block_ledges() {
+        let ledge = |position: IVec3| floor(position) || position == IVec3::new(2, 1, 0);
+        let collision = move_and_collide(
+            player(Vec3::new(1.5, 1.0, 0.5)),
+            Vec3::new(0.5, -0.1, 0.0),
+            1.0,
+            &ledge,
+        );
+
+        assert_near(collision.motion, Vec3::new(0.5, 1.0, 0.0));
+        assert!(collision.on_ground);
+    }
+
+    #[test]
+    fn walking_stops_at_two_block_ledges() {
+        let ledge = |position: IVec3| {
+            floor(position) || position.x == 2 && (position.y == 1 || position.y == 2)
+        };
+        let collision = move_and_collide(
+            player(Vec3::new(1.5, 1.0, 0.5)),
+            Vec3::new(0.5, -0.1, 0.0),
+            1.0,
+            &ledge,
+        );
+
+        assert_near(collision.motion, Vec3::new(0.2, 0.0, 0.0));
+        assert_eq!(collision.collided, [true, true, false]);
+        assert!(collision.on_ground);
+    }
+
+    #[test]
+    fn boxes_in_the_air_do_not_step() {
+        let ledge = |position: IVec3| floor(position) || position == IVec3::new(2, 1, 0);
+        let collision = move_and_collide(
+            player(Vec3::new(1.5, 1.5, 0.5)),
+            Vec3::new(0.5, 0.1, 0.0),
+            1.0,
+            &ledge,
+        );
+
+        assert_near(collision.motion, Vec3::new(0.2, 0.1, 0.0));
+        assert!(!collision.on_ground);
+    }
+
+    #[test]
+    fn edges_stop_boxes_above_a_drop() {
+        let ledge = |position: IVec3| position.y <= 0 && position.x <= 1;
+        let motion = keep_on_edge(
+            player(Vec3::new(1.5, 1.0, 0.5)),
+            Vec3::new(1.0, -0.1, 0.5),
+            CROUCH_DROP,
+            &ledge,
+        );
+
+        // The box keeps some of its footprint over the ledge.
+        assert!(motion.x > 0.7 && motion.x <= 0.8, "{}", motion);
+        assert_eq!(motion.y, -0.1);
+        assert_eq!(motion.z, 0.5);
+    }
+
+    #[test]
+    fn drops_within_the_crouch_drop_are_allowed() {
+        let motion = Vec3::new(1.0, 0.0, 0.5);
+        let just_above = player(Vec3::new(1.5, 1.0 + CROUCH_DROP - 0.1, 0.5));
+        assert_eq!(
+            keep_on_edge(just_above, motion, CROUCH_DROP, &floor),
+            motion
+        );
+
+        let too_high = player(Vec3::new(1.5, 1.0 + CROUCH_DROP + 0.1, 0.5));
+        assert_eq!(
+            keep_on_edge(too_high, motion, CROUCH_DROP, &floor),
+            Vec3::ZERO
+        );
+    }
+
+    #[test]
+    fn rays_hit_the_face_they_enter_through() {
+        let hit = raycast(Vec3::new(0.5, 2.5, 0.5), -Vec3::Y, 5.0, &floor);
+        assert_eq!(
+            hit,
+            Some(RaycastHit {
+                position: IVec3::ZERO,
+                normal: IVec3::Y,
+            })
+        );
+
+        let wall = |position: IVec3| position.x == 3;
+        let hit = raycast(
+            Vec3::new(0.5, 1.5, 0.5),
+            Vec3::new(1.0, 0.0, 0.4),
+            5.0,
+            &wall,
+        );
+        assert_eq!(
+            hit,
+            Some(RaycastHit {
+                position: IVec3::new(3, 1, 1),
+                normal: -IVec3::X,
+            })
+        );
+    }
+
+    #[test]
+    fn rays_starting_inside_a_block_hit_it() {
+        let hit = raycast(Vec3::new(0.5, -0.5, 0.5), Vec3::X, 5.0, &floor);
+        assert_eq!(
+            hit,
+            Some(RaycastHit {
+                position: IVec3::new(0, -1, 0),
+                normal: IVec3::ZERO,
+            })
+        );
+    }
+
+    #[test]
+    fn rays_miss_blocks_beyond_their_reach() {
+        let wall = |position: IVec3| position.x == 8;
+        assert_eq!(raycast(Vec3::new(0.5, 1.5, 0.5), Vec3::X, 5.0, &wall), None);
+        assert_eq!(
+            raycast(Vec3::new(0.5, 1.5, 0.5), Vec3::ZERO, 5.0, &floor),
+            None
+        );
+    }
+}
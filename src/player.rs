@@ -1,11 +1,147 @@
+use std::path::Path;
+
+use bevy::input::mouse::MouseMotion;
 use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::chunk::Chunk;
+use crate::input::{Action, Actions, Settings, Stick};
+use crate::inventory::{Inventory, InventorySave};
+use crate::physics::{keep_on_edge, move_and_collide, BoundingBox};
+use crate::registry::Registry;
+use crate::utils::{block_to_chunk, block_to_local};
+use crate::world::World;
+#[cfg(feature = "render")]
+use crate::{
+    client::ServerConnection, inventory::starting_inventory, physics::raycast,
+    protocol::ClientMessage, tick::BlockWorld, world::HIGHEST_SURFACE,
+};
+
+pub const GRAVITY: f32 = 32.0;
+pub const TERMINAL_VELOCITY: f32 = 78.0;
+/// Enough to jump a little over one block.
+pub const JUMP_VELOCITY: f32 = 9.0;
+pub const STEP_HEIGHT: f32 = 1.0;
+/// How far a crouching player is willing to drop off the edge of a block.
+pub const CROUCH_DROP: f32 = 0.5;
+/// How much of their acceleration and friction a player keeps in the air.
+pub const AIR_CONTROL: f32 = 0.25;
+
+/// Width, height and eye height of the player's body, in blocks.
+pub const PLAYER_WIDTH: f32 = 0.6;
+pub const PLAYER_HEIGHT: f32 = 1.8;
+pub const EYE_HEIGHT: f32 = 1.62;
+
+/// How far away, in blocks, the player can break and place blocks.
+pub const REACH_DISTANCE: f32 = 5.0;
+
+pub const PLAYER_SAVE_PATH: &str = "saves/player.ron";
+/// Seconds between automatic saves of the player.
+pub const AUTOSAVE_INTERVAL: f32 = 10.0;
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum MovementMode {
+    /// Moves freely through blocks, with jump and sneak going up and down.
+    #[default]
+    Flying,
+    /// Falls under gravity, jumps, and collides with solid blocks.
+    Walking,
+}
+
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum MovementState {
+    #[default]
+    Walk,
+    Sprint,
+    /// Slow, and never walks off the edge of a block.
+    Crouch,
+    Fly,
+    FlyFast,
+}
+
+/// How the player moves in one [`MovementState`].
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct MovementProfile {
+    /// Top speed, in blocks per second.
+    pub speed: f32,
+    /// Speed gained per second while heading somewhere.
+    pub acceleration: f32,
+    /// Speed lost per second once there is no input.
+    pub friction: f32,
+}
+
+/// The [`MovementProfile`] of each [`MovementState`].
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct MovementProfiles {
+    pub walk: MovementProfile,
+    pub sprint: MovementProfile,
+    pub crouch: MovementProfile,
+    pub fly: MovementProfile,
+    pub fly_fast: MovementProfile,
+}
 
-use crate::{Camera3d, MouseMotion};
+impl MovementProfiles {
+    /// Profiles scaled from a walking speed, in blocks per second.
+    pub fn from_walk_speed(speed: f32) -> Self {
+        let on_ground = |scale: f32| MovementProfile {
+            speed: speed * scale,
+            acceleration: speed * 8.0,
+            friction: speed * 10.0,
+        };
+        let in_air = |scale: f32| MovementProfile {
+            speed: speed * scale,
+            acceleration: speed * scale * 3.0,
+            friction: speed * scale * 3.0,
+        };
+
+        MovementProfiles {
+            walk: on_ground(1.0),
+            sprint: on_ground(1.3),
+            crouch: on_ground(0.3),
+            fly: in_air(1.0),
+            fly_fast: in_air(3.0),
+        }
+    }
+
+    pub fn get(&self, state: MovementState) -> MovementProfile {
+        match state {
+            MovementState::Walk => self.walk,
+            MovementState::Sprint => self.sprint,
+            MovementState::Crouch => self.crouch,
+            MovementState::Fly => self.fly,
+            MovementState::FlyFast => self.fly_fast,
+        }
+    }
+}
+
+impl Default for MovementProfiles {
+    fn default() -> Self {
+        MovementProfiles::from_walk_speed(Settings::default().movement_speed)
+    }
+}
 
 #[derive(Component, Default)]
 pub struct Player {
     pub pitch: f32,
     pub yaw: f32,
+    pub movement_mode: MovementMode,
+    pub movement_state: MovementState,
+    pub movement_profiles: MovementProfiles,
+    pub velocity: Vec3,
+    pub on_ground: bool,
+}
+
+impl Player {
+    /// The player's body, for a camera at `eye`.
+    pub fn bounding_box(eye: Vec3) -> BoundingBox {
+        let feet = eye - Vec3::Y * EYE_HEIGHT;
+        let half_width = PLAYER_WIDTH / 2.0;
+
+        BoundingBox::new(
+            feet - Vec3::new(half_width, 0.0, half_width),
+            feet + Vec3::new(half_width, PLAYER_HEIGHT, half_width),
+        )
+    }
 }
 
 pub fn grab_mouse(mut windows: ResMut<Windows>) {
@@ -14,37 +150,136 @@ pub fn grab_mouse(mut windows: ResMut<Windows>) {
     lock_mouse(window);
 }
 
-pub fn create_player(mut commands: Commands) {
+/// Where the player is kept between sessions, if anywhere.
+pub struct PlayerSavePath(pub Option<&'static str>);
+
+/// Everything about the player that is kept between sessions.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PlayerSave {
+    pub position: [f32; 3],
+    pub pitch: f32,
+    pub yaw: f32,
+    pub inventory: InventorySave,
+}
+
+impl PlayerSave {
+    pub fn load(path: impl AsRef<Path>) -> Option<Self> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path).ok()?;
+
+        ron::from_str(&contents)
+            .map_err(|error| warn!("Could not parse {}: {}", path.display(), error))
+            .ok()
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), String> {
+        let path = path.as_ref();
+        let contents = ron::to_string(self).map_err(|error| error.to_string())?;
+
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(|error| error.to_string())?;
+        }
+
+        std::fs::write(path, contents).map_err(|error| error.to_string())
+    }
+}
+
+/// Spawns the player where it was last saved, or a new one above the ground at
+/// the middle of the world.
+#[cfg(feature = "render")]
+pub fn create_player(
+    mut commands: Commands,
+    settings: Res<Settings>,
+    registry: Res<Registry<'static>>,
+    save_path: Res<PlayerSavePath>,
+) {
+    let (transform, pitch, yaw, inventory) = match save_path.0.and_then(PlayerSave::load) {
+        Some(save) => {
+            let inventory = Inventory::from_save(&save.inventory, &registry);
+
+            (
+                Transform::from_translation(save.position.into()),
+                save.pitch,
+                save.yaw,
+                inventory,
+            )
+        }
+        None => (
+            Transform::from_xyz(0.5, HIGHEST_SURFACE as f32 + EYE_HEIGHT, 0.5),
+            0.0,
+            0.0,
+            starting_inventory(&registry),
+        ),
+    };
+
     commands
         .spawn_bundle(PerspectiveCameraBundle {
-            transform: Transform::from_xyz(0.0, 0.0, 0.0),
+            transform,
+            ..default()
+        })
+        .insert(Player {
+            pitch,
+            yaw,
+            movement_profiles: MovementProfiles::from_walk_speed(settings.movement_speed),
             ..default()
         })
-        .insert(Player { ..default() });
+        .insert(inventory);
 }
 
-pub fn manage_mouse(
-    mut windows: ResMut<Windows>,
-    keyboard_input: Res<Input<KeyCode>>,
-    mouse_input: Res<Input<MouseButton>>,
+/// Writes the player to the [`PlayerSavePath`] every [`AUTOSAVE_INTERVAL`]
+/// seconds.
+pub fn save_player(
+    time: Res<Time>,
+    mut since_save: Local<f32>,
+    save_path: Res<PlayerSavePath>,
+    players: Query<(&Player, &Transform, &Inventory)>,
 ) {
+    let save_path = match save_path.0 {
+        Some(save_path) => save_path,
+        None => return,
+    };
+
+    *since_save += time.delta_seconds();
+    if *since_save < AUTOSAVE_INTERVAL {
+        return;
+    }
+    *since_save = 0.0;
+
+    for (player, transform, inventory) in players.iter() {
+        let save = PlayerSave {
+            position: transform.translation.into(),
+            pitch: player.pitch,
+            yaw: player.yaw,
+            inventory: inventory.to_save(),
+        };
+
+        if let Err(error) = save.save(save_path) {
+            warn!("Could not save the player to {}: {}", save_path, error);
+        }
+    }
+}
+
+pub fn manage_mouse(mut windows: ResMut<Windows>, actions: Actions) {
     let window = windows.get_primary_mut().unwrap();
 
-    if keyboard_input.just_pressed(KeyCode::Escape) {
+    if actions.just_pressed(Action::ReleaseCursor) {
         unlock_mouse(window);
     }
 
-    if mouse_input.just_pressed(MouseButton::Left) {
+    if actions.just_pressed(Action::Break) {
         lock_mouse(window);
     }
 }
 
 pub fn rotate_camera(
+    time: Res<Time>,
     mut windows: ResMut<Windows>,
-    mut camera: Query<(&mut Player, &mut Transform), With<Camera3d>>,
+    settings: Res<Settings>,
+    actions: Actions,
+    mut camera: Query<(&mut Player, &mut Transform)>,
     mut mouse_motion: EventReader<MouseMotion>,
 ) {
-    let speed = 0.06;
+    let speed = settings.mouse_sensitivity;
 
     let window = windows.get_primary_mut().unwrap();
 
@@ -56,6 +291,15 @@ pub fn rotate_camera(
             }
         }
 
+        if window.cursor_locked() {
+            let look = actions.stick(Stick::Right)
+                * settings.gamepad_look_speed.to_radians()
+                * time.delta_seconds();
+
+            player.pitch += look.y;
+            player.yaw -= look.x;
+        }
+
         player.pitch = player
             .pitch
             .clamp(-90.0f32.to_radians(), 90.0f32.to_radians());
@@ -67,40 +311,354 @@ pub fn rotate_camera(
     }
 }
 
-pub fn move_camera(
-    time: ResMut<Time>,
+pub fn toggle_movement_mode(
     windows: Res<Windows>,
-    keyboard_input: Res<Input<KeyCode>>,
-    mut camera: Query<&mut Transform, With<Camera3d>>,
+    actions: Actions,
+    mut players: Query<&mut Player>,
 ) {
-    let speed = 6.0 * time.delta_seconds();
+    let window = windows.get_primary().unwrap();
+
+    if !window.cursor_locked() || !actions.just_pressed(Action::ToggleFly) {
+        return;
+    }
+
+    for mut player in players.iter_mut() {
+        player.movement_mode = match player.movement_mode {
+            MovementMode::Flying => MovementMode::Walking,
+            MovementMode::Walking => MovementMode::Flying,
+        };
+        player.velocity = Vec3::ZERO;
+        player.on_ground = false;
+    }
+}
+
+/// Rebuilds the players' [`MovementProfiles`] when the [`Settings`] change, so a
+/// new movement speed applies without restarting.
+pub fn update_movement_profiles(settings: Res<Settings>, mut players: Query<&mut Player>) {
+    if !settings.is_changed() {
+        return;
+    }
 
+    let profiles = MovementProfiles::from_walk_speed(settings.movement_speed);
+    for mut player in players.iter_mut() {
+        if player.movement_profiles != profiles {
+            player.movement_profiles = profiles;
+        }
+    }
+}
+
+/// Breaks the block the player is looking at, or places the block of their
+/// selected item against it. The edit shows straight away and is sent to the
+/// server, which confirms or undoes it.
+#[cfg(feature = "render")]
+pub fn interact_with_blocks(
+    windows: Res<Windows>,
+    actions: Actions,
+    connection: Option<ResMut<ServerConnection>>,
+    registry: Res<Registry<'static>>,
+    mut chunks: Query<&mut Chunk<'static>>,
+    mut players: Query<(&Transform, &mut Inventory), With<Player>>,
+) {
     let window = windows.get_primary().unwrap();
+    let breaking = actions.just_pressed(Action::Break);
+    let placing = actions.just_pressed(Action::Place);
 
-    for mut camera_transform in camera.iter_mut() {
-        let mut velocity = Vec3::ZERO;
+    let mut connection = match connection {
+        Some(connection) if window.cursor_locked() && (breaking || placing) => connection,
+        _ => return,
+    };
+    let connection = &mut *connection;
 
-        let local_z = camera_transform.local_z();
-        let forward = -Vec3::new(local_z.x, 0., local_z.z);
-        let strafe = Vec3::new(local_z.z, 0., -local_z.x);
+    let mut block_world = BlockWorld::new(
+        chunks
+            .iter_mut()
+            .map(|chunk| (chunk.position, chunk))
+            .collect(),
+        &registry,
+        0,
+    );
 
-        for key in keyboard_input.get_pressed() {
-            if window.cursor_locked() {
-                match key {
-                    KeyCode::W => velocity += forward,
-                    KeyCode::S => velocity -= forward,
-                    KeyCode::A => velocity -= strafe,
-                    KeyCode::D => velocity += strafe,
-                    KeyCode::Space => velocity += Vec3::Y,
-                    KeyCode::LShift => velocity -= Vec3::Y,
-                    _ => {}
+    for (transform, mut inventory) in players.iter_mut() {
+        // Fluids are looked through rather than aimed at.
+        let hit = raycast(
+            transform.translation,
+            transform.forward(),
+            REACH_DISTANCE,
+            &|position| {
+                block_world
+                    .block_data(position)
+                    .is_some_and(|block| block.fluid.is_none())
+            },
+        );
+        let hit = match hit {
+            Some(hit) => hit,
+            None => continue,
+        };
+
+        if breaking {
+            let sequence =
+                connection
+                    .prediction
+                    .predict(&mut block_world, hit.position, None, None);
+            connection.connection.send(&ClientMessage::EditBlock {
+                sequence,
+                position: hit.position,
+                block: 0,
+            });
+            continue;
+        }
+
+        let target = hit.position + hit.normal;
+        let block = inventory
+            .selected_stack()
+            .and_then(|stack| registry.items.get(&stack.item))
+            .and_then(|item| item.places);
+        let (block, id) = match block {
+            Some(block) if hit.normal != IVec3::ZERO => {
+                match connection.ids.blocks.id(Some(block)) {
+                    Some(id) => (block, id),
+                    // The server does not know about this block.
+                    None => continue,
                 }
             }
+            _ => continue,
+        };
+
+        let loaded = block_world.is_loaded(target);
+        let replaceable = block_world
+            .block_data(target)
+            .is_none_or(|block| block.fluid.is_some());
+        let inside_player = registry.blocks.get(&block).is_some_and(|block| block.solid)
+            && BoundingBox::block(target).overlaps(Player::bounding_box(transform.translation));
+
+        if loaded && replaceable && !inside_player {
+            let selected = inventory.selected;
+            let spent = inventory.take(selected, 1);
+
+            let sequence =
+                connection
+                    .prediction
+                    .predict(&mut block_world, target, Some(block), spent);
+            connection.connection.send(&ClientMessage::EditBlock {
+                sequence,
+                position: target,
+                block: id,
+            });
         }
+    }
+
+    block_world.relight();
+}
 
-        velocity = velocity.normalize_or_zero();
+/// What the player is asking their body to do this frame.
+#[derive(Debug, Default, Copy, Clone)]
+struct MovementInput {
+    /// Horizontal direction to move in, at most one block long.
+    direction: Vec3,
+    jump: bool,
+    sneak: bool,
+    sprint: bool,
+}
 
-        camera_transform.translation += velocity * speed;
+impl MovementInput {
+    fn read(actions: &Actions, camera_transform: &Transform) -> Self {
+        let local_z = camera_transform.local_z();
+        let forward = -Vec3::new(local_z.x, 0., local_z.z).normalize_or_zero();
+        let strafe = Vec3::new(local_z.z, 0., -local_z.x).normalize_or_zero();
+
+        let mut direction = Vec3::ZERO;
+        for (action, offset) in [
+            (Action::Forward, forward),
+            (Action::Back, -forward),
+            (Action::Left, -strafe),
+            (Action::Right, strafe),
+        ] {
+            if actions.pressed(action) {
+                direction += offset;
+            }
+        }
+
+        let stick = actions.stick(Stick::Left);
+        direction = direction.normalize_or_zero() + forward * stick.y + strafe * stick.x;
+
+        MovementInput {
+            direction: direction.clamp_length_max(1.0),
+            jump: actions.pressed(Action::Jump),
+            sneak: actions.pressed(Action::Sneak),
+            sprint: actions.pressed(Action::Sprint),
+        }
+    }
+}
+
+pub fn move_camera(
+    time: Res<Time>,
+    windows: Res<Windows>,
+    actions: Actions,
+    world: Res<World>,
+    registry: Res<Registry<'static>>,
+    chunks: Query<&Chunk<'static>>,
+    mut camera: Query<(&mut Player, &mut Transform)>,
+) {
+    let window = windows.get_primary().unwrap();
+
+    // Blocks in chunks that have not been generated yet are solid, so nobody
+    // falls out of the world before it loads.
+    let is_solid = |position: IVec3| {
+        let chunk = world
+            .chunks
+            .get(&block_to_chunk(position))
+            .and_then(|entity| chunks.get(*entity).ok());
+
+        match chunk {
+            Some(chunk) => chunk
+                .block(block_to_local(position))
+                .and_then(|block| registry.blocks.get(&block))
+                .is_some_and(|block| block.solid),
+            None => true,
+        }
+    };
+
+    for (mut player, mut camera_transform) in camera.iter_mut() {
+        let input = if window.cursor_locked() {
+            MovementInput::read(&actions, &camera_transform)
+        } else {
+            MovementInput::default()
+        };
+
+        match player.movement_mode {
+            MovementMode::Walking => walk(
+                &mut player,
+                &mut camera_transform,
+                input,
+                time.delta_seconds(),
+                &is_solid,
+            ),
+            MovementMode::Flying => fly(
+                &mut player,
+                &mut camera_transform,
+                input,
+                time.delta_seconds(),
+            ),
+        }
+    }
+}
+
+/// Moves `velocity` towards `target`, speeding up with the profile's acceleration
+/// while there is somewhere to go and slowing down with its friction otherwise.
+fn accelerate(
+    velocity: Vec3,
+    target: Vec3,
+    profile: MovementProfile,
+    control: f32,
+    delta_seconds: f32,
+) -> Vec3 {
+    let rate = if target == Vec3::ZERO {
+        profile.friction
+    } else {
+        profile.acceleration
+    };
+    let max_change = rate * control * delta_seconds;
+    let change = target - velocity;
+
+    if change.length() <= max_change {
+        target
+    } else {
+        velocity + change.normalize() * max_change
+    }
+}
+
+fn fly(
+    player: &mut Player,
+    camera_transform: &mut Transform,
+    input: MovementInput,
+    delta_seconds: f32,
+) {
+    player.movement_state = if input.sprint {
+        MovementState::FlyFast
+    } else {
+        MovementState::Fly
+    };
+    let profile = player.movement_profiles.get(player.movement_state);
+
+    let mut direction = input.direction;
+    if input.jump {
+        direction += Vec3::Y;
+    }
+    if input.sneak {
+        direction -= Vec3::Y;
+    }
+
+    player.velocity = accelerate(
+        player.velocity,
+        direction.clamp_length_max(1.0) * profile.speed,
+        profile,
+        1.0,
+        delta_seconds,
+    );
+
+    camera_transform.translation += player.velocity * delta_seconds;
+}
+
+fn walk(
+    player: &mut Player,
+    camera_transform: &mut Transform,
+    input: MovementInput,
+    delta_seconds: f32,
+    is_solid: &impl Fn(IVec3) -> bool,
+) {
+    player.movement_state = if input.sneak {
+        MovementState::Crouch
+    } else if input.sprint && input.direction != Vec3::ZERO {
+        MovementState::Sprint
+    } else {
+        MovementState::Walk
+    };
+    let profile = player.movement_profiles.get(player.movement_state);
+    let control = if player.on_ground { 1.0 } else { AIR_CONTROL };
+
+    let horizontal = accelerate(
+        Vec3::new(player.velocity.x, 0.0, player.velocity.z),
+        input.direction * profile.speed,
+        profile,
+        control,
+        delta_seconds,
+    );
+    player.velocity.x = horizontal.x;
+    player.velocity.z = horizontal.z;
+    player.velocity.y = (player.velocity.y - GRAVITY * delta_seconds).max(-TERMINAL_VELOCITY);
+
+    if input.jump && player.on_ground {
+        player.velocity.y = JUMP_VELOCITY;
+    }
+
+    let bounding_box = Player::bounding_box(camera_transform.translation);
+    let mut motion = player.velocity * delta_seconds;
+
+    if player.movement_state == MovementState::Crouch && player.on_ground {
+        let kept = keep_on_edge(bounding_box, motion, CROUCH_DROP, is_solid);
+
+        if kept.x != motion.x {
+            player.velocity.x = 0.0;
+        }
+        if kept.z != motion.z {
+            player.velocity.z = 0.0;
+        }
+        motion = kept;
+    }
+
+    let collision = move_and_collide(bounding_box, motion, STEP_HEIGHT, is_solid);
+
+    camera_transform.translation += collision.motion;
+    player.on_ground = collision.on_ground;
+
+    if collision.collided[0] {
+        player.velocity.x = 0.0;
+    }
+    if collision.collided[1] {
+        player.velocity.y = 0.0;
+    }
+    if collision.collided[2] {
+        player.velocity.z = 0.0;
     }
 }
 
@@ -0,0 +1,256 @@
+use bevy::prelude::*;
+
+use crate::game::load_blocks;
+use crate::modding::{finish_registration, RegistryStage};
+use crate::registry::Registry;
+use crate::tick::{tick_blocks, BlockChanged, BlockUpdate, PendingBlockUpdates};
+use crate::world::{advance_world_tick, World, WorldSystem, WorldTick};
+#[cfg(feature = "render")]
+use crate::{
+    client::{
+        advance_loopback_clock, connect_to_server, flush_server_connection,
+        receive_server_messages, send_player_movement,
+    },
+    error::{create_error_text, report_asset_errors, AssetError},
+    game::{
+        check_assets, create_ui_camera, load_assets, load_block_models, load_resource_packs,
+        run_ingame, scan_resource_packs, AssetHandles, AssetPaths, GameState,
+    },
+    input::{
+        apply_bindings, choose_rebind_action, create_rebind_prompt, load_settings, rebind_actions,
+        reload_settings, show_rebind_prompt, track_active_gamepad, ActiveGamepad, InputMap,
+        RebindAction, Rebinding,
+    },
+    inventory::select_hotbar_slot,
+    light::light_chunks,
+    material::{BlockMaterial, BlockMaterialMode, PreferredMaterialMode},
+    modding::finish_model_registration,
+    player::{
+        create_player, grab_mouse, interact_with_blocks, manage_mouse, move_camera, rotate_camera,
+        save_player, toggle_movement_mode, update_movement_profiles, PlayerSavePath,
+        PLAYER_SAVE_PATH,
+    },
+    registry::ClientRegistry,
+    remote_player::{
+        interpolate_remote_players, position_name_tags, update_remote_players, RemotePlayerEvent,
+    },
+    resource_pack::{BlockModelDefinition, BlockModelDefinitionLoader},
+    world::{build_chunks, create_world, remesh_chunk_neighbours, sort_translucent_chunks},
+};
+
+/// The block and item registry and the world, with no window, assets or
+/// players. This is all a headless server or a test needs.
+pub struct CorePlugin {
+    /// Whether Defaria's own blocks and items are registered at startup.
+    pub builtin_content: bool,
+    /// Whether block updates and random ticks run here, which only the app
+    /// that owns the world should do.
+    pub simulate: bool,
+}
+
+impl Default for CorePlugin {
+    fn default() -> Self {
+        CorePlugin {
+            builtin_content: true,
+            simulate: false,
+        }
+    }
+}
+
+impl Plugin for CorePlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(Registry::default())
+            .insert_resource(World::default());
+
+        app.add_startup_stage_before(
+            StartupStage::Startup,
+            RegistryStage::Register,
+            SystemStage::parallel(),
+        )
+        .add_startup_stage_after(
+            RegistryStage::Register,
+            RegistryStage::Override,
+            SystemStage::parallel(),
+        )
+        .add_startup_stage_after(
+            RegistryStage::Override,
+            RegistryStage::Finish,
+            SystemStage::parallel(),
+        )
+        .add_startup_system_to_stage(RegistryStage::Finish, finish_registration);
+
+        if self.builtin_content {
+            app.add_startup_system_to_stage(RegistryStage::Register, load_blocks);
+        }
+
+        if self.simulate {
+            app.insert_resource(WorldTick::default())
+                .insert_resource(PendingBlockUpdates::default())
+                .add_event::<BlockUpdate>()
+                .add_event::<BlockChanged>()
+                .add_system(advance_world_tick.before(WorldSystem::Tick))
+                .add_system(tick_blocks.label(WorldSystem::Tick));
+        }
+    }
+}
+
+/// Loads the settings and assets, then moves from [`GameState::Loading`] to
+/// [`GameState::Ingame`] once the block textures are in. The settings are read
+/// again when their file changes, switching to the resource packs in them. Needs
+/// the [`RenderPlugin`] for the block models the textures are laid out for. What
+/// fails to load is logged and shown on screen.
+#[cfg(feature = "render")]
+#[derive(Default)]
+pub struct LoadingPlugin {
+    pub paths: AssetPaths,
+}
+
+#[cfg(feature = "render")]
+impl Plugin for LoadingPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(self.paths.clone())
+            .insert_resource(AssetHandles::default())
+            .add_asset::<BlockModelDefinition>()
+            .init_asset_loader::<BlockModelDefinitionLoader>()
+            .add_event::<AssetError>()
+            .add_state(GameState::Loading)
+            .add_system_set(
+                SystemSet::on_enter(GameState::Loading)
+                    .with_system(load_settings)
+                    .with_system(load_assets)
+                    .with_system(create_ui_camera)
+                    .with_system(create_error_text.after(load_assets)),
+            )
+            // Before `Update`, so chunk layers despawned for new packs are gone
+            // by the time the chunks are meshed again.
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                reload_settings.before(load_resource_packs),
+            )
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                load_resource_packs.before(check_assets),
+            )
+            .add_system_to_stage(
+                CoreStage::PreUpdate,
+                scan_resource_packs.before(check_assets),
+            )
+            .add_system_to_stage(CoreStage::PreUpdate, check_assets)
+            .add_system(report_asset_errors);
+    }
+}
+
+/// Lights and meshes the chunks in the world and draws them. Needs the
+/// [`CorePlugin`] added first, for the stages block models are registered in.
+#[cfg(feature = "render")]
+#[derive(Default)]
+pub struct RenderPlugin {
+    /// The material chunks are drawn with, if the block textures allow it. Only
+    /// [`BlockMaterialMode::TextureArray`] draws light and ambient occlusion.
+    pub material_mode: BlockMaterialMode,
+}
+
+#[cfg(feature = "render")]
+impl Plugin for RenderPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(ClientRegistry::default())
+            .insert_resource(self.material_mode)
+            .insert_resource(PreferredMaterialMode(self.material_mode))
+            .add_plugin(MaterialPlugin::<BlockMaterial>::default())
+            .add_startup_system_to_stage(RegistryStage::Register, load_block_models)
+            .add_startup_system_to_stage(RegistryStage::Finish, finish_model_registration)
+            .add_system_set(SystemSet::on_enter(GameState::Ingame).with_system(create_world))
+            .add_system_set(
+                SystemSet::new()
+                    .with_run_criteria(run_ingame)
+                    .with_system(light_chunks.label(WorldSystem::Light))
+                    .with_system(remesh_chunk_neighbours.label(WorldSystem::Light))
+                    .with_system(build_chunks.after(WorldSystem::Light))
+                    .with_system(sort_translucent_chunks),
+            );
+    }
+}
+
+/// The local player: input, the camera, movement, the inventory and editing
+/// blocks.
+#[cfg(feature = "render")]
+pub struct PlayerPlugin {
+    /// Where the player is loaded from and saved to, or `None` to start afresh
+    /// every time and never save.
+    pub save_path: Option<&'static str>,
+}
+
+#[cfg(feature = "render")]
+impl Default for PlayerPlugin {
+    fn default() -> Self {
+        PlayerPlugin {
+            save_path: Some(PLAYER_SAVE_PATH),
+        }
+    }
+}
+
+#[cfg(feature = "render")]
+impl Plugin for PlayerPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(PlayerSavePath(self.save_path))
+            .insert_resource(InputMap::default())
+            .insert_resource(ActiveGamepad::default())
+            .insert_resource(Rebinding::default())
+            .add_event::<RebindAction>()
+            .add_system_set(
+                SystemSet::on_enter(GameState::Ingame)
+                    .with_system(grab_mouse)
+                    .with_system(create_player)
+                    .with_system(create_rebind_prompt),
+            )
+            .add_system_set(
+                SystemSet::new()
+                    .with_run_criteria(run_ingame)
+                    .with_system(apply_bindings.before(choose_rebind_action))
+                    .with_system(choose_rebind_action.before(rebind_actions))
+                    .with_system(rebind_actions)
+                    .with_system(show_rebind_prompt.after(rebind_actions))
+                    .with_system(track_active_gamepad)
+                    .with_system(interact_with_blocks.before(manage_mouse))
+                    .with_system(manage_mouse)
+                    .with_system(rotate_camera)
+                    .with_system(toggle_movement_mode.before(move_camera))
+                    .with_system(update_movement_profiles.before(move_camera))
+                    .with_system(move_camera)
+                    .with_system(select_hotbar_slot)
+                    .with_system(save_player),
+            );
+    }
+}
+
+/// The connection to the server, or to a server started for this game alone,
+/// and the other players it tells us about.
+#[cfg(feature = "render")]
+pub struct ClientPlugin;
+
+#[cfg(feature = "render")]
+impl Plugin for ClientPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<RemotePlayerEvent>()
+            .add_system_set(SystemSet::on_enter(GameState::Ingame).with_system(connect_to_server))
+            .add_system_set(
+                SystemSet::new()
+                    .with_run_criteria(run_ingame)
+                    .with_system(advance_loopback_clock.before(receive_server_messages))
+                    .with_system(receive_server_messages.before(WorldSystem::Light))
+                    .with_system(send_player_movement.after(move_camera))
+                    .with_system(update_remote_players.after(receive_server_messages))
+                    .with_system(interpolate_remote_players.after(update_remote_players))
+                    .with_system(
+                        position_name_tags
+                            .after(interpolate_remote_players)
+                            .after(move_camera),
+                    )
+                    .with_system(
+                        flush_server_connection
+                            .after(send_player_movement)
+                            .after(interact_with_blocks),
+                    ),
+            );
+    }
+}
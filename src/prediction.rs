@@ -0,0 +1,406 @@
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+
+use crate::inventory::ItemStack;
+use crate::key::Key;
+use crate::tick::BlockWorld;
+
+/// A block edit shown locally before the server has answered it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct PredictedEdit {
+    sequence: u32,
+    position: IVec3,
+    /// The item used up making the edit, given back if it is rejected.
+    spent: Option<ItemStack>,
+}
+
+/// What to undo once the server has answered an edit.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub struct Resolution {
+    /// The block and state the server says is at the position, when nothing else
+    /// is predicted there any more.
+    pub restore: Option<(IVec3, Option<Key<'static>>, u8)>,
+    pub refund: Option<ItemStack>,
+}
+
+/// The client's block edits that the server has not answered yet. Edits are
+/// applied to the local world straight away and numbered, then kept or rolled
+/// back when the server accepts or rejects that number.
+#[derive(Debug, Default)]
+pub struct Prediction {
+    next_sequence: u32,
+    pending: Vec<PredictedEdit>,
+    /// The latest block and state the server has sent for each position with
+    /// edits pending, which is what a rollback goes back to.
+    server_blocks: HashMap<IVec3, (Option<Key<'static>>, u8)>,
+}
+
+impl Prediction {
+    /// Applies an edit to the local world and returns the sequence number to
+    /// send it to the server with.
+    pub fn predict(
+        &mut self,
+        block_world: &mut BlockWorld,
+        position: IVec3,
+        block: Option<Key<'static>>,
+        spent: Option<ItemStack>,
+    ) -> u32 {
+        let sequence = self.next_sequence;
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+
+        self.server_blocks
+            .entry(position)
+            .or_insert_with(|| (block_world.block(position), block_world.state(position)));
+        self.pending.push(PredictedEdit {
+            sequence,
+            position,
+            spent,
+        });
+        block_world.set_block(position, block, 0);
+
+        sequence
+    }
+
+    /// Takes in a block change from the server. Changes to positions with edits
+    /// pending are held back so they do not undo the prediction. Returns whether
+    /// the change should be applied now.
+    pub fn server_change(
+        &mut self,
+        position: IVec3,
+        block: Option<Key<'static>>,
+        state: u8,
+    ) -> bool {
+        match self.server_blocks.get_mut(&position) {
+            Some(server_block) => {
+                *server_block = (block, state);
+                false
+            }
+            None => true,
+        }
+    }
+
+    /// Settles the edit with this sequence number. Accepted edits keep their
+    /// prediction, since the server sends the resulting block right after.
+    /// Rejected edits go back to the block the server says is there, unless a
+    /// later edit is still pending at the same position.
+    pub fn resolve(&mut self, sequence: u32, accepted: bool) -> Resolution {
+        let index = match self
+            .pending
+            .iter()
+            .position(|edit| edit.sequence == sequence)
+        {
+            Some(index) => index,
+            None => return Resolution::default(),
+        };
+        let edit = self.pending.remove(index);
+
+        let mut resolution = Resolution {
+            refund: if accepted { None } else { edit.spent },
+            ..default()
+        };
+
+        if !self
+            .pending
+            .iter()
+            .any(|other| other.position == edit.position)
+        {
+            let (block, state) = self.server_blocks.remove(&edit.position).unwrap();
+            if !accepted {
+                resolution.restore = Some((edit.position, block, state));
+            }
+        }
+
+        resolution
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chunk::{Block, Chunk};
+    use crate::inventory::Inventory;
+    use crate::loopback::{loopback_pair, LoopbackClock};
+    use crate::protocol::{ClientMessage, Connection, IdTable, ServerMessage};
+    use crate::registry::Registry;
+
+    const LATENCY: u64 = 50;
+
+    const STONE: Key<'static> = Key {
+        namespace: "test",
+        name: "stone",
+    };
+    const DIRT: Key<'static> = Key {
+        namespace: "test",
+        name: "dirt",
+    };
+
+    fn block_world<'r, 'w>(
+        world: &'w mut World,
+        registry: &'r Registry<'static>,
+    ) -> BlockWorld<'r, 'w> {
+        BlockWorld::new(
+            world
+                .query::<&mut Chunk<'static>>()
+                .iter_mut(world)
+                .map(|chunk| (chunk.position, chunk))
+                .collect(),
+            registry,
+            0,
+        )
+    }
+
+    /// A client with one chunk loaded, talking to a scripted server over a
+    /// loopback stream.
+    struct Harness {
+        clock: LoopbackClock,
+        client: Connection,
+        server: Connection,
+        ids: IdTable,
+        registry: Registry<'static>,
+        world: World,
+        prediction: Prediction,
+        inventory: Inventory,
+    }
+
+    impl Harness {
+        fn new() -> Self {
+            let clock = LoopbackClock::default();
+            let (client, server) = loopback_pair(&clock, LATENCY);
+
+            let mut registry = Registry::default();
+            for key in [STONE, DIRT] {
+                registry.blocks.insert(
+                    key,
+                    Block {
+                        solid: true,
+                        ..default()
+                    },
+                );
+            }
+
+            let mut world = World::new();
+            world.spawn().insert(Chunk::new(IVec3::ZERO));
+
+            Harness {
+                clock,
+                client: Connection::from_transport(client),
+                server: Connection::from_transport(server),
+                ids: IdTable::new([STONE, DIRT]),
+                registry,
+                world,
+                prediction: Prediction::default(),
+                inventory: Inventory::default(),
+            }
+        }
+
+        fn block(&mut self, position: IVec3) -> Option<Key<'static>> {
+            block_world(&mut self.world, &self.registry).block(position)
+        }
+
+        fn set_block(&mut self, position: IVec3, block: Option<Key<'static>>, state: u8) {
+            block_world(&mut self.world, &self.registry).set_block(position, block, state);
+        }
+
+        /// Places or breaks a block locally and sends the edit to the server,
+        /// like the client does.
+        fn edit(&mut self, position: IVec3, block: Option<Key<'static>>) -> u32 {
+            let spent = block
+                .filter(|block| self.inventory.remove(*block, 1) == 1)
+                .map(|item| ItemStack { item, count: 1 });
+            let sequence = self.prediction.predict(
+                &mut block_world(&mut self.world, &self.registry),
+                position,
+                block,
+                spent,
+            );
+
+            self.client.send(&ClientMessage::EditBlock {
+                sequence,
+                position,
+                block: self.ids.id(block).unwrap(),
+            });
+            self.client.flush().unwrap();
+            sequence
+        }
+
+        fn server_send(&mut self, message: ServerMessage) {
+            self.server.send(&message);
+            self.server.flush().unwrap();
+        }
+
+        fn server_receive(&mut self) -> Vec<ClientMessage> {
+            self.server.receive().unwrap()
+        }
+
+        /// Moves the clock on and handles whatever has reached the client by then,
+        /// like the client does.
+        fn step(&mut self, milliseconds: u64) {
+            self.clock.advance(milliseconds);
+
+            for message in self.client.receive::<ServerMessage>().unwrap() {
+                match message {
+                    ServerMessage::BlockChange {
+                        position,
+                        block,
+                        state,
+                    } => {
+                        let block = self.ids.key(block).unwrap();
+                        if self.prediction.server_change(position, block, state) {
+                            self.set_block(position, block, state);
+                        }
+                    }
+                    ServerMessage::EditResult { sequence, accepted } => {
+                        let resolution = self.prediction.resolve(sequence, accepted);
+
+                        if let Some((position, block, state)) = resolution.restore {
+                            self.set_block(position, block, state);
+                        }
+                        if let Some(stack) = resolution.refund {
+                            self.inventory
+                                .insert(stack, self.registry.max_stack_size(stack.item));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn accepted_edits_are_kept() {
+        let mut harness = Harness::new();
+        let position = IVec3::new(3, 4, 5);
+        harness.inventory.insert(
+            ItemStack {
+                item: STONE,
+                count: 1,
+            },
+            64,
+        );
+
+        let sequence = harness.edit(position, Some(STONE));
+        assert_eq!(harness.block(position), Some(STONE));
+        assert_eq!(harness.inventory.selected_stack(), None);
+
+        harness.step(LATENCY - 1);
+        assert!(harness.server_receive().is_empty());
+        harness.step(1);
+        assert_eq!(
+            harness.server_receive(),
+            vec![ClientMessage::EditBlock {
+                sequence,
+                position,
+                block: harness.ids.id(Some(STONE)).unwrap(),
+            }]
+        );
+
+        harness.server_send(ServerMessage::EditResult {
+            sequence,
+            accepted: true,
+        });
+        harness.server_send(ServerMessage::BlockChange {
+            position,
+            block: harness.ids.id(Some(STONE)).unwrap(),
+            state: 0,
+        });
+        harness.step(LATENCY);
+
+        assert_eq!(harness.block(position), Some(STONE));
+        assert_eq!(harness.inventory.selected_stack(), None);
+        assert!(harness.prediction.pending.is_empty());
+        assert!(harness.prediction.server_blocks.is_empty());
+    }
+
+    #[test]
+    fn rejected_edits_are_rolled_back_and_refunded() {
+        let mut harness = Harness::new();
+        let position = IVec3::new(3, 4, 5);
+        let stone = ItemStack {
+            item: STONE,
+            count: 1,
+        };
+        harness.inventory.insert(stone, 64);
+
+        let sequence = harness.edit(position, Some(STONE));
+        harness.step(LATENCY);
+        assert_eq!(harness.server_receive().len(), 1);
+
+        harness.server_send(ServerMessage::BlockChange {
+            position,
+            block: 0,
+            state: 0,
+        });
+        harness.server_send(ServerMessage::EditResult {
+            sequence,
+            accepted: false,
+        });
+
+        harness.step(LATENCY - 1);
+        assert_eq!(harness.block(position), Some(STONE));
+        assert_eq!(harness.inventory.selected_stack(), None);
+
+        harness.step(1);
+        assert_eq!(harness.block(position), None);
+        assert_eq!(harness.inventory.selected_stack(), Some(stone));
+        assert!(harness.prediction.server_blocks.is_empty());
+    }
+
+    #[test]
+    fn held_back_changes_are_applied_once_resolved() {
+        let mut harness = Harness::new();
+        let position = IVec3::new(3, 4, 5);
+        harness.set_block(position, Some(DIRT), 0);
+
+        // Someone else replaces the block while this client's edit of it is on
+        // its way.
+        let sequence = harness.edit(position, None);
+        harness.server_send(ServerMessage::BlockChange {
+            position,
+            block: harness.ids.id(Some(STONE)).unwrap(),
+            state: 2,
+        });
+
+        harness.step(LATENCY);
+        assert_eq!(harness.block(position), None);
+        assert_eq!(harness.server_receive().len(), 1);
+
+        harness.server_send(ServerMessage::EditResult {
+            sequence,
+            accepted: false,
+        });
+        harness.step(LATENCY);
+
+        assert_eq!(harness.block(position), Some(STONE));
+        assert_eq!(
+            block_world(&mut harness.world, &harness.registry).state(position),
+            2
+        );
+        assert_eq!(harness.inventory.selected_stack(), None);
+    }
+
+    #[test]
+    fn later_edits_at_the_same_position_hold_back_the_rollback() {
+        let mut harness = Harness::new();
+        let position = IVec3::new(3, 4, 5);
+        harness.set_block(position, Some(DIRT), 0);
+
+        let first = harness.edit(position, None);
+        let second = harness.edit(position, Some(STONE));
+        harness.step(LATENCY);
+        assert_eq!(harness.server_receive().len(), 2);
+
+        harness.server_send(ServerMessage::EditResult {
+            sequence: first,
+            accepted: false,
+        });
+        harness.step(LATENCY);
+        assert_eq!(harness.block(position), Some(STONE));
+
+        harness.server_send(ServerMessage::EditResult {
+            sequence: second,
+            accepted: false,
+        });
+        harness.step(LATENCY);
+        assert_eq!(harness.block(position), Some(DIRT));
+    }
+}
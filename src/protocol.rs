@@ -0,0 +1,1166 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+
+use crate::chunk::{Chunk, CHUNK_VOLUME};
+use crate::inventory::INVENTORY_SIZE;
+use crate::key::Key;
+use crate::registry::Registry;
+
+/// Bumped whenever the encoding of any packet changes. Clients and servers only
+/// talk to the same version.
+pub const PROTOCOL_VERSION: u32 = 4;
+
+/// Frames longer than this are treated as a broken connection rather than
+/// allocated.
+pub const MAX_FRAME_LENGTH: usize = 16 * 1024 * 1024;
+
+/// Longest string, in bytes, any packet may hold.
+pub const MAX_STRING_LENGTH: usize = 1024;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProtocolError {
+    /// The packet ended before everything in it was read.
+    UnexpectedEnd,
+    /// Bytes were left over after the whole packet was read.
+    TrailingBytes(usize),
+    UnknownPacket(u8),
+    /// A string or list claims to be longer than the limit or the packet.
+    InvalidLength(usize),
+    InvalidString,
+    /// A float that is infinite or not a number.
+    InvalidFloat,
+    /// A variable length integer that does not fit in 32 bits.
+    InvalidVarint,
+    /// An ID missing from the synced registry table.
+    UnknownId(u16),
+    /// A block index outside of a chunk.
+    InvalidBlockIndex(u16),
+    /// Compressed chunk data that does not fill exactly one chunk.
+    InvalidChunkData,
+    FrameTooLong(usize),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtocolError::UnexpectedEnd => write!(f, "packet ended early"),
+            ProtocolError::TrailingBytes(count) => write!(f, "{} bytes left after packet", count),
+            ProtocolError::UnknownPacket(id) => write!(f, "unknown packet {}", id),
+            ProtocolError::InvalidLength(length) => write!(f, "invalid length {}", length),
+            ProtocolError::InvalidString => write!(f, "string is not UTF-8"),
+            ProtocolError::InvalidFloat => write!(f, "float is not finite"),
+            ProtocolError::InvalidVarint => write!(f, "varint is too long"),
+            ProtocolError::UnknownId(id) => write!(f, "unknown registry ID {}", id),
+            ProtocolError::InvalidBlockIndex(index) => {
+                write!(f, "block index {} is outside a chunk", index)
+            }
+            ProtocolError::InvalidChunkData => write!(f, "chunk data does not fill a chunk"),
+            ProtocolError::FrameTooLong(length) => {
+                write!(f, "frame of {} bytes is too long", length)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {}
+
+impl From<ProtocolError> for io::Error {
+    fn from(error: ProtocolError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, error)
+    }
+}
+
+/// Writes the fields of a packet, little endian.
+#[derive(Default)]
+pub struct PacketWriter {
+    pub bytes: Vec<u8>,
+}
+
+impl PacketWriter {
+    pub fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn i32(&mut self, value: i32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn f32(&mut self, value: f32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Seven bits per byte, low bits first, with the top bit set on every byte
+    /// but the last.
+    pub fn varint(&mut self, mut value: u32) {
+        while value >= 0x80 {
+            self.u8(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.u8(value as u8);
+    }
+
+    /// Writes at most [`MAX_STRING_LENGTH`] bytes of `value`, cut at a character
+    /// boundary, since readers reject anything longer.
+    pub fn string(&mut self, value: &str) {
+        let mut length = value.len().min(MAX_STRING_LENGTH);
+        while !value.is_char_boundary(length) {
+            length -= 1;
+        }
+        let value = &value[..length];
+
+        self.varint(value.len() as u32);
+        self.bytes.extend_from_slice(value.as_bytes());
+    }
+
+    pub fn ivec3(&mut self, value: IVec3) {
+        for component in value.to_array() {
+            self.i32(component);
+        }
+    }
+
+    pub fn vec3(&mut self, value: Vec3) {
+        for component in value.to_array() {
+            self.f32(component);
+        }
+    }
+}
+
+/// Reads the fields of a packet, failing instead of reading past its end.
+pub struct PacketReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> PacketReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        PacketReader { bytes }
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], ProtocolError> {
+        if self.bytes.len() < N {
+            return Err(ProtocolError::UnexpectedEnd);
+        }
+
+        let (taken, rest) = self.bytes.split_at(N);
+        self.bytes = rest;
+
+        Ok(taken.try_into().unwrap())
+    }
+
+    pub fn u8(&mut self) -> Result<u8, ProtocolError> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    pub fn u16(&mut self) -> Result<u16, ProtocolError> {
+        Ok(u16::from_le_bytes(self.take()?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, ProtocolError> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    pub fn i32(&mut self) -> Result<i32, ProtocolError> {
+        Ok(i32::from_le_bytes(self.take()?))
+    }
+
+    pub fn f32(&mut self) -> Result<f32, ProtocolError> {
+        let value = f32::from_le_bytes(self.take()?);
+
+        if value.is_finite() {
+            Ok(value)
+        } else {
+            Err(ProtocolError::InvalidFloat)
+        }
+    }
+
+    pub fn varint(&mut self) -> Result<u32, ProtocolError> {
+        let mut value = 0u32;
+
+        for shift in (0..35).step_by(7) {
+            let byte = self.u8()?;
+            let bits = (byte & 0x7f) as u32;
+
+            if shift == 28 && bits > 0x0f {
+                return Err(ProtocolError::InvalidVarint);
+            }
+            value |= bits << shift;
+
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+
+        Err(ProtocolError::InvalidVarint)
+    }
+
+    /// Reads the length of a list whose entries are at least `entry_size` bytes
+    /// each, so a lying length cannot make the reader allocate more than the
+    /// packet could hold.
+    pub fn length(&mut self, entry_size: usize, max: usize) -> Result<usize, ProtocolError> {
+        let length = self.varint()? as usize;
+
+        if length > max || length.saturating_mul(entry_size) > self.bytes.len() {
+            return Err(ProtocolError::InvalidLength(length));
+        }
+
+        Ok(length)
+    }
+
+    pub fn string(&mut self) -> Result<String, ProtocolError> {
+        let length = self.length(1, MAX_STRING_LENGTH)?;
+        let (bytes, rest) = self.bytes.split_at(length);
+        self.bytes = rest;
+
+        String::from_utf8(bytes.to_vec()).map_err(|_| ProtocolError::InvalidString)
+    }
+
+    pub fn ivec3(&mut self) -> Result<IVec3, ProtocolError> {
+        Ok(IVec3::new(self.i32()?, self.i32()?, self.i32()?))
+    }
+
+    pub fn vec3(&mut self) -> Result<Vec3, ProtocolError> {
+        Ok(Vec3::new(self.f32()?, self.f32()?, self.f32()?))
+    }
+
+    /// Fails if anything is left unread.
+    pub fn finish(self) -> Result<(), ProtocolError> {
+        match self.bytes.len() {
+            0 => Ok(()),
+            count => Err(ProtocolError::TrailingBytes(count)),
+        }
+    }
+}
+
+/// Something that can be sent over a [`Connection`].
+pub trait Packet: Sized {
+    fn encode(&self, writer: &mut PacketWriter);
+    fn decode(reader: &mut PacketReader) -> Result<Self, ProtocolError>;
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = PacketWriter::default();
+        self.encode(&mut writer);
+
+        writer.bytes
+    }
+
+    /// Decodes a whole packet, failing if it is malformed or has bytes left over.
+    fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let mut reader = PacketReader::new(bytes);
+        let packet = Self::decode(&mut reader)?;
+        reader.finish()?;
+
+        Ok(packet)
+    }
+}
+
+/// Numbers the keys of a registry, so packets can name them in two bytes. `0`
+/// always stands for nothing, such as an empty block.
+#[derive(Debug, Clone, Default)]
+pub struct IdTable {
+    /// The key for each ID, starting at `1`. Keys the other side knows about but
+    /// this one does not are `None`.
+    keys: Vec<Option<Key<'static>>>,
+    names: Vec<String>,
+    ids: HashMap<Key<'static>, u16>,
+}
+
+impl IdTable {
+    /// Numbers `keys` in order of their names, so the same keys always get the
+    /// same IDs.
+    pub fn new(keys: impl IntoIterator<Item = Key<'static>>) -> Self {
+        let mut keys: Vec<Key<'static>> = keys.into_iter().collect();
+        keys.sort_by_key(|key| key.to_string());
+        keys.truncate(u16::MAX as usize);
+
+        let names: Vec<String> = keys.iter().map(|key| key.to_string()).collect();
+
+        IdTable::from_names(names, |name| {
+            keys.iter().copied().find(|key| key.to_string() == name)
+        })
+    }
+
+    /// The table the other side sent, with each name looked up by `resolve`.
+    pub fn from_names(names: Vec<String>, resolve: impl Fn(&str) -> Option<Key<'static>>) -> Self {
+        let keys: Vec<Option<Key<'static>>> = names.iter().map(|name| resolve(name)).collect();
+        let ids = keys
+            .iter()
+            .enumerate()
+            .filter_map(|(index, key)| Some(((*key)?, index as u16 + 1)))
+            .collect();
+
+        IdTable { keys, names, ids }
+    }
+
+    pub fn names(&self) -> &[String] {
+        &self.names
+    }
+
+    /// The ID of `key`, if the other side knows about it.
+    pub fn id(&self, key: Option<Key<'static>>) -> Option<u16> {
+        match key {
+            Some(key) => self.ids.get(&key).copied(),
+            None => Some(0),
+        }
+    }
+
+    pub fn key(&self, id: u16) -> Result<Option<Key<'static>>, ProtocolError> {
+        match id {
+            0 => Ok(None),
+            id => self
+                .keys
+                .get(id as usize - 1)
+                .copied()
+                .ok_or(ProtocolError::UnknownId(id)),
+        }
+    }
+}
+
+/// The [`IdTable`]s for the blocks and items of a registry.
+#[derive(Debug, Clone, Default)]
+pub struct RegistryIds {
+    pub blocks: IdTable,
+    pub items: IdTable,
+}
+
+impl RegistryIds {
+    pub fn new(registry: &Registry<'static>) -> Self {
+        RegistryIds {
+            blocks: IdTable::new(registry.blocks.keys().copied()),
+            items: IdTable::new(registry.items.keys().copied()),
+        }
+    }
+
+    /// The tables a server sent in its [`ServerMessage::Welcome`], matched up
+    /// with this side's registry.
+    pub fn from_welcome(
+        blocks: Vec<String>,
+        items: Vec<String>,
+        registry: &Registry<'static>,
+    ) -> Self {
+        RegistryIds {
+            blocks: IdTable::from_names(blocks, |name| registry.block_key(name)),
+            items: IdTable::from_names(items, |name| registry.item_key(name)),
+        }
+    }
+
+    pub fn welcome(&self) -> ServerMessage {
+        ServerMessage::Welcome {
+            blocks: self.blocks.names().to_vec(),
+            items: self.items.names().to_vec(),
+        }
+    }
+}
+
+/// The blocks of a chunk as IDs from the block [`IdTable`]. On the wire, runs of
+/// the same block and state are sent once.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkData {
+    pub position: IVec3,
+    pub blocks: Vec<u16>,
+    pub states: Vec<u8>,
+}
+
+impl ChunkData {
+    pub fn from_chunk(chunk: &Chunk<'static>, block_ids: &IdTable) -> Self {
+        ChunkData {
+            position: chunk.position,
+            blocks: chunk
+                .blocks
+                .iter()
+                .map(|block| block_ids.id(*block).unwrap_or(0))
+                .collect(),
+            states: chunk.states.to_vec(),
+        }
+    }
+
+    /// Rebuilds the chunk. Blocks this side has not registered are left empty.
+    pub fn to_chunk(&self, block_ids: &IdTable) -> Result<Chunk<'static>, ProtocolError> {
+        if self.blocks.len() != CHUNK_VOLUME || self.states.len() != CHUNK_VOLUME {
+            return Err(ProtocolError::InvalidChunkData);
+        }
+
+        let mut chunk = Chunk::new(self.position);
+        for (index, block) in self.blocks.iter().enumerate() {
+            chunk.blocks[index] = block_ids.key(*block)?;
+        }
+        chunk.states.copy_from_slice(&self.states);
+
+        Ok(chunk)
+    }
+
+    fn encode(&self, writer: &mut PacketWriter) {
+        writer.ivec3(self.position);
+
+        let mut runs: Vec<(u32, u16, u8)> = Vec::new();
+        for (block, state) in self.blocks.iter().zip(&self.states) {
+            match runs.last_mut() {
+                Some((length, run_block, run_state))
+                    if run_block == block && run_state == state =>
+                {
+                    *length += 1
+                }
+                _ => runs.push((1, *block, *state)),
+            }
+        }
+
+        writer.varint(runs.len() as u32);
+        for (length, block, state) in runs {
+            writer.varint(length);
+            writer.u16(block);
+            writer.u8(state);
+        }
+    }
+
+    fn decode(reader: &mut PacketReader) -> Result<Self, ProtocolError> {
+        let position = reader.ivec3()?;
+        let runs = reader.length(4, CHUNK_VOLUME)?;
+
+        let mut blocks = Vec::with_capacity(CHUNK_VOLUME);
+        let mut states = Vec::with_capacity(CHUNK_VOLUME);
+        for _ in 0..runs {
+            let length = reader.varint()? as usize;
+            let block = reader.u16()?;
+            let state = reader.u8()?;
+
+            if length == 0 || blocks.len() + length > CHUNK_VOLUME {
+                return Err(ProtocolError::InvalidChunkData);
+            }
+            blocks.resize(blocks.len() + length, block);
+            states.resize(states.len() + length, state);
+        }
+
+        if blocks.len() != CHUNK_VOLUME {
+            return Err(ProtocolError::InvalidChunkData);
+        }
+
+        Ok(ChunkData {
+            position,
+            blocks,
+            states,
+        })
+    }
+}
+
+/// One block of a [`ServerMessage::BulkBlockChange`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BlockChangeEntry {
+    /// Index of the block in its chunk.
+    pub index: u16,
+    pub block: u16,
+    pub state: u8,
+}
+
+/// Sent by clients to the server.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientMessage {
+    /// The first message on a new connection.
+    Hello {
+        version: u32,
+        name: String,
+        view_radius: u32,
+    },
+    PlayerPosition {
+        position: Vec3,
+    },
+    PlayerLook {
+        pitch: f32,
+        yaw: f32,
+    },
+    /// Asks for the block at `position` to be replaced by the block with ID
+    /// `block`, or broken if it is `0`. The server answers with an
+    /// [`ServerMessage::EditResult`] carrying the same sequence number.
+    EditBlock {
+        sequence: u32,
+        position: IVec3,
+        block: u16,
+    },
+    /// Answers the server's keepalive with the same ID.
+    KeepAlive {
+        id: u32,
+    },
+}
+
+impl Packet for ClientMessage {
+    fn encode(&self, writer: &mut PacketWriter) {
+        match self {
+            ClientMessage::Hello {
+                version,
+                name,
+                view_radius,
+            } => {
+                writer.u8(0);
+                writer.u32(*version);
+                writer.string(name);
+                writer.u32(*view_radius);
+            }
+            ClientMessage::PlayerPosition { position } => {
+                writer.u8(1);
+                writer.vec3(*position);
+            }
+            ClientMessage::PlayerLook { pitch, yaw } => {
+                writer.u8(2);
+                writer.f32(*pitch);
+                writer.f32(*yaw);
+            }
+            ClientMessage::EditBlock {
+                sequence,
+                position,
+                block,
+            } => {
+                writer.u8(3);
+                writer.u32(*sequence);
+                writer.ivec3(*position);
+                writer.u16(*block);
+            }
+            ClientMessage::KeepAlive { id } => {
+                writer.u8(4);
+                writer.u32(*id);
+            }
+        }
+    }
+
+    fn decode(reader: &mut PacketReader) -> Result<Self, ProtocolError> {
+        Ok(match reader.u8()? {
+            0 => ClientMessage::Hello {
+                version: reader.u32()?,
+                name: reader.string()?,
+                view_radius: reader.u32()?,
+            },
+            1 => ClientMessage::PlayerPosition {
+                position: reader.vec3()?,
+            },
+            2 => ClientMessage::PlayerLook {
+                pitch: reader.f32()?,
+                yaw: reader.f32()?,
+            },
+            3 => ClientMessage::EditBlock {
+                sequence: reader.u32()?,
+                position: reader.ivec3()?,
+                block: reader.u16()?,
+            },
+            4 => ClientMessage::KeepAlive { id: reader.u32()? },
+            id => return Err(ProtocolError::UnknownPacket(id)),
+        })
+    }
+}
+
+/// Sent by the server to clients.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServerMessage {
+    /// Answers a client's hello with the names behind every block and item ID.
+    Welcome {
+        blocks: Vec<String>,
+        items: Vec<String>,
+    },
+    /// Sent just before the server hangs up.
+    Disconnect {
+        reason: String,
+    },
+    ChunkData(ChunkData),
+    UnloadChunk {
+        position: IVec3,
+    },
+    BlockChange {
+        position: IVec3,
+        block: u16,
+        state: u8,
+    },
+    /// Several blocks changing in one chunk.
+    BulkBlockChange {
+        chunk: IVec3,
+        changes: Vec<BlockChangeEntry>,
+    },
+    /// Items for the player's inventory, such as the drops of a block they broke,
+    /// as item IDs and counts.
+    GiveItems {
+        stacks: Vec<(u16, u32)>,
+    },
+    /// Checks the client is still there. It answers with the same ID.
+    KeepAlive {
+        id: u32,
+    },
+    /// Whether the edit with this sequence number was made. A rejected edit is
+    /// preceded by a [`ServerMessage::BlockChange`] with the block actually there.
+    EditResult {
+        sequence: u32,
+        accepted: bool,
+    },
+    /// Another player is in the game, either just joined or already there when
+    /// this client joined.
+    PlayerJoined {
+        id: u32,
+        name: String,
+    },
+    PlayerLeft {
+        id: u32,
+    },
+    /// Where another player's camera was at `time`, in milliseconds of the
+    /// server's clock.
+    PlayerMoved {
+        id: u32,
+        time: u32,
+        position: Vec3,
+        pitch: f32,
+        yaw: f32,
+    },
+    /// The player's whole inventory, sent when it joins, as an item ID and count
+    /// for every slot. Empty slots have the item ID 0.
+    SetInventory {
+        slots: Vec<(u16, u32)>,
+    },
+}
+
+impl Packet for ServerMessage {
+    fn encode(&self, writer: &mut PacketWriter) {
+        match self {
+            ServerMessage::Welcome { blocks, items } => {
+                writer.u8(0);
+                for names in [blocks, items] {
+                    writer.varint(names.len() as u32);
+                    for name in names {
+                        writer.string(name);
+                    }
+                }
+            }
+            ServerMessage::Disconnect { reason } => {
+                writer.u8(1);
+                writer.string(reason);
+            }
+            ServerMessage::ChunkData(data) => {
+                writer.u8(2);
+                data.encode(writer);
+            }
+            ServerMessage::UnloadChunk { position } => {
+                writer.u8(3);
+                writer.ivec3(*position);
+            }
+            ServerMessage::BlockChange {
+                position,
+                block,
+                state,
+            } => {
+                writer.u8(4);
+                writer.ivec3(*position);
+                writer.u16(*block);
+                writer.u8(*state);
+            }
+            ServerMessage::BulkBlockChange { chunk, changes } => {
+                writer.u8(5);
+                writer.ivec3(*chunk);
+                writer.varint(changes.len() as u32);
+                for change in changes {
+                    writer.u16(change.index);
+                    writer.u16(change.block);
+                    writer.u8(change.state);
+                }
+            }
+            ServerMessage::GiveItems { stacks } => {
+                writer.u8(6);
+                writer.varint(stacks.len() as u32);
+                for (item, count) in stacks {
+                    writer.u16(*item);
+                    writer.u32(*count);
+                }
+            }
+            ServerMessage::KeepAlive { id } => {
+                writer.u8(7);
+                writer.u32(*id);
+            }
+            ServerMessage::EditResult { sequence, accepted } => {
+                writer.u8(8);
+                writer.u32(*sequence);
+                writer.u8(*accepted as u8);
+            }
+            ServerMessage::PlayerJoined { id, name } => {
+                writer.u8(9);
+                writer.u32(*id);
+                writer.string(name);
+            }
+            ServerMessage::PlayerLeft { id } => {
+                writer.u8(10);
+                writer.u32(*id);
+            }
+            ServerMessage::PlayerMoved {
+                id,
+                time,
+                position,
+                pitch,
+                yaw,
+            } => {
+                writer.u8(11);
+                writer.u32(*id);
+                writer.u32(*time);
+                writer.vec3(*position);
+                writer.f32(*pitch);
+                writer.f32(*yaw);
+            }
+            ServerMessage::SetInventory { slots } => {
+                writer.u8(12);
+                writer.varint(slots.len() as u32);
+                for (item, count) in slots {
+                    writer.u16(*item);
+                    writer.u32(*count);
+                }
+            }
+        }
+    }
+
+    fn decode(reader: &mut PacketReader) -> Result<Self, ProtocolError> {
+        Ok(match reader.u8()? {
+            0 => {
+                let mut tables = [Vec::new(), Vec::new()];
+                for names in &mut tables {
+                    let length = reader.length(1, u16::MAX as usize)?;
+                    for _ in 0..length {
+                        names.push(reader.string()?);
+                    }
+                }
+                let [blocks, items] = tables;
+
+                ServerMessage::Welcome { blocks, items }
+            }
+            1 => ServerMessage::Disconnect {
+                reason: reader.string()?,
+            },
+            2 => ServerMessage::ChunkData(ChunkData::decode(reader)?),
+            3 => ServerMessage::UnloadChunk {
+                position: reader.ivec3()?,
+            },
+            4 => ServerMessage::BlockChange {
+                position: reader.ivec3()?,
+                block: reader.u16()?,
+                state: reader.u8()?,
+            },
+            5 => {
+                let chunk = reader.ivec3()?;
+                let length = reader.length(5, CHUNK_VOLUME)?;
+                let mut changes = Vec::with_capacity(length);
+                for _ in 0..length {
+                    let index = reader.u16()?;
+                    if index as usize >= CHUNK_VOLUME {
+                        return Err(ProtocolError::InvalidBlockIndex(index));
+                    }
+
+                    changes.push(BlockChangeEntry {
+                        index,
+                        block: reader.u16()?,
+                        state: reader.u8()?,
+                    });
+                }
+
+                ServerMessage::BulkBlockChange { chunk, changes }
+            }
+            6 => {
+                let length = reader.length(6, usize::MAX)?;
+                let mut stacks = Vec::with_capacity(length);
+                for _ in 0..length {
+                    stacks.push((reader.u16()?, reader.u32()?));
+                }
+
+                ServerMessage::GiveItems { stacks }
+            }
+            7 => ServerMessage::KeepAlive { id: reader.u32()? },
+            8 => ServerMessage::EditResult {
+                sequence: reader.u32()?,
+                accepted: reader.u8()? != 0,
+            },
+            9 => ServerMessage::PlayerJoined {
+                id: reader.u32()?,
+                name: reader.string()?,
+            },
+            10 => ServerMessage::PlayerLeft { id: reader.u32()? },
+            11 => ServerMessage::PlayerMoved {
+                id: reader.u32()?,
+                time: reader.u32()?,
+                position: reader.vec3()?,
+                pitch: reader.f32()?,
+                yaw: reader.f32()?,
+            },
+            12 => {
+                let length = reader.length(6, INVENTORY_SIZE)?;
+                let mut slots = Vec::with_capacity(length);
+                for _ in 0..length {
+                    slots.push((reader.u16()?, reader.u32()?));
+                }
+
+                ServerMessage::SetInventory { slots }
+            }
+            id => return Err(ProtocolError::UnknownPacket(id)),
+        })
+    }
+}
+
+/// A byte stream packets can be sent over. Reads and writes must not block:
+/// they fail with [`io::ErrorKind::WouldBlock`] instead.
+pub trait Transport: Read + Write + Send + Sync {}
+
+impl<T: Read + Write + Send + Sync> Transport for T {}
+
+/// A packet stream over TCP or any other [`Transport`]. Every packet is framed by
+/// its length as a little endian `u32`. Nothing blocks: sends are buffered until
+/// [`Connection::flush`], and receiving returns only the packets that have fully
+/// arrived.
+pub struct Connection {
+    stream: Box<dyn Transport>,
+    incoming: Vec<u8>,
+    outgoing: Vec<u8>,
+    /// Whether the other side has hung up.
+    closed: bool,
+}
+
+impl Connection {
+    pub fn new(stream: TcpStream) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        stream.set_nodelay(true)?;
+
+        Ok(Connection::from_transport(stream))
+    }
+
+    pub fn from_transport(stream: impl Transport + 'static) -> Self {
+        Connection {
+            stream: Box::new(stream),
+            incoming: Vec::new(),
+            outgoing: Vec::new(),
+            closed: false,
+        }
+    }
+
+    pub fn send(&mut self, packet: &impl Packet) {
+        let payload = packet.to_bytes();
+
+        self.outgoing
+            .extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        self.outgoing.extend_from_slice(&payload);
+    }
+
+    /// Writes as much of what was sent as the socket takes right now.
+    pub fn flush(&mut self) -> io::Result<()> {
+        while !self.outgoing.is_empty() {
+            match self.stream.write(&self.outgoing) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(written) => {
+                    self.outgoing.drain(..written);
+                }
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(error) => return Err(error),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether everything sent has been written to the socket.
+    pub fn is_flushed(&self) -> bool {
+        self.outgoing.is_empty()
+    }
+
+    /// Every packet that has fully arrived since the last call. Fails once the
+    /// other side has hung up and every packet before that has been received, or
+    /// when it sends a malformed packet.
+    pub fn receive<P: Packet>(&mut self) -> io::Result<Vec<P>> {
+        let mut buffer = [0; 4096];
+        while !self.closed {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => self.closed = true,
+                Ok(read) => self.incoming.extend_from_slice(&buffer[..read]),
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                Err(error) => return Err(error),
+            }
+        }
+
+        let mut packets = Vec::new();
+        while self.incoming.len() >= 4 {
+            let length = u32::from_le_bytes(self.incoming[..4].try_into().unwrap()) as usize;
+            if length > MAX_FRAME_LENGTH {
+                return Err(ProtocolError::FrameTooLong(length).into());
+            }
+            if self.incoming.len() < 4 + length {
+                break;
+            }
+
+            packets.push(P::from_bytes(&self.incoming[4..4 + length])?);
+            self.incoming.drain(..4 + length);
+        }
+
+        if self.closed && packets.is_empty() {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        Ok(packets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loopback::{loopback_pair, LoopbackClock};
+
+    const STONE: Key<'static> = Key {
+        namespace: "test",
+        name: "stone",
+    };
+
+    fn client_messages() -> Vec<ClientMessage> {
+        vec![
+            ClientMessage::Hello {
+                version: PROTOCOL_VERSION,
+                name: "Player".to_string(),
+                view_radius: 4,
+            },
+            ClientMessage::PlayerPosition {
+                position: Vec3::new(1.5, -2.0, 300.25),
+            },
+            ClientMessage::PlayerLook {
+                pitch: -0.5,
+                yaw: 3.0,
+            },
+            ClientMessage::EditBlock {
+                sequence: 7,
+                position: IVec3::new(-1, 20, 5),
+                block: 2,
+            },
+            ClientMessage::KeepAlive { id: u32::MAX },
+        ]
+    }
+
+    fn chunk_data() -> ChunkData {
+        let mut chunk = Chunk::new(IVec3::new(1, -2, 3));
+        for block in &mut chunk.blocks[..CHUNK_VOLUME / 2] {
+            *block = Some(STONE);
+        }
+        chunk.states[5] = 3;
+
+        ChunkData::from_chunk(&chunk, &IdTable::new([STONE]))
+    }
+
+    fn server_messages() -> Vec<ServerMessage> {
+        vec![
+            ServerMessage::Welcome {
+                blocks: vec!["test:stone".to_string(), "test:dirt".to_string()],
+                items: vec!["test:stick".to_string()],
+            },
+            ServerMessage::Disconnect {
+                reason: "Server closed".to_string(),
+            },
+            ServerMessage::ChunkData(chunk_data()),
+            ServerMessage::UnloadChunk {
+                position: IVec3::new(0, -1, 2),
+            },
+            ServerMessage::BlockChange {
+                position: IVec3::new(4, 5, -6),
+                block: 1,
+                state: 2,
+            },
+            ServerMessage::BulkBlockChange {
+                chunk: IVec3::new(1, 1, 1),
+                changes: vec![
+                    BlockChangeEntry {
+                        index: 0,
+                        block: 1,
+                        state: 0,
+                    },
+                    BlockChangeEntry {
+                        index: (CHUNK_VOLUME - 1) as u16,
+                        block: 0,
+                        state: 7,
+                    },
+                ],
+            },
+            ServerMessage::GiveItems {
+                stacks: vec![(1, 64), (2, 1)],
+            },
+            ServerMessage::KeepAlive { id: 9 },
+            ServerMessage::EditResult {
+                sequence: 7,
+                accepted: true,
+            },
+            ServerMessage::PlayerJoined {
+                id: 3,
+                name: "Other".to_string(),
+            },
+            ServerMessage::PlayerLeft { id: 3 },
+            ServerMessage::PlayerMoved {
+                id: 3,
+                time: 1000,
+                position: Vec3::new(0.5, 17.0, -4.0),
+                pitch: 0.1,
+                yaw: -1.2,
+            },
+            ServerMessage::SetInventory {
+                slots: vec![(1, 64), (0, 0), (2, 1)],
+            },
+        ]
+    }
+
+    #[test]
+    fn client_messages_round_trip() {
+        for message in client_messages() {
+            assert_eq!(ClientMessage::from_bytes(&message.to_bytes()), Ok(message));
+        }
+    }
+
+    #[test]
+    fn server_messages_round_trip() {
+        for message in server_messages() {
+            assert_eq!(ServerMessage::from_bytes(&message.to_bytes()), Ok(message));
+        }
+    }
+
+    #[test]
+    fn chunk_data_is_run_length_encoded() {
+        let data = chunk_data();
+        let bytes = ServerMessage::ChunkData(data.clone()).to_bytes();
+        assert!(bytes.len() < 64);
+
+        let chunk = data.to_chunk(&IdTable::new([STONE])).unwrap();
+        assert_eq!(chunk.blocks[0], Some(STONE));
+        assert_eq!(chunk.blocks[CHUNK_VOLUME - 1], None);
+        assert_eq!(chunk.states[5], 3);
+    }
+
+    #[test]
+    fn truncated_packets_are_errors() {
+        for message in client_messages() {
+            let bytes = message.to_bytes();
+            for length in 0..bytes.len() {
+                assert!(ClientMessage::from_bytes(&bytes[..length]).is_err());
+            }
+        }
+
+        for message in server_messages() {
+            let bytes = message.to_bytes();
+            for length in 0..bytes.len() {
+                assert!(ServerMessage::from_bytes(&bytes[..length]).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn overlong_varints_are_errors() {
+        assert_eq!(
+            PacketReader::new(&[0xff, 0xff, 0xff, 0xff, 0x0f]).varint(),
+            Ok(u32::MAX)
+        );
+        assert_eq!(
+            PacketReader::new(&[0xff, 0xff, 0xff, 0xff, 0x1f]).varint(),
+            Err(ProtocolError::InvalidVarint)
+        );
+        assert_eq!(
+            PacketReader::new(&[0x80; 6]).varint(),
+            Err(ProtocolError::InvalidVarint)
+        );
+    }
+
+    #[test]
+    fn lengths_over_the_limit_are_errors() {
+        let mut writer = PacketWriter::default();
+        writer.varint(MAX_STRING_LENGTH as u32 + 1);
+        writer
+            .bytes
+            .resize(writer.bytes.len() + MAX_STRING_LENGTH + 1, b'a');
+        assert_eq!(
+            PacketReader::new(&writer.bytes).string(),
+            Err(ProtocolError::InvalidLength(MAX_STRING_LENGTH + 1))
+        );
+
+        // A list claiming more entries than the packet has room for.
+        let mut writer = PacketWriter::default();
+        writer.u8(6);
+        writer.varint(1000);
+        assert_eq!(
+            ServerMessage::from_bytes(&writer.bytes),
+            Err(ProtocolError::InvalidLength(1000))
+        );
+    }
+
+    #[test]
+    fn long_strings_are_cut_when_written() {
+        let name = "é".repeat(MAX_STRING_LENGTH);
+        let hello = ClientMessage::Hello {
+            version: PROTOCOL_VERSION,
+            name,
+            view_radius: 4,
+        };
+
+        match ClientMessage::from_bytes(&hello.to_bytes()) {
+            Ok(ClientMessage::Hello { name, .. }) => {
+                assert_eq!(name.len(), MAX_STRING_LENGTH);
+                assert!(name.chars().all(|c| c == 'é'));
+            }
+            other => panic!("expected a hello, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn block_indices_outside_a_chunk_are_errors() {
+        let change = ServerMessage::BulkBlockChange {
+            chunk: IVec3::ZERO,
+            changes: vec![BlockChangeEntry {
+                index: CHUNK_VOLUME as u16,
+                block: 1,
+                state: 0,
+            }],
+        };
+        assert_eq!(
+            ServerMessage::from_bytes(&change.to_bytes()),
+            Err(ProtocolError::InvalidBlockIndex(CHUNK_VOLUME as u16))
+        );
+
+        let mut overfull = chunk_data();
+        overfull.blocks.push(0);
+        overfull.states.push(0);
+        assert_eq!(
+            ServerMessage::from_bytes(&ServerMessage::ChunkData(overfull).to_bytes()),
+            Err(ProtocolError::InvalidChunkData)
+        );
+    }
+
+    #[test]
+    fn trailing_bytes_are_errors() {
+        let mut bytes = ClientMessage::KeepAlive { id: 1 }.to_bytes();
+        bytes.extend_from_slice(&[0, 0]);
+        assert_eq!(
+            ClientMessage::from_bytes(&bytes),
+            Err(ProtocolError::TrailingBytes(2))
+        );
+        assert_eq!(
+            ClientMessage::from_bytes(&[200]),
+            Err(ProtocolError::UnknownPacket(200))
+        );
+    }
+
+    #[test]
+    fn frames_arrive_whole() {
+        let clock = LoopbackClock::default();
+        let (mut raw, other) = loopback_pair(&clock, 0);
+        let mut connection = Connection::from_transport(other);
+
+        let payload = ServerMessage::KeepAlive { id: 5 }.to_bytes();
+        let mut frame = (payload.len() as u32).to_le_bytes().to_vec();
+        frame.extend_from_slice(&payload);
+
+        raw.write_all(&frame[..3]).unwrap();
+        assert!(connection.receive::<ServerMessage>().unwrap().is_empty());
+        raw.write_all(&frame[3..]).unwrap();
+        assert_eq!(
+            connection.receive::<ServerMessage>().unwrap(),
+            vec![ServerMessage::KeepAlive { id: 5 }]
+        );
+
+        raw.write_all(&(MAX_FRAME_LENGTH as u32 + 1).to_le_bytes())
+            .unwrap();
+        let error = connection.receive::<ServerMessage>().unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -1,12 +1,48 @@
-use crate::{chunk::Block, key::Key, render::BlockModel};
+#[cfg(feature = "render")]
+use crate::render::{BlockAppearance, BlockModel};
+use crate::{
+    chunk::Block, inventory::ItemStack, item::Item, key::Key, modding::Contributions,
+    tick::BlockBehaviour,
+};
 use bevy::utils::HashMap;
 
 #[derive(Default)]
 pub struct Registry<'a> {
     pub blocks: HashMap<Key<'a>, Block>,
+    pub behaviours: HashMap<Key<'a>, BlockBehaviour>,
+    pub items: HashMap<Key<'a>, Item<'a>>,
+    /// What breaking a block gives back, for blocks that do not just drop their
+    /// own item.
+    pub drops: HashMap<Key<'a>, Vec<ItemStack>>,
+    /// Which mod each entry came from.
+    pub contributions: Contributions,
 }
 
+impl<'a> Registry<'a> {
+    /// The registered block written as `namespace:name`.
+    pub fn block_key(&self, name: &str) -> Option<Key<'a>> {
+        self.blocks
+            .keys()
+            .find(|key| key.to_string() == name)
+            .copied()
+    }
+
+    /// The registered item written as `namespace:name`.
+    pub fn item_key(&self, name: &str) -> Option<Key<'a>> {
+        self.items
+            .keys()
+            .find(|key| key.to_string() == name)
+            .copied()
+    }
+}
+
+#[cfg(feature = "render")]
 #[derive(Default)]
 pub struct ClientRegistry<'a> {
     pub block_models: HashMap<Key<'a>, BlockModel<'a>>,
+    /// How each block model is drawn with the resource packs in use, filled in
+    /// once they are loaded.
+    pub appearances: HashMap<Key<'a>, BlockAppearance>,
+    /// Which mod each block model came from.
+    pub contributions: Contributions,
 }
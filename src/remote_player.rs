@@ -0,0 +1,479 @@
+use std::collections::VecDeque;
+use std::f32::consts::TAU;
+
+use bevy::prelude::*;
+use bevy::render::camera::Camera3d;
+
+use crate::client::ServerConnection;
+use crate::game::AssetHandles;
+use crate::player::{EYE_HEIGHT, PLAYER_HEIGHT, PLAYER_WIDTH};
+
+/// Seconds remote players are shown behind the server, so there is usually a
+/// later snapshot to move towards even when one arrives late.
+pub const INTERPOLATION_DELAY: f64 = 0.1;
+/// Snapshots kept for each remote player.
+const MAX_SNAPSHOTS: usize = 32;
+/// How far above the top of a remote player their name floats.
+const NAME_TAG_HEIGHT: f32 = 0.3;
+
+/// Where a remote player was at a moment of the server's clock.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Snapshot {
+    /// Seconds of the server's clock.
+    pub time: f64,
+    pub position: Vec3,
+    pub pitch: f32,
+    pub yaw: f32,
+}
+
+impl Snapshot {
+    fn lerp(self, other: Snapshot, t: f32) -> Snapshot {
+        Snapshot {
+            time: self.time + (other.time - self.time) * t as f64,
+            position: self.position.lerp(other.position, t),
+            pitch: self.pitch + (other.pitch - self.pitch) * t,
+            yaw: lerp_angle(self.yaw, other.yaw, t),
+        }
+    }
+}
+
+/// Interpolates between two angles in radians the short way round.
+fn lerp_angle(from: f32, to: f32, t: f32) -> f32 {
+    let difference = (to - from).rem_euclid(TAU);
+    let difference = if difference > TAU / 2.0 {
+        difference - TAU
+    } else {
+        difference
+    };
+
+    from + difference * t
+}
+
+/// A jitter buffer of snapshots, in order of server time however they arrived.
+#[derive(Debug, Default, Clone)]
+pub struct SnapshotBuffer {
+    snapshots: VecDeque<Snapshot>,
+}
+
+impl SnapshotBuffer {
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+
+    pub fn push(&mut self, snapshot: Snapshot) {
+        let index = self
+            .snapshots
+            .iter()
+            .rposition(|other| other.time <= snapshot.time)
+            .map_or(0, |index| index + 1);
+
+        if index > 0 && self.snapshots[index - 1].time == snapshot.time {
+            self.snapshots[index - 1] = snapshot;
+            return;
+        }
+
+        self.snapshots.insert(index, snapshot);
+        if self.snapshots.len() > MAX_SNAPSHOTS {
+            self.snapshots.pop_front();
+        }
+    }
+
+    /// The player at `time`, between the snapshots either side of it. Before the
+    /// first snapshot and after the last, the nearest one is held. Snapshots no
+    /// longer needed for later times are dropped.
+    pub fn sample(&mut self, time: f64) -> Option<Snapshot> {
+        while self.snapshots.len() >= 2 && self.snapshots[1].time <= time {
+            self.snapshots.pop_front();
+        }
+
+        let first = *self.snapshots.front()?;
+        let next = match self.snapshots.get(1) {
+            Some(next) if time > first.time => *next,
+            _ => return Some(first),
+        };
+
+        let t = ((time - first.time) / (next.time - first.time)) as f32;
+        Some(first.lerp(next, t))
+    }
+}
+
+/// Estimates the server's clock from the times on the snapshots it sends,
+/// smoothing over how long each one took to arrive.
+#[derive(Debug, Default, Copy, Clone)]
+pub struct ServerClock {
+    offset: Option<f64>,
+}
+
+impl ServerClock {
+    pub fn observe(&mut self, server_time: f64, local_time: f64) {
+        const SMOOTHING: f64 = 0.1;
+
+        let offset = server_time - local_time;
+        self.offset = Some(match self.offset {
+            Some(current) => current + (offset - current) * SMOOTHING,
+            None => offset,
+        });
+    }
+
+    /// The server's clock at `local_time`, once a snapshot has been seen.
+    pub fn server_time(&self, local_time: f64) -> Option<f64> {
+        Some(local_time + self.offset?)
+    }
+}
+
+/// Another player in the game, drawn where the server last said they were.
+#[derive(Component, Debug)]
+pub struct RemotePlayer {
+    pub id: u32,
+    pub name: String,
+    pub snapshots: SnapshotBuffer,
+    /// The UI text showing their name.
+    pub name_tag: Entity,
+}
+
+/// The head of a [`RemotePlayer`], which pitches with their view.
+#[derive(Component)]
+pub struct RemotePlayerHead;
+
+/// Players joining, leaving and moving, as the server tells it.
+pub enum RemotePlayerEvent {
+    Joined { id: u32, name: String },
+    Left { id: u32 },
+    Moved { id: u32, snapshot: Snapshot },
+}
+
+/// Spawns players as they join and despawns them as they leave, or all of them
+/// when the connection is lost.
+pub fn update_remote_players(
+    mut commands: Commands,
+    mut events: EventReader<RemotePlayerEvent>,
+    connection: Option<Res<ServerConnection>>,
+    asset_handles: Res<AssetHandles>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
+    mut players: Query<(Entity, &mut RemotePlayer)>,
+) {
+    if connection.is_none() {
+        for (entity, player) in players.iter() {
+            commands.entity(entity).despawn_recursive();
+            commands.entity(player.name_tag).despawn_recursive();
+        }
+        return;
+    }
+
+    // Players joining in this batch are spawned at the end of it, so the
+    // snapshots sent with them are not lost.
+    let mut joined: Vec<RemotePlayer> = Vec::new();
+
+    for event in events.iter() {
+        match event {
+            RemotePlayerEvent::Joined { id, name } => {
+                let known = players.iter().any(|(_, player)| player.id == *id)
+                    || joined.iter().any(|player| player.id == *id);
+                if known {
+                    continue;
+                }
+
+                info!("{} joined", name);
+                joined.push(RemotePlayer {
+                    id: *id,
+                    name: name.clone(),
+                    snapshots: SnapshotBuffer::default(),
+                    name_tag: spawn_name_tag(&mut commands, &asset_handles, name),
+                });
+            }
+            RemotePlayerEvent::Left { id } => {
+                for (entity, player) in players.iter() {
+                    if player.id == *id {
+                        info!("{} left", player.name);
+                        commands.entity(entity).despawn_recursive();
+                        commands.entity(player.name_tag).despawn_recursive();
+                    }
+                }
+                joined.retain(|player| {
+                    if player.id == *id {
+                        commands.entity(player.name_tag).despawn_recursive();
+                    }
+                    player.id != *id
+                });
+            }
+            RemotePlayerEvent::Moved { id, snapshot } => {
+                let existing = players.iter_mut().map(|(_, player)| player);
+                for mut player in existing {
+                    if player.id == *id {
+                        player.snapshots.push(*snapshot);
+                    }
+                }
+                for player in joined.iter_mut() {
+                    if player.id == *id {
+                        player.snapshots.push(*snapshot);
+                    }
+                }
+            }
+        }
+    }
+
+    for player in joined {
+        spawn_remote_player(&mut commands, &mut meshes, &mut materials, player);
+    }
+}
+
+/// A box for the body with a box for the head on top, hidden until the first
+/// snapshot places it.
+fn spawn_remote_player(
+    commands: &mut Commands,
+    meshes: &mut Assets<Mesh>,
+    materials: &mut Assets<StandardMaterial>,
+    player: RemotePlayer,
+) {
+    const HEAD_SIZE: f32 = 0.5;
+
+    let body_height = PLAYER_HEIGHT - HEAD_SIZE;
+    let body = meshes.add(Mesh::from(shape::Box::new(
+        PLAYER_WIDTH,
+        body_height,
+        PLAYER_WIDTH * 0.5,
+    )));
+    let head = meshes.add(Mesh::from(shape::Cube { size: HEAD_SIZE }));
+    let body_material = materials.add(StandardMaterial {
+        base_color: Color::rgb(0.2, 0.4, 0.8),
+        unlit: true,
+        ..default()
+    });
+    let head_material = materials.add(StandardMaterial {
+        base_color: Color::rgb(0.9, 0.75, 0.6),
+        unlit: true,
+        ..default()
+    });
+
+    // The entity sits at the player's eyes, like the local camera does.
+    commands
+        .spawn_bundle(TransformBundle::default())
+        .insert(player)
+        .with_children(|parent| {
+            parent.spawn_bundle(PbrBundle {
+                mesh: body,
+                material: body_material,
+                transform: Transform::from_xyz(0.0, -EYE_HEIGHT + body_height / 2.0, 0.0),
+                visibility: Visibility { is_visible: false },
+                ..default()
+            });
+            parent
+                .spawn_bundle(PbrBundle {
+                    mesh: head,
+                    material: head_material,
+                    transform: Transform::from_xyz(
+                        0.0,
+                        PLAYER_HEIGHT - EYE_HEIGHT - HEAD_SIZE / 2.0,
+                        0.0,
+                    ),
+                    visibility: Visibility { is_visible: false },
+                    ..default()
+                })
+                .insert(RemotePlayerHead);
+        });
+}
+
+fn spawn_name_tag(commands: &mut Commands, asset_handles: &AssetHandles, name: &str) -> Entity {
+    commands
+        .spawn_bundle(TextBundle {
+            style: Style {
+                position_type: PositionType::Absolute,
+                ..default()
+            },
+            text: Text::with_section(
+                name,
+                TextStyle {
+                    font: asset_handles.font.clone(),
+                    font_size: 18.0,
+                    color: Color::WHITE,
+                },
+                default(),
+            ),
+            visibility: Visibility { is_visible: false },
+            ..default()
+        })
+        .id()
+}
+
+/// Moves remote players to where they were [`INTERPOLATION_DELAY`] ago on the
+/// server, between the snapshots either side of that moment.
+pub fn interpolate_remote_players(
+    time: Res<Time>,
+    connection: Option<Res<ServerConnection>>,
+    mut players: Query<(&mut RemotePlayer, &mut Transform, &Children)>,
+    mut parts: Query<
+        (&mut Transform, &mut Visibility, Option<&RemotePlayerHead>),
+        Without<RemotePlayer>,
+    >,
+) {
+    let render_time = match connection.and_then(|connection| {
+        connection
+            .server_clock
+            .server_time(time.seconds_since_startup())
+    }) {
+        Some(server_time) => server_time - INTERPOLATION_DELAY,
+        None => return,
+    };
+
+    for (mut player, mut transform, children) in players.iter_mut() {
+        let snapshot = match player.snapshots.sample(render_time) {
+            Some(snapshot) => snapshot,
+            None => continue,
+        };
+
+        transform.translation = snapshot.position;
+        transform.rotation = Quat::from_axis_angle(Vec3::Y, snapshot.yaw);
+
+        for child in children.iter() {
+            if let Ok((mut part_transform, mut visibility, head)) = parts.get_mut(*child) {
+                visibility.is_visible = true;
+                if head.is_some() {
+                    part_transform.rotation = Quat::from_axis_angle(Vec3::X, snapshot.pitch);
+                }
+            }
+        }
+    }
+}
+
+/// Keeps each name tag on screen just above its player, hiding it when the
+/// player is behind the camera.
+pub fn position_name_tags(
+    windows: Res<Windows>,
+    images: Res<Assets<Image>>,
+    cameras: Query<(&Camera, &GlobalTransform), With<Camera3d>>,
+    players: Query<(&RemotePlayer, &GlobalTransform)>,
+    mut name_tags: Query<(&mut Style, &mut Visibility, &Node), Without<RemotePlayer>>,
+) {
+    let (camera, camera_transform) = match cameras.iter().next() {
+        Some(camera) => camera,
+        None => return,
+    };
+
+    for (player, transform) in players.iter() {
+        let (mut style, mut visibility, node) = match name_tags.get_mut(player.name_tag) {
+            Ok(name_tag) => name_tag,
+            Err(_) => continue,
+        };
+
+        let above_head =
+            transform.translation + Vec3::Y * (PLAYER_HEIGHT - EYE_HEIGHT + NAME_TAG_HEIGHT);
+        let screen_position = camera
+            .world_to_screen(&windows, &images, camera_transform, above_head)
+            .filter(|_| !player.snapshots.is_empty());
+
+        visibility.is_visible = screen_position.is_some();
+        if let Some(screen_position) = screen_position {
+            style.position = Rect {
+                left: Val::Px(screen_position.x - node.size.x / 2.0),
+                bottom: Val::Px(screen_position.y),
+                ..default()
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI;
+
+    use super::*;
+
+    fn snapshot(time: f64, x: f32) -> Snapshot {
+        Snapshot {
+            time,
+            position: Vec3::new(x, 0.0, 0.0),
+            pitch: 0.0,
+            yaw: 0.0,
+        }
+    }
+
+    fn times(buffer: &SnapshotBuffer) -> Vec<f64> {
+        buffer
+            .snapshots
+            .iter()
+            .map(|snapshot| snapshot.time)
+            .collect()
+    }
+
+    #[test]
+    fn late_snapshots_are_put_in_order() {
+        let mut buffer = SnapshotBuffer::default();
+        for time in [3.0, 1.0, 4.0, 2.0] {
+            buffer.push(snapshot(time, 0.0));
+        }
+
+        assert_eq!(times(&buffer), [1.0, 2.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn a_snapshot_replaces_one_with_the_same_time() {
+        let mut buffer = SnapshotBuffer::default();
+        buffer.push(snapshot(1.0, 0.0));
+        buffer.push(snapshot(2.0, 0.0));
+        buffer.push(snapshot(1.0, 5.0));
+
+        assert_eq!(times(&buffer), [1.0, 2.0]);
+        assert_eq!(buffer.snapshots[0].position.x, 5.0);
+    }
+
+    #[test]
+    fn only_the_latest_snapshots_are_kept() {
+        let mut buffer = SnapshotBuffer::default();
+        for time in 0..MAX_SNAPSHOTS + 8 {
+            buffer.push(snapshot(time as f64, 0.0));
+        }
+
+        assert_eq!(buffer.snapshots.len(), MAX_SNAPSHOTS);
+        assert_eq!(buffer.snapshots[0].time, 8.0);
+    }
+
+    #[test]
+    fn times_outside_the_buffer_hold_the_nearest_snapshot() {
+        let mut buffer = SnapshotBuffer::default();
+        assert_eq!(buffer.sample(1.0), None);
+
+        buffer.push(snapshot(1.0, 2.0));
+        buffer.push(snapshot(2.0, 6.0));
+
+        assert_eq!(buffer.sample(0.5), Some(snapshot(1.0, 2.0)));
+        assert_eq!(buffer.sample(3.0), Some(snapshot(2.0, 6.0)));
+        // The earlier snapshot is no longer needed.
+        assert_eq!(times(&buffer), [2.0]);
+    }
+
+    #[test]
+    fn sampling_between_snapshots_interpolates() {
+        let mut buffer = SnapshotBuffer::default();
+        buffer.push(snapshot(1.0, 2.0));
+        buffer.push(snapshot(2.0, 6.0));
+
+        assert_eq!(buffer.sample(1.5), Some(snapshot(1.5, 4.0)));
+        assert_eq!(times(&buffer), [1.0, 2.0]);
+    }
+
+    #[test]
+    fn yaw_turns_the_short_way_across_half_a_turn() {
+        let halfway = lerp_angle(PI - 0.1, -PI + 0.1, 0.5);
+        assert!((halfway - PI).abs() < 1e-5);
+
+        let halfway = lerp_angle(-PI + 0.1, PI - 0.1, 0.5);
+        assert!((halfway + PI).abs() < 1e-5);
+
+        assert!((lerp_angle(0.2, 0.6, 0.5) - 0.4).abs() < 1e-6);
+    }
+
+    #[test]
+    fn the_server_clock_smooths_over_arrival_times() {
+        let mut clock = ServerClock::default();
+        assert_eq!(clock.server_time(5.0), None);
+
+        clock.observe(100.0, 10.0);
+        assert_eq!(clock.server_time(11.0), Some(101.0));
+
+        // A snapshot that arrived a second late only moves the clock a little.
+        clock.observe(101.0, 12.0);
+        let server_time = clock.server_time(12.0).unwrap();
+        assert!((server_time - 101.9).abs() < 1e-9);
+    }
+}
@@ -1,13 +1,27 @@
 use bevy::prelude::*;
-use bevy::render::mesh::{Indices, PrimitiveTopology};
+use bevy::render::mesh::{Indices, PrimitiveTopology, VertexAttributeValues};
+use serde::Deserialize;
 
-use crate::world::World;
+use crate::chunk::{chunk_index, Block, Chunk, CHUNK_SIZE};
+use crate::error::MISSING_TEXTURE;
+use crate::key::Key;
+use crate::light::MAX_LIGHT;
+use crate::material::{
+    ATTRIBUTE_AMBIENT_OCCLUSION, ATTRIBUTE_LIGHT_COLOR, ATTRIBUTE_TEXTURE_LAYER,
+};
+use crate::registry::{ClientRegistry, Registry};
+use crate::utils::{block_to_chunk, block_to_local};
 
 #[derive(Debug)]
 pub struct Vertex {
     pub position: [f32; 3],
     pub normal: [f32; 3],
     pub texcoord: [f32; 2],
+    pub layer: u32,
+    /// How much light reaches this corner, from `0.0` when fully enclosed to `1.0`.
+    pub ambient_occlusion: f32,
+    /// Colour of the light reaching the face this vertex belongs to.
+    pub color: [f32; 3],
 }
 
 #[derive(Debug)]
@@ -16,6 +30,22 @@ pub struct MeshFragment {
     pub indices: Vec<u32>,
 }
 
+/// Where a block face samples its texture from. Atlas based materials use the
+/// `uv` rectangle, texture array materials use the full `0..1` range and `layer`.
+#[derive(Debug, Copy, Clone)]
+pub struct BlockTexture {
+    pub uv: Rect<f32>,
+    pub layer: u32,
+}
+
+/// How a block is drawn with the resource packs in use: its registered model,
+/// changed by any definition the packs give it.
+#[derive(Debug, Copy, Clone)]
+pub struct BlockAppearance {
+    pub texture: BlockTexture,
+    pub layer: RenderLayer,
+}
+
 #[derive(Copy, Clone)]
 pub enum BlockFace {
     Front,
@@ -26,20 +56,205 @@ pub enum BlockFace {
     Right,
 }
 
+impl BlockFace {
+    pub fn normal(self) -> IVec3 {
+        match self {
+            BlockFace::Front => IVec3::Z,
+            BlockFace::Back => -IVec3::Z,
+            BlockFace::Top => IVec3::Y,
+            BlockFace::Bottom => -IVec3::Y,
+            BlockFace::Left => -IVec3::X,
+            BlockFace::Right => IVec3::X,
+        }
+    }
+}
+
+/// A chunk's blocks plus a one block border taken from the surrounding chunks, so
+/// meshing can look across chunk borders. Positions are chunk local and range from
+/// `-1` to `CHUNK_SIZE` on every axis.
+pub struct ChunkNeighbourhood<'a> {
+    blocks: Vec<Option<Key<'a>>>,
+    states: Vec<u8>,
+    sky_light: Vec<u8>,
+    block_light: Vec<[u8; 3]>,
+}
+
+const NEIGHBOURHOOD_SIZE: usize = CHUNK_SIZE + 2;
+
+impl<'a> ChunkNeighbourhood<'a> {
+    pub fn new<'c>(
+        chunk_position: IVec3,
+        mut get_chunk: impl FnMut(IVec3) -> Option<&'c Chunk<'a>>,
+    ) -> Self
+    where
+        'a: 'c,
+    {
+        let mut chunks = [None; 27];
+        for (i, chunk) in chunks.iter_mut().enumerate() {
+            let offset = IVec3::new(i as i32 / 9, i as i32 / 3 % 3, i as i32 % 3) - IVec3::ONE;
+            *chunk = get_chunk(chunk_position + offset);
+        }
+
+        // Cells outside the loaded world are left open to the sky.
+        let mut blocks = vec![None; NEIGHBOURHOOD_SIZE.pow(3)];
+        let mut states = vec![0; NEIGHBOURHOOD_SIZE.pow(3)];
+        let mut sky_light = vec![MAX_LIGHT; NEIGHBOURHOOD_SIZE.pow(3)];
+        let mut block_light = vec![[0; 3]; NEIGHBOURHOOD_SIZE.pow(3)];
+        for i in 0..blocks.len() {
+            let position = neighbourhood_position(i);
+            let offset = block_to_chunk(position) + IVec3::ONE;
+
+            if let Some(chunk) = chunks[(offset.x * 9 + offset.y * 3 + offset.z) as usize] {
+                let index = chunk_index(block_to_local(position));
+                blocks[i] = chunk.blocks[index];
+                states[i] = chunk.states[index];
+                sky_light[i] = chunk.sky_light[index];
+                block_light[i] = chunk.block_light[index];
+            }
+        }
+
+        ChunkNeighbourhood {
+            blocks,
+            states,
+            sky_light,
+            block_light,
+        }
+    }
+
+    pub fn block(&self, position: IVec3) -> Option<Key<'a>> {
+        self.blocks[neighbourhood_index(position)]
+    }
+
+    pub fn state(&self, position: IVec3) -> u8 {
+        self.states[neighbourhood_index(position)]
+    }
+
+    pub fn sky_light(&self, position: IVec3) -> u8 {
+        self.sky_light[neighbourhood_index(position)]
+    }
+
+    pub fn block_light(&self, position: IVec3) -> [u8; 3] {
+        self.block_light[neighbourhood_index(position)]
+    }
+}
+
+fn neighbourhood_index(position: IVec3) -> usize {
+    let padded = position + IVec3::ONE;
+
+    padded.x as usize * NEIGHBOURHOOD_SIZE * NEIGHBOURHOOD_SIZE
+        + padded.y as usize * NEIGHBOURHOOD_SIZE
+        + padded.z as usize
+}
+
+fn neighbourhood_position(index: usize) -> IVec3 {
+    IVec3::new(
+        (index / (NEIGHBOURHOOD_SIZE * NEIGHBOURHOOD_SIZE)) as i32,
+        (index / NEIGHBOURHOOD_SIZE % NEIGHBOURHOOD_SIZE) as i32,
+        (index % NEIGHBOURHOOD_SIZE) as i32,
+    ) - IVec3::ONE
+}
+
+/// Everything a block model can look at while generating its mesh.
+pub struct MeshContext<'a> {
+    pub registry: &'a Registry<'static>,
+    pub client_registry: &'a ClientRegistry<'static>,
+    pub neighbourhood: &'a ChunkNeighbourhood<'static>,
+}
+
+impl<'a> MeshContext<'a> {
+    pub fn block(&self, position: IVec3) -> Option<&'a Block> {
+        self.neighbourhood
+            .block(position)
+            .and_then(|block| self.registry.blocks.get(&block))
+    }
+
+    pub fn is_opaque(&self, position: IVec3) -> bool {
+        self.block(position).is_some_and(|block| block.is_opaque())
+    }
+
+    /// Whether the block at `position` has its `face` covered by its neighbour.
+    pub fn is_face_hidden(&self, position: IVec3, face: BlockFace) -> bool {
+        let layer = |position| {
+            let block = self.neighbourhood.block(position)?;
+            let appearance = self.client_registry.appearances.get(&block)?;
+            Some((block, appearance.layer))
+        };
+
+        match (layer(position), layer(position + face.normal())) {
+            (Some(block), neighbour) => is_face_hidden(block, neighbour),
+            (None, _) => false,
+        }
+    }
+}
+
+/// Which pass a block is drawn in, and how its faces are culled against others.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Deserialize)]
+pub enum RenderLayer {
+    /// Fully covers its cell and hides every face next to it.
+    Opaque,
+    /// Either fully opaque or fully clear per pixel, like leaves.
+    Cutout,
+    /// Partially see through, like glass or water, drawn back to front.
+    Translucent,
+}
+
+impl RenderLayer {
+    pub const ALL: [RenderLayer; 3] = [
+        RenderLayer::Opaque,
+        RenderLayer::Cutout,
+        RenderLayer::Translucent,
+    ];
+
+    pub fn alpha_mode(self) -> AlphaMode {
+        match self {
+            RenderLayer::Opaque => AlphaMode::Opaque,
+            RenderLayer::Cutout => AlphaMode::Mask(0.5),
+            RenderLayer::Translucent => AlphaMode::Blend,
+        }
+    }
+}
+
+/// Opaque neighbours hide any face. See through neighbours only hide faces of the
+/// same block in the same layer, so glass next to glass merges into one pane but
+/// stone is still drawn behind glass.
+pub fn is_face_hidden(block: (Key, RenderLayer), neighbour: Option<(Key, RenderLayer)>) -> bool {
+    match neighbour {
+        Some((_, RenderLayer::Opaque)) => true,
+        Some(neighbour) => neighbour == block && block.1 != RenderLayer::Opaque,
+        None => false,
+    }
+}
+
 pub struct BlockModel<'a> {
     pub texture: &'a str,
-    pub generate_mesh: fn(world: &World, position: IVec3, texture: Rect<f32>) -> MeshFragment,
+    pub layer: RenderLayer,
+    pub generate_mesh:
+        fn(context: &MeshContext, position: IVec3, texture: BlockTexture) -> MeshFragment,
 }
 
+/// How a block with no block model of its own is drawn: a cube with the
+/// placeholder texture.
+pub const UNKNOWN_BLOCK_MODEL: BlockModel<'static> = BlockModel {
+    texture: MISSING_TEXTURE,
+    layer: RenderLayer::Opaque,
+    generate_mesh: generate_cube,
+};
+
 pub fn build_mesh(mesh_fragment: MeshFragment) -> Mesh {
     let mut positions = Vec::new();
     let mut normals = Vec::new();
     let mut texcoords = Vec::new();
+    let mut layers = Vec::new();
+    let mut ambient_occlusion = Vec::new();
+    let mut colors = Vec::new();
 
     for vertex in mesh_fragment.vertices {
         positions.push(vertex.position);
         normals.push(vertex.normal);
         texcoords.push(vertex.texcoord);
+        layers.push(vertex.layer);
+        ambient_occlusion.push(vertex.ambient_occlusion);
+        colors.push(vertex.color);
     }
 
     let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
@@ -47,6 +262,9 @@ pub fn build_mesh(mesh_fragment: MeshFragment) -> Mesh {
     mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
     mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
     mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, texcoords);
+    mesh.insert_attribute(ATTRIBUTE_TEXTURE_LAYER, layers);
+    mesh.insert_attribute(ATTRIBUTE_AMBIENT_OCCLUSION, ambient_occlusion);
+    mesh.insert_attribute(ATTRIBUTE_LIGHT_COLOR, colors);
 
     mesh.set_indices(Some(Indices::U32(mesh_fragment.indices)));
 
@@ -75,7 +293,11 @@ pub fn aggregate_mesh_fragments(mesh_fragments: Vec<MeshFragment>) -> MeshFragme
     MeshFragment { vertices, indices }
 }
 
-pub fn generate_block(position: IVec3, textures: [Option<Rect<f32>>; 6]) -> MeshFragment {
+pub fn generate_block(
+    context: &MeshContext,
+    position: IVec3,
+    textures: [Option<BlockTexture>; 6],
+) -> MeshFragment {
     let mut block_faces: Vec<MeshFragment> = vec![];
 
     for (i, block_face) in [
@@ -90,14 +312,97 @@ pub fn generate_block(position: IVec3, textures: [Option<Rect<f32>>; 6]) -> Mesh
     .enumerate()
     {
         if let Some(texture) = textures[i] {
-            block_faces.push(generate_block_face(*block_face, position, texture));
+            if context.is_face_hidden(position, *block_face) {
+                continue;
+            }
+
+            let mut fragment = generate_block_face(*block_face, position, texture);
+            apply_ambient_occlusion(context, &mut fragment, *block_face, position);
+            apply_light(context, &mut fragment, *block_face, position);
+            block_faces.push(fragment);
         }
     }
 
     aggregate_mesh_fragments(block_faces)
 }
 
-pub fn generate_block_face(face: BlockFace, position: IVec3, texture: Rect<f32>) -> MeshFragment {
+/// Draws a full block with the same texture on every face.
+pub fn generate_cube(
+    context: &MeshContext,
+    position: IVec3,
+    texture: BlockTexture,
+) -> MeshFragment {
+    generate_block(context, position, [Some(texture); 6])
+}
+
+/// Draws a fluid as a block whose top corners sit at the average surface height
+/// of the cells of the same fluid around them, so the surface slopes down the
+/// way it flows.
+pub fn generate_fluid(
+    context: &MeshContext,
+    position: IVec3,
+    texture: BlockTexture,
+) -> MeshFragment {
+    let mut fragment = generate_block(context, position, [Some(texture); 6]);
+    let top = position.y as f32 + 1.0;
+
+    for vertex in &mut fragment.vertices {
+        if vertex.position[1] == top {
+            let corner = IVec3::new(
+                vertex.position[0] as i32,
+                position.y,
+                vertex.position[2] as i32,
+            );
+            vertex.position[1] = position.y as f32 + fluid_corner_height(context, position, corner);
+        }
+    }
+
+    fragment
+}
+
+/// Surface height at the top corner of `position` nearest to `corner`, averaged
+/// over the four cells sharing that corner. Fluid falling into any of them fills
+/// the corner completely.
+fn fluid_corner_height(context: &MeshContext, position: IVec3, corner: IVec3) -> f32 {
+    let key = context.neighbourhood.block(position);
+    let fluid = match context.block(position).and_then(|block| block.fluid) {
+        Some(fluid) => fluid,
+        None => return 1.0,
+    };
+
+    let mut total = 0.0;
+    let mut count = 0;
+
+    for offset in [
+        IVec3::new(-1, 0, -1),
+        IVec3::new(0, 0, -1),
+        IVec3::new(-1, 0, 0),
+        IVec3::new(0, 0, 0),
+    ] {
+        let cell = corner + offset;
+        if context.neighbourhood.block(cell) != key {
+            continue;
+        }
+
+        if context.neighbourhood.block(cell + IVec3::Y) == key {
+            return 1.0;
+        }
+
+        total += fluid.height(context.neighbourhood.state(cell));
+        count += 1;
+    }
+
+    total / count.max(1) as f32
+}
+
+pub fn generate_block_face(
+    face: BlockFace,
+    position: IVec3,
+    texture: BlockTexture,
+) -> MeshFragment {
+    let uv = texture.uv;
+    let layer = texture.layer;
+
     let left = position.x as f32;
     let right = left + 1.0;
     let bottom = position.y as f32;
@@ -111,135 +416,481 @@ pub fn generate_block_face(face: BlockFace, position: IVec3, texture: Rect<f32>)
                 Vertex {
                     position: [left, top, front],
                     normal: [0.0, 0.0, 1.0],
-                    texcoord: [texture.left, texture.top],
+                    texcoord: [uv.left, uv.top],
+                    layer,
+                    ambient_occlusion: 1.0,
+                    color: [1.0, 1.0, 1.0],
                 },
                 Vertex {
                     position: [left, bottom, front],
                     normal: [0.0, 0.0, 1.0],
-                    texcoord: [texture.left, texture.bottom],
+                    texcoord: [uv.left, uv.bottom],
+                    layer,
+                    ambient_occlusion: 1.0,
+                    color: [1.0, 1.0, 1.0],
                 },
                 Vertex {
                     position: [right, bottom, front],
                     normal: [0.0, 0.0, 1.0],
-                    texcoord: [texture.right, texture.bottom],
+                    texcoord: [uv.right, uv.bottom],
+                    layer,
+                    ambient_occlusion: 1.0,
+                    color: [1.0, 1.0, 1.0],
                 },
                 Vertex {
                     position: [right, top, front],
                     normal: [0.0, 0.0, 1.0],
-                    texcoord: [texture.right, texture.top],
+                    texcoord: [uv.right, uv.top],
+                    layer,
+                    ambient_occlusion: 1.0,
+                    color: [1.0, 1.0, 1.0],
                 },
             ],
             BlockFace::Back => vec![
                 Vertex {
                     position: [right, bottom, back],
                     normal: [0.0, 0.0, -1.0],
-                    texcoord: [texture.left, texture.bottom],
+                    texcoord: [uv.left, uv.bottom],
+                    layer,
+                    ambient_occlusion: 1.0,
+                    color: [1.0, 1.0, 1.0],
                 },
                 Vertex {
                     position: [left, bottom, back],
                     normal: [0.0, 0.0, -1.0],
-                    texcoord: [texture.right, texture.bottom],
+                    texcoord: [uv.right, uv.bottom],
+                    layer,
+                    ambient_occlusion: 1.0,
+                    color: [1.0, 1.0, 1.0],
                 },
                 Vertex {
                     position: [left, top, back],
                     normal: [0.0, 0.0, -1.0],
-                    texcoord: [texture.right, texture.top],
+                    texcoord: [uv.right, uv.top],
+                    layer,
+                    ambient_occlusion: 1.0,
+                    color: [1.0, 1.0, 1.0],
                 },
                 Vertex {
                     position: [right, top, back],
                     normal: [0.0, 0.0, -1.0],
-                    texcoord: [texture.left, texture.top],
+                    texcoord: [uv.left, uv.top],
+                    layer,
+                    ambient_occlusion: 1.0,
+                    color: [1.0, 1.0, 1.0],
                 },
             ],
             BlockFace::Left => vec![
                 Vertex {
                     position: [left, top, back],
                     normal: [-1.0, 0.0, 0.0],
-                    texcoord: [texture.left, texture.top],
+                    texcoord: [uv.left, uv.top],
+                    layer,
+                    ambient_occlusion: 1.0,
+                    color: [1.0, 1.0, 1.0],
                 },
                 Vertex {
                     position: [left, bottom, back],
                     normal: [-1.0, 0.0, 0.0],
-                    texcoord: [texture.left, texture.bottom],
+                    texcoord: [uv.left, uv.bottom],
+                    layer,
+                    ambient_occlusion: 1.0,
+                    color: [1.0, 1.0, 1.0],
                 },
                 Vertex {
                     position: [left, bottom, front],
                     normal: [-1.0, 0.0, 0.0],
-                    texcoord: [texture.right, texture.bottom],
+                    texcoord: [uv.right, uv.bottom],
+                    layer,
+                    ambient_occlusion: 1.0,
+                    color: [1.0, 1.0, 1.0],
                 },
                 Vertex {
                     position: [left, top, front],
                     normal: [-1.0, 0.0, 0.0],
-                    texcoord: [texture.right, texture.top],
+                    texcoord: [uv.right, uv.top],
+                    layer,
+                    ambient_occlusion: 1.0,
+                    color: [1.0, 1.0, 1.0],
                 },
             ],
             BlockFace::Right => vec![
                 Vertex {
                     position: [right, bottom, front],
                     normal: [1.0, 0.0, 0.0],
-                    texcoord: [texture.left, texture.bottom],
+                    texcoord: [uv.left, uv.bottom],
+                    layer,
+                    ambient_occlusion: 1.0,
+                    color: [1.0, 1.0, 1.0],
                 },
                 Vertex {
                     position: [right, bottom, back],
                     normal: [1.0, 0.0, 0.0],
-                    texcoord: [texture.right, texture.bottom],
+                    texcoord: [uv.right, uv.bottom],
+                    layer,
+                    ambient_occlusion: 1.0,
+                    color: [1.0, 1.0, 1.0],
                 },
                 Vertex {
                     position: [right, top, back],
                     normal: [1.0, 0.0, 0.0],
-                    texcoord: [texture.right, texture.top],
+                    texcoord: [uv.right, uv.top],
+                    layer,
+                    ambient_occlusion: 1.0,
+                    color: [1.0, 1.0, 1.0],
                 },
                 Vertex {
                     position: [right, top, front],
                     normal: [1.0, 0.0, 0.0],
-                    texcoord: [texture.left, texture.top],
+                    texcoord: [uv.left, uv.top],
+                    layer,
+                    ambient_occlusion: 1.0,
+                    color: [1.0, 1.0, 1.0],
                 },
             ],
             BlockFace::Top => vec![
                 Vertex {
                     position: [right, top, back],
                     normal: [0.0, 1.0, 0.0],
-                    texcoord: [texture.right, texture.top],
+                    texcoord: [uv.right, uv.top],
+                    layer,
+                    ambient_occlusion: 1.0,
+                    color: [1.0, 1.0, 1.0],
                 },
                 Vertex {
                     position: [left, top, back],
                     normal: [0.0, 1.0, 0.0],
-                    texcoord: [texture.left, texture.top],
+                    texcoord: [uv.left, uv.top],
+                    layer,
+                    ambient_occlusion: 1.0,
+                    color: [1.0, 1.0, 1.0],
                 },
                 Vertex {
                     position: [left, top, front],
                     normal: [0.0, 1.0, 0.0],
-                    texcoord: [texture.left, texture.bottom],
+                    texcoord: [uv.left, uv.bottom],
+                    layer,
+                    ambient_occlusion: 1.0,
+                    color: [1.0, 1.0, 1.0],
                 },
                 Vertex {
                     position: [right, top, front],
                     normal: [0.0, 1.0, 0.0],
-                    texcoord: [texture.right, texture.bottom],
+                    texcoord: [uv.right, uv.bottom],
+                    layer,
+                    ambient_occlusion: 1.0,
+                    color: [1.0, 1.0, 1.0],
                 },
             ],
             BlockFace::Bottom => vec![
                 Vertex {
                     position: [left, bottom, front],
                     normal: [0.0, -1.0, 0.0],
-                    texcoord: [texture.right, texture.top],
+                    texcoord: [uv.right, uv.top],
+                    layer,
+                    ambient_occlusion: 1.0,
+                    color: [1.0, 1.0, 1.0],
                 },
                 Vertex {
                     position: [left, bottom, back],
                     normal: [0.0, -1.0, 0.0],
-                    texcoord: [texture.right, texture.bottom],
+                    texcoord: [uv.right, uv.bottom],
+                    layer,
+                    ambient_occlusion: 1.0,
+                    color: [1.0, 1.0, 1.0],
                 },
                 Vertex {
                     position: [right, bottom, back],
                     normal: [0.0, -1.0, 0.0],
-                    texcoord: [texture.left, texture.bottom],
+                    texcoord: [uv.left, uv.bottom],
+                    layer,
+                    ambient_occlusion: 1.0,
+                    color: [1.0, 1.0, 1.0],
                 },
                 Vertex {
                     position: [right, bottom, front],
                     normal: [0.0, -1.0, 0.0],
-                    texcoord: [texture.left, texture.top],
+                    texcoord: [uv.left, uv.top],
+                    layer,
+                    ambient_occlusion: 1.0,
+                    color: [1.0, 1.0, 1.0],
                 },
             ],
         },
         indices: vec![0, 1, 2, 2, 3, 0],
     }
 }
+
+/// Darkens each corner of a block face by the opaque blocks touching it, using the
+/// two side cells and the corner cell in front of the face. If the corners are
+/// shaded unevenly the quad is split along its other diagonal, so the darkening
+/// stays symmetrical instead of smearing along one triangle.
+pub fn apply_ambient_occlusion(
+    context: &MeshContext,
+    fragment: &mut MeshFragment,
+    face: BlockFace,
+    position: IVec3,
+) {
+    let normal = face.normal();
+    let front = position + normal;
+    let [axis_a, axis_b] = match face {
+        BlockFace::Left | BlockFace::Right => [IVec3::Y, IVec3::Z],
+        BlockFace::Top | BlockFace::Bottom => [IVec3::X, IVec3::Z],
+        BlockFace::Front | BlockFace::Back => [IVec3::X, IVec3::Y],
+    };
+
+    let mut levels = Vec::with_capacity(fragment.vertices.len());
+
+    for vertex in &mut fragment.vertices {
+        let corner = IVec3::from(vertex.position.map(|component| component.round() as i32));
+        let direction = (corner - position) * 2 - IVec3::ONE;
+
+        let side_a = axis_a * direction.dot(axis_a);
+        let side_b = axis_b * direction.dot(axis_b);
+
+        let level = ambient_occlusion_level(
+            context.is_opaque(front + side_a),
+            context.is_opaque(front + side_b),
+            context.is_opaque(front + side_a + side_b),
+        );
+
+        vertex.ambient_occlusion = level as f32 / 3.0;
+        levels.push(level);
+    }
+
+    if levels.len() == 4 && levels[1] + levels[3] > levels[0] + levels[2] {
+        fragment.indices = vec![1, 2, 3, 3, 0, 1];
+    }
+}
+
+/// Classic voxel ambient occlusion, from `0` (fully occluded) to `3` (unoccluded).
+/// Two occluding sides hide the corner completely, whatever the corner cell holds.
+pub fn ambient_occlusion_level(side_a: bool, side_b: bool, corner: bool) -> u8 {
+    if side_a && side_b {
+        0
+    } else {
+        3 - side_a as u8 - side_b as u8 - corner as u8
+    }
+}
+
+/// Colours a block face with the light in the cell it faces. Blocks that emit light
+/// are drawn at least as bright as their own light, whatever surrounds them.
+pub fn apply_light(
+    context: &MeshContext,
+    fragment: &mut MeshFragment,
+    face: BlockFace,
+    position: IVec3,
+) {
+    let front = position + face.normal();
+    let mut block_light = context.neighbourhood.block_light(front);
+
+    if let Some(block) = context.block(position) {
+        for (light, emitted) in block_light.iter_mut().zip(block.emitted_light()) {
+            *light = (*light).max(emitted);
+        }
+    }
+
+    let color = light_color(context.neighbourhood.sky_light(front), block_light);
+
+    for vertex in &mut fragment.vertices {
+        vertex.color = color;
+    }
+}
+
+/// The colour a cell is lit with. Sky light is white, and each component takes
+/// whichever of the sky and block light is brighter.
+pub fn light_color(sky_light: u8, block_light: [u8; 3]) -> [f32; 3] {
+    block_light.map(|level| light_brightness(sky_light.max(level)))
+}
+
+/// Each level of light is a fixed fraction dimmer than the one above it.
+pub fn light_brightness(level: u8) -> f32 {
+    0.8f32.powi((MAX_LIGHT - level.min(MAX_LIGHT)) as i32)
+}
+
+/// Reorders a mesh's triangles so the ones furthest from `eye` are drawn first,
+/// which translucent geometry needs to blend correctly. `eye` is in the mesh's space.
+pub fn sort_back_to_front(mesh: &mut Mesh, eye: Vec3) {
+    let positions = match mesh.attribute(Mesh::ATTRIBUTE_POSITION) {
+        Some(VertexAttributeValues::Float32x3(positions)) => positions.clone(),
+        _ => return,
+    };
+
+    if let Some(Indices::U32(indices)) = mesh.indices_mut() {
+        let mut triangles: Vec<[u32; 3]> = indices
+            .chunks_exact(3)
+            .map(|triangle| [triangle[0], triangle[1], triangle[2]])
+            .collect();
+
+        let distance = |triangle: &[u32; 3]| {
+            let centre = triangle.iter().fold(Vec3::ZERO, |sum, index| {
+                sum + Vec3::from(positions[*index as usize])
+            }) / 3.0;
+            centre.distance_squared(eye)
+        };
+
+        triangles.sort_by(|a, b| distance(b).total_cmp(&distance(a)));
+
+        *indices = triangles.into_iter().flatten().collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(name: &'static str) -> Key<'static> {
+        Key {
+            namespace: "defaria",
+            name,
+        }
+    }
+
+    fn stone() -> (Key<'static>, RenderLayer) {
+        (key("stone"), RenderLayer::Opaque)
+    }
+
+    fn glass() -> (Key<'static>, RenderLayer) {
+        (key("glass"), RenderLayer::Translucent)
+    }
+
+    #[test]
+    fn glass_next_to_glass_hides_the_shared_face() {
+        assert!(is_face_hidden(glass(), Some(glass())));
+    }
+
+    #[test]
+    fn stone_is_drawn_behind_glass() {
+        assert!(!is_face_hidden(stone(), Some(glass())));
+    }
+
+    #[test]
+    fn stone_hides_the_face_of_glass_against_it() {
+        assert!(is_face_hidden(glass(), Some(stone())));
+        assert!(is_face_hidden(stone(), Some(stone())));
+    }
+
+    #[test]
+    fn leaves_next_to_leaves_hide_the_shared_face() {
+        let leaves = (key("leaves"), RenderLayer::Cutout);
+        assert!(is_face_hidden(leaves, Some(leaves)));
+        assert!(!is_face_hidden(leaves, Some(glass())));
+    }
+
+    #[test]
+    fn different_translucent_blocks_show_their_faces() {
+        let water = (key("water"), RenderLayer::Translucent);
+        assert!(!is_face_hidden(water, Some(glass())));
+        assert!(!is_face_hidden(glass(), Some(water)));
+    }
+
+    #[test]
+    fn faces_into_empty_cells_are_drawn() {
+        assert!(!is_face_hidden(stone(), None));
+        assert!(!is_face_hidden(glass(), None));
+    }
+
+    /// One flat triangle on the `z = 0` plane per `x`, in the given order.
+    fn triangles_at(xs: &[f32]) -> Mesh {
+        let positions: Vec<[f32; 3]> = xs
+            .iter()
+            .flat_map(|x| [[*x, 0.0, 0.0], [*x + 0.1, 0.0, 0.0], [*x, 0.1, 0.0]])
+            .collect();
+        let indices = (0..positions.len() as u32).collect();
+
+        let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+        mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+        mesh.set_indices(Some(Indices::U32(indices)));
+        mesh
+    }
+
+    #[test]
+    fn translucent_triangles_are_sorted_furthest_first() {
+        let mut mesh = triangles_at(&[2.0, 8.0, -5.0, 0.0]);
+        sort_back_to_front(&mut mesh, Vec3::new(1.0, 0.0, 0.0));
+
+        // About 1, 7, 6 and 1 blocks away, the first a little further than the last.
+        let first_indices: Vec<u32> = match mesh.indices() {
+            Some(Indices::U32(indices)) => indices.chunks_exact(3).map(|t| t[0]).collect(),
+            _ => unreachable!(),
+        };
+        assert_eq!(first_indices, [3, 6, 0, 9]);
+    }
+
+    #[test]
+    fn corners_darken_with_each_occluding_neighbour() {
+        assert_eq!(ambient_occlusion_level(false, false, false), 3);
+        assert_eq!(ambient_occlusion_level(false, false, true), 2);
+        assert_eq!(ambient_occlusion_level(true, false, false), 2);
+        assert_eq!(ambient_occlusion_level(false, true, true), 1);
+        // Two sides close the corner off, whatever is in it.
+        assert_eq!(ambient_occlusion_level(true, true, false), 0);
+        assert_eq!(ambient_occlusion_level(true, true, true), 0);
+    }
+
+    /// The top face of the block at `(1, 1, 1)`, with stone in `stone`.
+    fn shaded_top_face(stone: &[IVec3]) -> MeshFragment {
+        let mut registry = Registry::default();
+        registry.blocks.insert(
+            key("stone"),
+            Block {
+                solid: true,
+                light_opacity: MAX_LIGHT,
+                ..default()
+            },
+        );
+        let client_registry = ClientRegistry::default();
+
+        let mut chunk = Chunk::new(IVec3::ZERO);
+        for position in stone {
+            chunk.blocks[chunk_index(*position)] = Some(key("stone"));
+        }
+        let neighbourhood = ChunkNeighbourhood::new(IVec3::ZERO, |position| {
+            (position == IVec3::ZERO).then_some(&chunk)
+        });
+        let context = MeshContext {
+            registry: &registry,
+            client_registry: &client_registry,
+            neighbourhood: &neighbourhood,
+        };
+
+        let position = IVec3::ONE;
+        let texture = BlockTexture {
+            uv: Rect {
+                left: 0.0,
+                right: 1.0,
+                top: 0.0,
+                bottom: 1.0,
+            },
+            layer: 0,
+        };
+        let mut fragment = generate_block_face(BlockFace::Top, position, texture);
+        apply_ambient_occlusion(&context, &mut fragment, BlockFace::Top, position);
+        fragment
+    }
+
+    #[test]
+    fn open_faces_are_unoccluded() {
+        let fragment = shaded_top_face(&[]);
+
+        assert!(fragment
+            .vertices
+            .iter()
+            .all(|vertex| vertex.ambient_occlusion == 1.0));
+        assert_eq!(fragment.indices, [0, 1, 2, 2, 3, 0]);
+    }
+
+    #[test]
+    fn uneven_corners_split_the_quad_along_the_other_diagonal() {
+        // Stone diagonally off the first corner, above and behind the face.
+        let fragment = shaded_top_face(&[IVec3::new(2, 2, 0)]);
+
+        let levels: Vec<f32> = fragment
+            .vertices
+            .iter()
+            .map(|vertex| vertex.ambient_occlusion * 3.0)
+            .collect();
+        assert_eq!(levels, [2.0, 3.0, 3.0, 3.0]);
+        assert_eq!(fragment.indices, [1, 2, 3, 3, 0, 1]);
+    }
+}
@@ -0,0 +1,214 @@
+use std::path::Path;
+
+use bevy::asset::{AssetLoader, BoxedFuture, HandleId, LoadContext, LoadState, LoadedAsset};
+use bevy::prelude::*;
+use bevy::reflect::TypeUuid;
+use bevy::utils::HashMap;
+use serde::Deserialize;
+
+use crate::error::AssetError;
+use crate::key::Key;
+use crate::render::RenderLayer;
+
+/// The folder in each resource pack block model definitions are read from, as
+/// `<namespace>/<name>.model.ron`.
+pub const MODELS_FOLDER: &str = "models";
+
+/// Changes how a registered block model is drawn. Read from
+/// `models/<namespace>/<name>.model.ron` in a resource pack, like:
+///
+/// ```ron
+/// (
+///     texture: Some("blocks/stained_glass.png"),
+///     layer: Some(Cutout),
+/// )
+/// ```
+///
+/// Anything left out keeps what the block model was registered with.
+#[derive(Debug, Clone, Default, Deserialize, TypeUuid)]
+#[uuid = "3c1f5a0e-8d2b-4e7a-9b61-52d0c4a7e913"]
+#[serde(default)]
+pub struct BlockModelDefinition {
+    /// The texture's path within the resource packs, like `blocks/glass.png`.
+    pub texture: Option<String>,
+    pub layer: Option<RenderLayer>,
+}
+
+#[derive(Default)]
+pub struct BlockModelDefinitionLoader;
+
+impl AssetLoader for BlockModelDefinitionLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let definition: BlockModelDefinition = ron::de::from_bytes(bytes)?;
+            load_context.set_default_asset(LoadedAsset::new(definition));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["model.ron"]
+    }
+}
+
+/// Every pack folder of `packs` from the bottom up, starting with the game's
+/// own assets at the root of the asset folder.
+fn stack(packs: &[String]) -> impl Iterator<Item = &str> {
+    std::iter::once("").chain(packs.iter().map(String::as_str))
+}
+
+/// The block textures and model definitions of a stack of resource packs, while
+/// they load.
+#[derive(Clone)]
+pub struct PackLoad {
+    packs: Vec<String>,
+    /// Each pack folder with the files loaded from it, bottom first.
+    files: Vec<(String, Vec<HandleUntyped>)>,
+}
+
+impl PackLoad {
+    /// Starts loading the `textures` folder and [`MODELS_FOLDER`] of each of
+    /// `packs` and the game's own assets below them.
+    pub fn start(
+        asset_server: &AssetServer,
+        packs: &[String],
+        textures: &str,
+        errors: &mut Vec<AssetError>,
+    ) -> Self {
+        let mut files = Vec::new();
+
+        for pack in stack(packs) {
+            let mut handles = Vec::new();
+            for folder in [textures, MODELS_FOLDER] {
+                let path = Path::new(pack).join(folder);
+                match asset_server.load_folder(&path) {
+                    Ok(folder_handles) => handles.extend(folder_handles),
+                    // The game's own assets need no model definitions.
+                    Err(_) if pack.is_empty() && folder == textures => {
+                        errors.push(AssetError::MissingFolder {
+                            path: path.to_string_lossy().into_owned(),
+                        })
+                    }
+                    Err(_) => {}
+                }
+            }
+
+            if handles.is_empty() && !pack.is_empty() {
+                errors.push(AssetError::EmptyPack {
+                    pack: pack.to_string(),
+                });
+            }
+            files.push((pack.to_string(), handles));
+        }
+
+        PackLoad {
+            packs: packs.to_vec(),
+            files,
+        }
+    }
+
+    /// The pack folders being loaded, lowest first.
+    pub fn packs(&self) -> &[String] {
+        &self.packs
+    }
+
+    /// Every file being loaded, from every pack.
+    pub fn handles(&self) -> impl Iterator<Item = &HandleUntyped> {
+        self.files.iter().flat_map(|(_, handles)| handles)
+    }
+
+    /// Whether every file has either loaded or failed to.
+    pub fn is_finished(&self, asset_server: &AssetServer) -> bool {
+        self.handles().all(|handle| {
+            matches!(
+                asset_server.get_load_state(handle),
+                LoadState::Loaded | LoadState::Failed
+            )
+        })
+    }
+
+    /// Lays the packs over each other, so each file comes from the highest pack
+    /// that loaded it.
+    pub fn merge(&self, asset_server: &AssetServer, errors: &mut Vec<AssetError>) -> MergedPacks {
+        let mut files = HashMap::default();
+
+        for (pack, handles) in &self.files {
+            for handle in handles {
+                let asset_path = match asset_server.get_handle_path(handle) {
+                    Some(asset_path) => asset_path,
+                    None => continue,
+                };
+                if asset_server.get_load_state(handle) == LoadState::Failed {
+                    errors.push(AssetError::FailedToLoad {
+                        path: asset_path.path().to_string_lossy().into_owned(),
+                    });
+                    continue;
+                }
+
+                let path = asset_path
+                    .path()
+                    .strip_prefix(pack)
+                    .unwrap_or_else(|_| asset_path.path());
+
+                files.insert(path.to_string_lossy().replace('\\', "/"), handle.clone());
+            }
+        }
+
+        MergedPacks {
+            load: self.clone(),
+            files,
+        }
+    }
+}
+
+/// The files of a stack of resource packs by their path within a pack, each
+/// from the highest pack that has it.
+pub struct MergedPacks {
+    /// What was merged, including files that failed to load, so they are
+    /// merged in once they are fixed.
+    load: PackLoad,
+    files: HashMap<String, HandleUntyped>,
+}
+
+impl MergedPacks {
+    /// The pack folders merged, lowest first.
+    pub fn packs(&self) -> &[String] {
+        self.load.packs()
+    }
+
+    /// Merges the same files again, after some of them changed.
+    pub fn merge_again(&self, asset_server: &AssetServer, errors: &mut Vec<AssetError>) -> Self {
+        self.load.merge(asset_server, errors)
+    }
+
+    /// Every file in the `textures` folder, by path.
+    pub fn textures<'a>(
+        &'a self,
+        textures: &'a str,
+    ) -> impl Iterator<Item = (&'a str, Handle<Image>)> + 'a {
+        self.files
+            .iter()
+            .filter(move |(path, _)| Path::new(path).starts_with(textures))
+            .map(|(path, handle)| (path.as_str(), handle.clone().typed()))
+    }
+
+    /// Whether the file loaded as `id` is in one of the packs, even if it failed
+    /// to load or a higher pack has its own.
+    pub fn contains(&self, id: HandleId) -> bool {
+        self.load.handles().any(|handle| handle.id == id)
+    }
+
+    pub fn texture(&self, path: &str) -> Option<Handle<Image>> {
+        self.files.get(path).map(|handle| handle.clone().typed())
+    }
+
+    /// The definition the packs give the block model of `key`, if any.
+    pub fn model_definition(&self, key: Key) -> Option<Handle<BlockModelDefinition>> {
+        let path = format!("{}/{}/{}.model.ron", MODELS_FOLDER, key.namespace, key.name);
+        self.files.get(&path).map(|handle| handle.clone().typed())
+    }
+}
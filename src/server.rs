@@ -0,0 +1,1711 @@
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::io;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bevy::app::ScheduleRunnerSettings;
+use bevy::log::LogPlugin;
+use bevy::prelude::*;
+use bevy::utils::{HashMap, HashSet};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::chunk::{chunk_index, Chunk, CHUNK_VOLUME};
+use crate::inventory::{starting_inventory, Inventory, InventorySave};
+use crate::key::Key;
+use crate::loopback::{loopback_pair, LoopbackClock, LoopbackStream};
+use crate::physics::BoundingBox;
+use crate::player::{Player, REACH_DISTANCE};
+use crate::plugins::CorePlugin;
+use crate::protocol::{
+    BlockChangeEntry, ChunkData, ClientMessage, Connection, IdTable, RegistryIds, ServerMessage,
+    PROTOCOL_VERSION,
+};
+use crate::registry::Registry;
+use crate::tick::{BlockChanged, BlockWorld, PendingBlockUpdates, ScheduledTick};
+use crate::utils::{
+    block_neighbours, block_to_chunk, block_to_local, chunk_to_block, local_position,
+    world_to_chunk,
+};
+use crate::world::{
+    chunks_in_view, generate_chunk, spawn_chunk, ChunkGenerator, ChunkViewer, World, WorldSystem,
+    WorldTick, TICKS_PER_SECOND,
+};
+
+/// The furthest out, in chunks, the server generates and sends chunks around a
+/// client, whatever view radius it asks for.
+pub const MAX_VIEW_RADIUS: u32 = 8;
+
+/// Chunks sent to each client per frame, so a client joining does not stall the
+/// server.
+pub const MAX_CHUNKS_SENT_PER_FRAME: usize = 16;
+
+/// How much further than [`REACH_DISTANCE`] the server lets a client edit
+/// blocks, since the position it last sent may already be out of date.
+const REACH_TOLERANCE: f32 = 1.5;
+
+/// Seconds between saves of the chunks that changed since the last one.
+pub const AUTOSAVE_INTERVAL: f64 = 30.0;
+
+/// Seconds between keepalives sent to each client.
+pub const KEEPALIVE_INTERVAL: f64 = 5.0;
+/// Seconds a client can go without sending anything before it is dropped.
+pub const CLIENT_TIMEOUT: f64 = 30.0;
+
+/// The port a dedicated server listens on when none is given.
+pub const DEFAULT_PORT: u16 = 30000;
+
+const USAGE: &str = "\
+Usage: defaria server [options]
+
+Options:
+    --world <dir>       Directory the world is kept in (default: saves/world)
+    --seed <number>     Seed for a new world (default: picked at random)
+    --port <port>       Port to listen on (default: 30000)
+    --frame-rate <hz>   Server frames per second (default: 60)
+    --tick-rate <hz>    World ticks per second (default: 20)";
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+pub enum ServerSystem {
+    Receive,
+    Send,
+}
+
+/// Where clients connect from.
+pub enum ServerSocket {
+    Tcp(TcpListener),
+    /// Streams handed over by clients running in the same process.
+    Loopback(Mutex<Receiver<LoopbackStream>>),
+}
+
+/// A connected client. Its `Transform` is where the client last said its
+/// camera was, and its [`Inventory`] is its player's, which the server keeps.
+#[derive(Component)]
+pub struct RemoteClient {
+    pub connection: Connection,
+    /// Identifies the client's player to the other clients.
+    pub id: u32,
+    /// The name the client gave, once it has said hello.
+    pub name: Option<String>,
+    /// Chunks the client has been sent and not yet told to unload.
+    pub sent_chunks: HashSet<IVec3>,
+    /// When the client last sent anything, in seconds since the server started.
+    pub last_heard: f64,
+    /// Set once the client has been told why it is being disconnected, so it is
+    /// dropped as soon as that has been sent.
+    pub closing: bool,
+    pub pitch: f32,
+    pub yaw: f32,
+    /// Whether the client has moved or looked around since the other clients
+    /// were last told.
+    pub moved: bool,
+}
+
+impl RemoteClient {
+    fn describe(&self) -> &str {
+        self.name.as_deref().unwrap_or("A client")
+    }
+}
+
+/// The inventory of every player that has joined the world, by name, as it
+/// will be saved.
+#[derive(Default)]
+pub struct PlayerInventories {
+    pub saved: BTreeMap<String, InventorySave>,
+    /// Whether any of them changed since they were last saved.
+    pub unsaved: bool,
+}
+
+/// Loaded chunks with blocks that changed since they were last saved.
+#[derive(Default)]
+pub struct UnsavedChunks(pub HashSet<IVec3>);
+
+/// A block edit asked for by a client, waiting to be validated.
+pub struct BlockEditRequest {
+    pub client: Entity,
+    /// The client's number for the edit, sent back with the result.
+    pub sequence: u32,
+    pub position: IVec3,
+    /// The block to place, or `None` to break the block there.
+    pub block: Option<Key<'static>>,
+}
+
+/// How a server is run, from the command line of a dedicated server.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerConfig {
+    pub world_dir: PathBuf,
+    /// The seed for a new world. A world that already exists keeps its own.
+    pub seed: Option<u64>,
+    pub port: u16,
+    /// Server frames per second.
+    pub frame_rate: f64,
+    /// World ticks per second, which need not divide the frame rate.
+    pub tick_rate: f32,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            world_dir: PathBuf::from("saves/world"),
+            seed: None,
+            port: DEFAULT_PORT,
+            frame_rate: 60.0,
+            tick_rate: TICKS_PER_SECOND,
+        }
+    }
+}
+
+impl ServerConfig {
+    /// Parses the arguments given after `server`.
+    pub fn from_args(args: &[String]) -> Result<Self, String> {
+        let mut config = ServerConfig::default();
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let mut value = || args.next().ok_or_else(|| format!("{} needs a value", arg));
+
+            match arg.as_str() {
+                "--world" => config.world_dir = PathBuf::from(value()?),
+                "--seed" => config.seed = Some(parse_arg(arg, value()?)?),
+                "--port" => config.port = parse_arg(arg, value()?)?,
+                "--frame-rate" => {
+                    config.frame_rate = parse_arg(arg, value()?)?;
+                    if !config.frame_rate.is_finite() || config.frame_rate <= 0.0 {
+                        return Err("--frame-rate must be a number above zero".to_string());
+                    }
+                }
+                "--tick-rate" => {
+                    config.tick_rate = parse_arg(arg, value()?)?;
+                    if !config.tick_rate.is_finite() || config.tick_rate <= 0.0 {
+                        return Err("--tick-rate must be a number above zero".to_string());
+                    }
+                }
+                _ => return Err(format!("Unknown argument {}", arg)),
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+fn parse_arg<T: std::str::FromStr>(arg: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("{} cannot be {}", arg, value))
+}
+
+/// Everything about a world that is kept in its directory, besides its chunks.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WorldSave {
+    pub seed: u64,
+}
+
+impl WorldSave {
+    pub fn load(path: impl AsRef<Path>) -> Option<Self> {
+        read_ron(path.as_ref())
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), String> {
+        write_ron(self, path.as_ref())
+    }
+
+    /// Loads the world in `config.world_dir`, or creates one there with the
+    /// configured seed.
+    pub fn load_or_create(config: &ServerConfig) -> Result<Self, String> {
+        let path = config.world_dir.join("world.ron");
+
+        if let Some(save) = WorldSave::load(&path) {
+            if config.seed.is_some_and(|seed| seed != save.seed) {
+                warn!(
+                    "{} already has the seed {}, ignoring --seed",
+                    config.world_dir.display(),
+                    save.seed
+                );
+            }
+            return Ok(save);
+        }
+
+        let save = WorldSave {
+            seed: config.seed.unwrap_or_else(random_seed),
+        };
+        save.save(&path)?;
+        Ok(save)
+    }
+}
+
+/// A chunk as it is kept in the world directory. Its light is worked out again
+/// when it is loaded.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChunkSave {
+    /// The blocks in the chunk, as `namespace:name`.
+    pub palette: Vec<String>,
+    /// Runs of cells in index order, each as its length, its block's place in
+    /// `palette` plus one, or `0` for air, and its state.
+    pub runs: Vec<(u16, u16, u8)>,
+    /// Each scheduled tick as the world ticks left until it is due, and the
+    /// block's position.
+    pub scheduled_ticks: Vec<(u64, IVec3)>,
+}
+
+impl ChunkSave {
+    /// Saves `chunk` as it is at the world tick `tick`.
+    pub fn from_chunk(chunk: &Chunk<'static>, tick: u64) -> Self {
+        let palette = IdTable::new(
+            chunk
+                .blocks
+                .iter()
+                .flatten()
+                .copied()
+                .collect::<HashSet<_>>(),
+        );
+
+        let mut runs: Vec<(u16, u16, u8)> = Vec::new();
+        for (block, state) in chunk.blocks.iter().zip(&chunk.states) {
+            let block = palette.id(*block).unwrap_or(0);
+
+            match runs.last_mut() {
+                Some((length, run_block, run_state))
+                    if *run_block == block && run_state == state =>
+                {
+                    *length += 1
+                }
+                _ => runs.push((1, block, *state)),
+            }
+        }
+
+        let mut scheduled_ticks: Vec<(u64, IVec3)> = chunk
+            .scheduled_ticks
+            .iter()
+            .map(|Reverse(scheduled_tick)| {
+                (
+                    scheduled_tick.due.saturating_sub(tick),
+                    scheduled_tick.position,
+                )
+            })
+            .collect();
+        scheduled_ticks.sort_unstable_by_key(|(delay, position)| (*delay, position.to_array()));
+
+        ChunkSave {
+            palette: palette.names().to_vec(),
+            runs,
+            scheduled_ticks,
+        }
+    }
+
+    /// Rebuilds the chunk at `position`, carrying on from the world tick `tick`.
+    /// Blocks that are no longer registered are left empty.
+    pub fn to_chunk(
+        &self,
+        position: IVec3,
+        registry: &Registry<'static>,
+        tick: u64,
+    ) -> Result<Chunk<'static>, String> {
+        let palette = IdTable::from_names(self.palette.clone(), |name| registry.block_key(name));
+        let mut chunk = Chunk::new(position);
+
+        let mut index = 0;
+        for (length, block, state) in &self.runs {
+            let end = index + *length as usize;
+            if end > CHUNK_VOLUME {
+                return Err("it has too many blocks".to_string());
+            }
+
+            let block = palette.key(*block).map_err(|error| error.to_string())?;
+            chunk.blocks[index..end].fill(block);
+            chunk.states[index..end].fill(*state);
+            index = end;
+        }
+        if index != CHUNK_VOLUME {
+            return Err("it has too few blocks".to_string());
+        }
+
+        for (delay, block_position) in &self.scheduled_ticks {
+            if block_to_chunk(*block_position) != position {
+                return Err(format!("it has a tick scheduled at {}", block_position));
+            }
+
+            chunk.scheduled_ticks.push(Reverse(ScheduledTick {
+                due: tick + delay,
+                position: *block_position,
+            }));
+        }
+
+        Ok(chunk)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Option<Self> {
+        read_ron(path.as_ref())
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), String> {
+        write_ron(self, path.as_ref())
+    }
+}
+
+/// Where the chunk at `position` is kept in the world directory `world_dir`.
+pub fn chunk_path(world_dir: &Path, position: IVec3) -> PathBuf {
+    world_dir
+        .join("chunks")
+        .join(format!("{}.{}.{}.ron", position.x, position.y, position.z))
+}
+
+fn read_ron<T: DeserializeOwned>(path: &Path) -> Option<T> {
+    let contents = std::fs::read_to_string(path).ok()?;
+
+    ron::from_str(&contents)
+        .map_err(|error| warn!("Could not parse {}: {}", path.display(), error))
+        .ok()
+}
+
+fn write_ron(value: &impl Serialize, path: &Path) -> Result<(), String> {
+    let contents = ron::to_string(value).map_err(|error| error.to_string())?;
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).map_err(|error| error.to_string())?;
+    }
+
+    std::fs::write(path, contents).map_err(|error| error.to_string())
+}
+
+/// Where the inventories of the players are kept in the world directory
+/// `world_dir`.
+pub fn inventories_path(world_dir: &Path) -> PathBuf {
+    world_dir.join("inventories.ron")
+}
+
+/// A seed for a world nobody picked one for.
+fn random_seed() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_nanos() as u64)
+}
+
+/// A headless app that owns the world: it generates and ticks chunks, streams
+/// them to the clients connecting through `socket`, and applies their block
+/// edits.
+pub fn server_app(socket: ServerSocket, config: ServerConfig) -> io::Result<App> {
+    if let ServerSocket::Tcp(listener) = &socket {
+        listener.set_nonblocking(true)?;
+    }
+
+    let tick_rate = config.tick_rate;
+    let mut app = App::new();
+    app.insert_resource(ScheduleRunnerSettings::run_loop(Duration::from_secs_f64(
+        1.0 / config.frame_rate,
+    )))
+    .insert_resource(socket)
+    .insert_resource(RegistryIds::default())
+    .insert_resource(UnsavedChunks::default())
+    .insert_resource(PlayerInventories::default())
+    .insert_resource(config)
+    .insert_resource(ChunkGenerator {
+        radius: MAX_VIEW_RADIUS,
+    })
+    .add_plugins(MinimalPlugins)
+    .add_plugin(CorePlugin {
+        builtin_content: true,
+        simulate: true,
+    })
+    .insert_resource(WorldTick::new(tick_rate))
+    .add_event::<BlockEditRequest>()
+    .add_startup_system(load_world)
+    .add_startup_system(load_inventories)
+    .add_startup_system(number_registry)
+    .add_system(accept_clients.before(ServerSystem::Receive))
+    .add_system(receive_client_messages.label(ServerSystem::Receive))
+    .add_system(
+        apply_block_edits
+            .after(ServerSystem::Receive)
+            .before(WorldSystem::Tick),
+    )
+    .add_system(track_player_inventories.after(apply_block_edits))
+    .add_system(autosave_inventories.after(track_player_inventories))
+    .add_system(load_chunks)
+    .add_system(track_unsaved_chunks.after(WorldSystem::Tick))
+    .add_system(unload_chunks.after(track_unsaved_chunks))
+    .add_system(autosave_chunks.after(track_unsaved_chunks))
+    .add_system(
+        stream_chunks
+            .label(ServerSystem::Send)
+            .after(WorldSystem::Tick),
+    )
+    .add_system(
+        broadcast_block_changes
+            .label(ServerSystem::Send)
+            .after(WorldSystem::Tick),
+    )
+    .add_system(keep_clients_alive.label(ServerSystem::Send))
+    .add_system(announce_players.label(ServerSystem::Send))
+    .add_system(
+        broadcast_player_movement
+            .label(ServerSystem::Send)
+            .after(announce_players),
+    )
+    .add_system(flush_clients.after(ServerSystem::Send));
+
+    Ok(app)
+}
+
+/// Starts a server for this game alone on a thread of its own, returning the
+/// client's end of a loopback stream to it. Everything sent either way arrives
+/// `latency` milliseconds of `clock` later.
+pub fn start_local_server(clock: &LoopbackClock, latency: u64) -> io::Result<Connection> {
+    let (client, server) = loopback_pair(clock, latency);
+    let (sender, receiver) = mpsc::channel();
+    sender.send(server).unwrap();
+
+    std::thread::Builder::new()
+        .name("server".to_string())
+        .spawn(move || {
+            let socket = ServerSocket::Loopback(Mutex::new(receiver));
+            match server_app(socket, ServerConfig::default()) {
+                Ok(mut app) => app.run(),
+                Err(error) => error!("Could not start the server: {}", error),
+            }
+        })?;
+
+    Ok(Connection::from_transport(client))
+}
+
+/// Runs a dedicated server with no window or renderer, configured by the
+/// arguments given after `server`, until the process is stopped.
+pub fn run_dedicated_server(args: &[String]) {
+    let config = match ServerConfig::from_args(args) {
+        Ok(config) => config,
+        Err(error) => {
+            eprintln!("{}\n\n{}", error, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    let port = config.port;
+    let frame_rate = config.frame_rate;
+    let tick_rate = config.tick_rate;
+    let app = TcpListener::bind(("0.0.0.0", port))
+        .and_then(|listener| server_app(ServerSocket::Tcp(listener), config));
+
+    match app {
+        Ok(mut app) => {
+            app.add_plugin(LogPlugin);
+            info!(
+                "Listening on port {} at {} frames and {} ticks per second",
+                port, frame_rate, tick_rate
+            );
+            app.run();
+        }
+        Err(error) => {
+            eprintln!("Could not listen on port {}: {}", port, error);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Loads the seed of the configured world, creating the world if it is new.
+pub fn load_world(config: Res<ServerConfig>, mut world: ResMut<World>) {
+    world.seed = match WorldSave::load_or_create(&config) {
+        Ok(save) => save.seed,
+        Err(error) => {
+            let seed = config.seed.unwrap_or_else(random_seed);
+            error!(
+                "Could not save {}, its seed {} will be lost: {}",
+                config.world_dir.display(),
+                seed,
+                error
+            );
+            seed
+        }
+    };
+    info!(
+        "Loaded {} with the seed {}",
+        config.world_dir.display(),
+        world.seed
+    );
+}
+
+/// Loads the inventories of the players who have been in the configured world.
+pub fn load_inventories(config: Res<ServerConfig>, mut inventories: ResMut<PlayerInventories>) {
+    if let Some(saved) = read_ron(&inventories_path(&config.world_dir)) {
+        inventories.saved = saved;
+    }
+}
+
+fn save_chunk(config: &ServerConfig, chunk: &Chunk<'static>, tick: u64) {
+    let path = chunk_path(&config.world_dir, chunk.position);
+
+    if let Err(error) = ChunkSave::from_chunk(chunk, tick).save(&path) {
+        error!("Could not save {}: {}", path.display(), error);
+    }
+}
+
+/// Loads the chunks within each viewer's view radius from the world directory,
+/// generating the ones that were never saved.
+pub fn load_chunks(
+    mut commands: Commands,
+    mut world: ResMut<World>,
+    config: Res<ServerConfig>,
+    registry: Res<Registry<'static>>,
+    world_tick: Res<WorldTick>,
+    chunk_generator: Res<ChunkGenerator>,
+    viewers: Query<(&Transform, &ChunkViewer)>,
+) {
+    for (transform, viewer) in viewers.iter() {
+        let centre = world_to_chunk(transform.translation);
+
+        for position in chunks_in_view(centre, viewer.radius.min(chunk_generator.radius)) {
+            if world.chunks.contains_key(&position) {
+                continue;
+            }
+
+            let path = chunk_path(&config.world_dir, position);
+            let chunk = match ChunkSave::load(&path)
+                .map(|save| save.to_chunk(position, &registry, world_tick.tick))
+            {
+                Some(Ok(chunk)) => chunk,
+                Some(Err(error)) => {
+                    warn!(
+                        "Could not load {}, generating it again: {}",
+                        path.display(),
+                        error
+                    );
+                    generate_chunk(world.seed, position)
+                }
+                None => generate_chunk(world.seed, position),
+            };
+
+            spawn_chunk(&mut commands, &mut world, chunk);
+        }
+    }
+}
+
+pub fn track_unsaved_chunks(
+    mut unsaved: ResMut<UnsavedChunks>,
+    mut block_changes: EventReader<BlockChanged>,
+) {
+    unsaved.0.extend(
+        block_changes
+            .iter()
+            .map(|BlockChanged(position)| block_to_chunk(*position)),
+    );
+}
+
+/// Saves and despawns the chunks more than a chunk past every viewer's view
+/// radius, so walking back and forth over a chunk border does not reload them.
+#[allow(clippy::too_many_arguments)]
+pub fn unload_chunks(
+    mut commands: Commands,
+    mut world: ResMut<World>,
+    config: Res<ServerConfig>,
+    world_tick: Res<WorldTick>,
+    chunk_generator: Res<ChunkGenerator>,
+    mut unsaved: ResMut<UnsavedChunks>,
+    chunks: Query<&Chunk<'static>>,
+    viewers: Query<(&Transform, &ChunkViewer)>,
+) {
+    let views: Vec<(IVec3, f32)> = viewers
+        .iter()
+        .map(|(transform, viewer)| {
+            let radius = viewer.radius.min(chunk_generator.radius) as f32 + 1.0;
+            (world_to_chunk(transform.translation), radius)
+        })
+        .collect();
+
+    let out_of_view: Vec<IVec3> = world
+        .chunks
+        .keys()
+        .copied()
+        .filter(|position| {
+            views.iter().all(|(centre, radius)| {
+                (*position - *centre).as_vec3().length_squared() > radius.powi(2)
+            })
+        })
+        .collect();
+
+    for position in out_of_view {
+        let entity = world.chunks.remove(&position).unwrap();
+
+        if let Ok(chunk) = chunks.get(entity) {
+            if unsaved.0.remove(&position) || !chunk.scheduled_ticks.is_empty() {
+                save_chunk(&config, chunk, world_tick.tick);
+            }
+        }
+        commands.entity(entity).despawn();
+    }
+}
+
+/// Saves the chunks that changed since they were last saved, every
+/// [`AUTOSAVE_INTERVAL`] seconds.
+pub fn autosave_chunks(
+    time: Res<Time>,
+    mut last_save: Local<f64>,
+    world: Res<World>,
+    config: Res<ServerConfig>,
+    world_tick: Res<WorldTick>,
+    mut unsaved: ResMut<UnsavedChunks>,
+    chunks: Query<&Chunk<'static>>,
+) {
+    let now = time.seconds_since_startup();
+    if now - *last_save < AUTOSAVE_INTERVAL {
+        return;
+    }
+    *last_save = now;
+
+    for position in unsaved.0.drain() {
+        if let Some(chunk) = world
+            .chunks
+            .get(&position)
+            .and_then(|entity| chunks.get(*entity).ok())
+        {
+            save_chunk(&config, chunk, world_tick.tick);
+        }
+    }
+}
+
+/// Keeps the saved inventory of each player in step with its client's.
+pub fn track_player_inventories(
+    mut inventories: ResMut<PlayerInventories>,
+    clients: Query<(&RemoteClient, &Inventory), Changed<Inventory>>,
+) {
+    for (client, inventory) in clients.iter() {
+        if let Some(name) = &client.name {
+            inventories.saved.insert(name.clone(), inventory.to_save());
+            inventories.unsaved = true;
+        }
+    }
+}
+
+/// Saves the players' inventories if any changed, every [`AUTOSAVE_INTERVAL`]
+/// seconds.
+pub fn autosave_inventories(
+    time: Res<Time>,
+    mut last_save: Local<f64>,
+    config: Res<ServerConfig>,
+    mut inventories: ResMut<PlayerInventories>,
+) {
+    let now = time.seconds_since_startup();
+    if now - *last_save < AUTOSAVE_INTERVAL || !inventories.unsaved {
+        return;
+    }
+    *last_save = now;
+
+    let path = inventories_path(&config.world_dir);
+    match write_ron(&inventories.saved, &path) {
+        Ok(()) => inventories.unsaved = false,
+        Err(error) => warn!("Could not save {}: {}", path.display(), error),
+    }
+}
+
+/// Numbers the registered blocks and items for the protocol, once they are all
+/// registered.
+pub fn number_registry(registry: Res<Registry<'static>>, mut registry_ids: ResMut<RegistryIds>) {
+    *registry_ids = RegistryIds::new(&registry);
+}
+
+pub fn accept_clients(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut next_id: Local<u32>,
+    socket: Res<ServerSocket>,
+) {
+    let mut accept = |connection| {
+        commands
+            .spawn()
+            .insert(RemoteClient {
+                connection,
+                id: *next_id,
+                name: None,
+                sent_chunks: HashSet::default(),
+                last_heard: time.seconds_since_startup(),
+                closing: false,
+                pitch: 0.0,
+                yaw: 0.0,
+                moved: false,
+            })
+            .insert(Inventory::default())
+            .insert_bundle(TransformBundle::default());
+        *next_id = next_id.wrapping_add(1);
+    };
+
+    match &*socket {
+        ServerSocket::Tcp(listener) => loop {
+            match listener.accept() {
+                Ok((stream, address)) => match Connection::new(stream) {
+                    Ok(connection) => {
+                        info!("{} connected", address);
+                        accept(connection);
+                    }
+                    Err(error) => warn!(
+                        "Could not set up the connection from {}: {}",
+                        address, error
+                    ),
+                },
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => return,
+                Err(error) => {
+                    warn!("Could not accept a client: {}", error);
+                    return;
+                }
+            }
+        },
+        ServerSocket::Loopback(receiver) => {
+            for stream in receiver.lock().unwrap().try_iter() {
+                info!("A local client connected");
+                accept(Connection::from_transport(stream));
+            }
+        }
+    }
+}
+
+fn disconnect(
+    commands: &mut Commands,
+    entity: Entity,
+    client: &RemoteClient,
+    reason: impl std::fmt::Display,
+) {
+    info!("{} left: {}", client.describe(), reason);
+    commands.entity(entity).despawn();
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn receive_client_messages(
+    mut commands: Commands,
+    time: Res<Time>,
+    registry: Res<Registry<'static>>,
+    registry_ids: Res<RegistryIds>,
+    inventories: Res<PlayerInventories>,
+    mut edits: EventWriter<BlockEditRequest>,
+    mut clients: Query<(Entity, &mut RemoteClient, &mut Transform, &mut Inventory)>,
+) {
+    for (entity, mut client, mut transform, mut inventory) in clients.iter_mut() {
+        if client.closing {
+            continue;
+        }
+
+        let messages = match client.connection.receive::<ClientMessage>() {
+            Ok(messages) => messages,
+            Err(error) => {
+                disconnect(&mut commands, entity, &client, error);
+                continue;
+            }
+        };
+
+        if !messages.is_empty() {
+            client.last_heard = time.seconds_since_startup();
+        }
+
+        for message in messages {
+            match message {
+                ClientMessage::Hello {
+                    version,
+                    name,
+                    view_radius,
+                } => {
+                    if version != PROTOCOL_VERSION {
+                        let reason = format!(
+                            "the server speaks protocol version {}, not {}",
+                            PROTOCOL_VERSION, version
+                        );
+                        info!("Turning {} away: {}", name, reason);
+                        client
+                            .connection
+                            .send(&ServerMessage::Disconnect { reason });
+                        client.closing = true;
+                        break;
+                    }
+
+                    info!("{} joined", name);
+                    *inventory = match inventories.saved.get(&name) {
+                        Some(save) => Inventory::from_save(save, &registry),
+                        None => starting_inventory(&registry),
+                    };
+                    client.name = Some(name);
+                    client.connection.send(&registry_ids.welcome());
+                    client.connection.send(&ServerMessage::SetInventory {
+                        slots: inventory
+                            .slots
+                            .iter()
+                            .map(|slot| match slot {
+                                Some(stack) => (
+                                    registry_ids.items.id(Some(stack.item)).unwrap_or(0),
+                                    stack.count,
+                                ),
+                                None => (0, 0),
+                            })
+                            .collect(),
+                    });
+                    commands.entity(entity).insert(ChunkViewer {
+                        radius: view_radius.min(MAX_VIEW_RADIUS),
+                    });
+                }
+                ClientMessage::PlayerPosition { position } => {
+                    transform.translation = position;
+                    client.moved = true;
+                }
+                ClientMessage::PlayerLook { pitch, yaw } => {
+                    transform.rotation =
+                        Quat::from_axis_angle(Vec3::Y, yaw) * Quat::from_axis_angle(Vec3::X, pitch);
+                    client.pitch = pitch;
+                    client.yaw = yaw;
+                    client.moved = true;
+                }
+                ClientMessage::EditBlock {
+                    sequence,
+                    position,
+                    block,
+                } => match registry_ids.blocks.key(block) {
+                    Ok(block) => edits.send(BlockEditRequest {
+                        client: entity,
+                        sequence,
+                        position,
+                        block,
+                    }),
+                    Err(error) => {
+                        disconnect(&mut commands, entity, &client, error);
+                        break;
+                    }
+                },
+                ClientMessage::KeepAlive { .. } => {}
+            }
+        }
+    }
+}
+
+/// An item in `inventory` that places `block`, if there is one.
+fn held_item(
+    registry: &Registry<'static>,
+    inventory: &Inventory,
+    block: Key<'static>,
+) -> Option<Key<'static>> {
+    inventory
+        .slots
+        .iter()
+        .flatten()
+        .map(|stack| stack.item)
+        .find(|item| {
+            registry
+                .items
+                .get(item)
+                .is_some_and(|item| item.places == Some(block))
+        })
+}
+
+/// Whether a client whose camera is at `eye` and who holds `inventory` may
+/// replace the block at `position` with `block`, or break it if that is `None`.
+/// Nobody may place a solid block inside one of `bodies`.
+fn is_valid_edit(
+    world: &BlockWorld,
+    eye: Vec3,
+    inventory: &Inventory,
+    bodies: &[BoundingBox],
+    position: IVec3,
+    block: Option<Key<'static>>,
+) -> bool {
+    let centre = position.as_vec3() + Vec3::splat(0.5);
+    if !world.is_loaded(position) || eye.distance(centre) > REACH_DISTANCE + REACH_TOLERANCE {
+        return false;
+    }
+
+    let existing = world.block_data(position);
+    let block = match block {
+        Some(block) => block,
+        None => return existing.is_some_and(|block| block.fluid.is_none()),
+    };
+
+    let registry = world.registry;
+    let held = held_item(registry, inventory, block).is_some();
+    let replaceable = existing.is_none_or(|block| block.fluid.is_some());
+    let supported = block_neighbours(position).iter().any(|neighbour| {
+        world
+            .block_data(*neighbour)
+            .is_some_and(|block| block.fluid.is_none())
+    });
+    let blocked = registry.blocks.get(&block).is_some_and(|block| block.solid)
+        && bodies
+            .iter()
+            .any(|body| body.overlaps(BoundingBox::block(position)));
+
+    held && replaceable && supported && !blocked
+}
+
+/// Applies the block edits clients asked for, if they are valid, taking placed
+/// blocks out of the client's inventory and putting the drops of broken ones
+/// in. Clients whose edit is refused are sent the block as it really is.
+#[allow(clippy::too_many_arguments)]
+pub fn apply_block_edits(
+    world_tick: Res<WorldTick>,
+    registry: Res<Registry<'static>>,
+    registry_ids: Res<RegistryIds>,
+    mut pending_updates: ResMut<PendingBlockUpdates>,
+    mut block_changes: EventWriter<BlockChanged>,
+    mut requests: EventReader<BlockEditRequest>,
+    mut chunks: Query<&mut Chunk<'static>>,
+    mut clients: Query<(&mut RemoteClient, &Transform, &mut Inventory)>,
+) {
+    let requests: Vec<&BlockEditRequest> = requests.iter().collect();
+    if requests.is_empty() {
+        return;
+    }
+
+    let bodies: Vec<BoundingBox> = clients
+        .iter()
+        .map(|(_, transform, _)| Player::bounding_box(transform.translation))
+        .collect();
+
+    let mut block_world = BlockWorld::new(
+        chunks
+            .iter_mut()
+            .map(|chunk| (chunk.position, chunk))
+            .collect(),
+        &registry,
+        world_tick.tick,
+    );
+
+    for request in requests {
+        let (mut client, transform, mut inventory) = match clients.get_mut(request.client) {
+            Ok(client) => client,
+            Err(_) => continue,
+        };
+
+        if !is_valid_edit(
+            &block_world,
+            transform.translation,
+            &inventory,
+            &bodies,
+            request.position,
+            request.block,
+        ) {
+            client.connection.send(&ServerMessage::BlockChange {
+                position: request.position,
+                block: registry_ids
+                    .blocks
+                    .id(block_world.block(request.position))
+                    .unwrap_or(0),
+                state: block_world.state(request.position),
+            });
+            client.connection.send(&ServerMessage::EditResult {
+                sequence: request.sequence,
+                accepted: false,
+            });
+            continue;
+        }
+
+        match request.block {
+            Some(block) => {
+                if let Some(item) = held_item(&registry, &inventory, block) {
+                    inventory.remove(item, 1);
+                }
+                block_world.set_block(request.position, Some(block), 0);
+            }
+            None => {
+                let drops = block_world.break_block(request.position);
+                for stack in &drops {
+                    inventory.insert(*stack, registry.max_stack_size(stack.item));
+                }
+
+                if !drops.is_empty() {
+                    client.connection.send(&ServerMessage::GiveItems {
+                        stacks: drops
+                            .iter()
+                            .filter_map(|stack| {
+                                Some((registry_ids.items.id(Some(stack.item))?, stack.count))
+                            })
+                            .collect(),
+                    });
+                }
+            }
+        }
+
+        client.connection.send(&ServerMessage::EditResult {
+            sequence: request.sequence,
+            accepted: true,
+        });
+    }
+
+    block_world.finish(&mut pending_updates, &mut block_changes);
+}
+
+/// Sends each client the generated chunks within its view radius that it does
+/// not have yet, nearest first, and tells it to unload the ones it has left
+/// behind.
+pub fn stream_chunks(
+    world: Res<World>,
+    registry_ids: Res<RegistryIds>,
+    chunks: Query<&Chunk<'static>>,
+    mut clients: Query<(&mut RemoteClient, &Transform, &ChunkViewer)>,
+) {
+    for (mut client, transform, viewer) in clients.iter_mut() {
+        let centre = world_to_chunk(transform.translation);
+        let distance_squared = |position: IVec3| (position - centre).as_vec3().length_squared();
+        let radius = viewer.radius as f32;
+
+        // Chunks are only unloaded a chunk past the view radius, so walking back
+        // and forth over a chunk border does not resend them.
+        let left_behind: Vec<IVec3> = client
+            .sent_chunks
+            .iter()
+            .copied()
+            .filter(|position| distance_squared(*position) > (radius + 1.0).powi(2))
+            .collect();
+        for position in left_behind {
+            client.sent_chunks.remove(&position);
+            client
+                .connection
+                .send(&ServerMessage::UnloadChunk { position });
+        }
+
+        let mut wanted: Vec<IVec3> = world
+            .chunks
+            .keys()
+            .copied()
+            .filter(|position| {
+                !client.sent_chunks.contains(position)
+                    && distance_squared(*position) <= radius.powi(2)
+            })
+            .collect();
+        wanted.sort_by(|a, b| distance_squared(*a).total_cmp(&distance_squared(*b)));
+
+        let mut sent = 0;
+        for position in wanted {
+            if sent == MAX_CHUNKS_SENT_PER_FRAME {
+                break;
+            }
+
+            // Chunks spawned this frame are not in the query yet.
+            if let Ok(chunk) = chunks.get(world.chunks[&position]) {
+                client
+                    .connection
+                    .send(&ServerMessage::ChunkData(ChunkData::from_chunk(
+                        chunk,
+                        &registry_ids.blocks,
+                    )));
+                client.sent_chunks.insert(position);
+                sent += 1;
+            }
+        }
+    }
+}
+
+/// Tells every client holding a chunk about the blocks that changed in it, in
+/// one packet per chunk.
+pub fn broadcast_block_changes(
+    mut block_changes: EventReader<BlockChanged>,
+    world: Res<World>,
+    registry_ids: Res<RegistryIds>,
+    chunks: Query<&Chunk<'static>>,
+    mut clients: Query<&mut RemoteClient>,
+) {
+    let mut seen = HashSet::default();
+    let mut changes: HashMap<IVec3, Vec<BlockChangeEntry>> = HashMap::default();
+
+    for BlockChanged(position) in block_changes.iter() {
+        if !seen.insert(*position) {
+            continue;
+        }
+
+        let chunk_position = block_to_chunk(*position);
+        let chunk = match world
+            .chunks
+            .get(&chunk_position)
+            .and_then(|entity| chunks.get(*entity).ok())
+        {
+            Some(chunk) => chunk,
+            None => continue,
+        };
+
+        let local = block_to_local(*position);
+        changes
+            .entry(chunk_position)
+            .or_default()
+            .push(BlockChangeEntry {
+                index: chunk_index(local) as u16,
+                block: registry_ids.blocks.id(chunk.block(local)).unwrap_or(0),
+                state: chunk.state(local),
+            });
+    }
+
+    for (chunk_position, changes) in changes {
+        let message = match changes[..] {
+            [change] => ServerMessage::BlockChange {
+                position: chunk_to_block(chunk_position, local_position(change.index)),
+                block: change.block,
+                state: change.state,
+            },
+            _ => ServerMessage::BulkBlockChange {
+                chunk: chunk_position,
+                changes,
+            },
+        };
+
+        for mut client in clients.iter_mut() {
+            if client.sent_chunks.contains(&chunk_position) {
+                client.connection.send(&message);
+            }
+        }
+    }
+}
+
+/// Sends every client a keepalive now and then, and drops the ones that have
+/// gone quiet.
+pub fn keep_clients_alive(
+    mut commands: Commands,
+    time: Res<Time>,
+    mut last_sent: Local<f64>,
+    mut next_id: Local<u32>,
+    mut clients: Query<(Entity, &mut RemoteClient)>,
+) {
+    let now = time.seconds_since_startup();
+    let send = now - *last_sent >= KEEPALIVE_INTERVAL;
+    if send {
+        *last_sent = now;
+        *next_id = next_id.wrapping_add(1);
+    }
+
+    for (entity, mut client) in clients.iter_mut() {
+        if now - client.last_heard > CLIENT_TIMEOUT {
+            disconnect(&mut commands, entity, &client, "timed out");
+        } else if send {
+            client
+                .connection
+                .send(&ServerMessage::KeepAlive { id: *next_id });
+        }
+    }
+}
+
+/// Where a client's player is, as told to the other clients.
+fn player_moved(client: &RemoteClient, transform: &Transform, time: &Time) -> ServerMessage {
+    ServerMessage::PlayerMoved {
+        id: client.id,
+        time: (time.seconds_since_startup() * 1000.0) as u32,
+        position: transform.translation,
+        pitch: client.pitch,
+        yaw: client.yaw,
+    }
+}
+
+/// Tells clients about players joining and leaving. Players join once their
+/// client has said hello, and newcomers are told about everyone already there.
+pub fn announce_players(
+    time: Res<Time>,
+    mut announced: Local<HashMap<Entity, u32>>,
+    mut clients: Query<(Entity, &mut RemoteClient, &Transform)>,
+) {
+    let mut left = Vec::new();
+    announced.retain(|entity, id| {
+        let present = clients
+            .get(*entity)
+            .is_ok_and(|(_, client, _)| !client.closing);
+        if !present {
+            left.push(*id);
+        }
+        present
+    });
+
+    let players: Vec<(Entity, u32, ServerMessage, ServerMessage)> = clients
+        .iter()
+        .filter(|(_, client, _)| !client.closing)
+        .filter_map(|(entity, client, transform)| {
+            let joined = ServerMessage::PlayerJoined {
+                id: client.id,
+                name: client.name.clone()?,
+            };
+            Some((
+                entity,
+                client.id,
+                joined,
+                player_moved(client, transform, &time),
+            ))
+        })
+        .collect();
+    let newcomers: HashSet<Entity> = players
+        .iter()
+        .map(|(entity, _, _, _)| *entity)
+        .filter(|entity| !announced.contains_key(entity))
+        .collect();
+
+    if left.is_empty() && newcomers.is_empty() {
+        return;
+    }
+
+    for (entity, mut client, _) in clients.iter_mut() {
+        if client.name.is_none() || client.closing {
+            continue;
+        }
+
+        for id in &left {
+            client
+                .connection
+                .send(&ServerMessage::PlayerLeft { id: *id });
+        }
+
+        // Newcomers hear about everyone, the others only about the newcomers.
+        let is_newcomer = newcomers.contains(&entity);
+        for (player, _, joined, moved) in &players {
+            if *player != entity && (is_newcomer || newcomers.contains(player)) {
+                client.connection.send(joined);
+                client.connection.send(moved);
+            }
+        }
+    }
+
+    for (entity, id, _, _) in players {
+        if newcomers.contains(&entity) {
+            announced.insert(entity, id);
+        }
+    }
+}
+
+/// Sends every client where the other players have moved to.
+pub fn broadcast_player_movement(
+    time: Res<Time>,
+    mut clients: Query<(Entity, &mut RemoteClient, &Transform)>,
+) {
+    let mut movements = Vec::new();
+    for (entity, mut client, transform) in clients.iter_mut() {
+        if client.moved && client.name.is_some() {
+            client.moved = false;
+            movements.push((entity, player_moved(&client, transform, &time)));
+        }
+    }
+
+    if movements.is_empty() {
+        return;
+    }
+
+    for (entity, mut client, _) in clients.iter_mut() {
+        if client.name.is_none() || client.closing {
+            continue;
+        }
+
+        for (player, moved) in &movements {
+            if *player != entity {
+                client.connection.send(moved);
+            }
+        }
+    }
+}
+
+pub fn flush_clients(mut commands: Commands, mut clients: Query<(Entity, &mut RemoteClient)>) {
+    for (entity, mut client) in clients.iter_mut() {
+        match client.connection.flush() {
+            Err(error) => disconnect(&mut commands, entity, &client, error),
+            Ok(()) if client.closing && client.connection.is_flushed() => {
+                disconnect(&mut commands, entity, &client, "turned away")
+            }
+            Ok(()) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::event::Events;
+
+    use super::*;
+    use crate::chunk::{Block, CHUNK_SIZE};
+    use crate::inventory::ItemStack;
+    use crate::item::Item;
+    use crate::loopback::LoopbackClock;
+    use crate::player::EYE_HEIGHT;
+    use crate::utils::temp_dir;
+
+    const STONE: Key<'static> = Key {
+        namespace: "test",
+        name: "stone",
+    };
+    const DIRT: Key<'static> = Key {
+        namespace: "test",
+        name: "dirt",
+    };
+
+    fn registry() -> Registry<'static> {
+        let mut registry = Registry::default();
+        for key in [STONE, DIRT] {
+            registry.blocks.insert(key, Block::default());
+        }
+        registry.blocks.get_mut(&STONE).unwrap().solid = true;
+        registry.items.insert(
+            STONE,
+            Item {
+                places: Some(STONE),
+                ..default()
+            },
+        );
+        registry
+    }
+
+    /// A chunk with a stone floor at y = 0, at the origin of `world`.
+    fn spawn_floor(world: &mut bevy::ecs::world::World) {
+        let mut chunk = Chunk::new(IVec3::ZERO);
+        for x in 0..CHUNK_SIZE as i32 {
+            for z in 0..CHUNK_SIZE as i32 {
+                chunk.blocks[chunk_index(IVec3::new(x, 0, z))] = Some(STONE);
+            }
+        }
+        world.spawn().insert(chunk);
+    }
+
+    fn holding_stone() -> Inventory {
+        let mut inventory = Inventory::default();
+        inventory.insert(
+            ItemStack {
+                item: STONE,
+                count: 1,
+            },
+            64,
+        );
+        inventory
+    }
+
+    /// Where a client standing on the floor next to the edited cells sees from.
+    fn eye() -> Vec3 {
+        Vec3::new(8.5, 1.0 + EYE_HEIGHT, 6.5)
+    }
+
+    /// Whether a client at `eye` holding `inventory` may make an edit to the
+    /// floor made by [`spawn_floor`], with players standing in `bodies`.
+    fn is_valid(
+        eye: Vec3,
+        inventory: &Inventory,
+        bodies: &[BoundingBox],
+        position: IVec3,
+        block: Option<Key<'static>>,
+    ) -> bool {
+        let registry = registry();
+        let mut world = bevy::ecs::world::World::new();
+        spawn_floor(&mut world);
+
+        let block_world = BlockWorld::new(
+            world
+                .query::<&mut Chunk<'static>>()
+                .iter_mut(&mut world)
+                .map(|chunk| (chunk.position, chunk))
+                .collect(),
+            &registry,
+            0,
+        );
+        is_valid_edit(&block_world, eye, inventory, bodies, position, block)
+    }
+
+    #[test]
+    fn chunk_saves_round_trip() {
+        let position = IVec3::new(-1, 2, 3);
+        let mut chunk = Chunk::new(position);
+        chunk.blocks[..100].fill(Some(STONE));
+        chunk.blocks[CHUNK_VOLUME - 1] = Some(DIRT);
+        chunk.states[50] = 7;
+        let ticked = chunk_to_block(position, IVec3::new(4, 5, 6));
+        chunk.scheduled_ticks.push(Reverse(ScheduledTick {
+            due: 105,
+            position: ticked,
+        }));
+
+        let save = ChunkSave::from_chunk(&chunk, 100);
+        assert_eq!(save.runs.len(), 5);
+        assert_eq!(save.scheduled_ticks, vec![(5, ticked)]);
+
+        let save: ChunkSave = ron::from_str(&ron::to_string(&save).unwrap()).unwrap();
+        let loaded = save.to_chunk(position, &registry(), 20).unwrap();
+        assert_eq!(loaded.position, position);
+        assert!(loaded.blocks == chunk.blocks);
+        assert!(loaded.states == chunk.states);
+        assert_eq!(
+            loaded.scheduled_ticks.peek(),
+            Some(&Reverse(ScheduledTick {
+                due: 25,
+                position: ticked,
+            }))
+        );
+    }
+
+    #[test]
+    fn unregistered_blocks_load_as_air() {
+        let mut chunk = Chunk::new(IVec3::ZERO);
+        chunk.blocks[0] = Some(DIRT);
+        chunk.blocks[1] = Some(STONE);
+        let save = ChunkSave::from_chunk(&chunk, 0);
+
+        let mut registry = registry();
+        registry.blocks.remove(&DIRT);
+        let loaded = save.to_chunk(IVec3::ZERO, &registry, 0).unwrap();
+        assert_eq!(loaded.blocks[0], None);
+        assert_eq!(loaded.blocks[1], Some(STONE));
+    }
+
+    #[test]
+    fn malformed_chunk_saves_are_errors() {
+        let registry = registry();
+        let mut save = ChunkSave::from_chunk(&Chunk::new(IVec3::ZERO), 0);
+
+        save.runs = vec![(4000, 0, 0)];
+        assert!(save.to_chunk(IVec3::ZERO, &registry, 0).is_err());
+
+        save.runs = vec![(4000, 0, 0), (200, 0, 0)];
+        assert!(save.to_chunk(IVec3::ZERO, &registry, 0).is_err());
+
+        save.runs = vec![(CHUNK_VOLUME as u16, 3, 0)];
+        assert!(save.to_chunk(IVec3::ZERO, &registry, 0).is_err());
+
+        save.runs = vec![(CHUNK_VOLUME as u16, 0, 0)];
+        save.scheduled_ticks = vec![(1, IVec3::new(16, 0, 0))];
+        assert!(save.to_chunk(IVec3::ZERO, &registry, 0).is_err());
+    }
+
+    #[test]
+    fn the_frame_rate_and_tick_rate_are_separate() {
+        let args: Vec<String> = ["--frame-rate", "30", "--tick-rate", "40"]
+            .iter()
+            .map(|arg| arg.to_string())
+            .collect();
+        let config = ServerConfig::from_args(&args).unwrap();
+        assert_eq!(config.frame_rate, 30.0);
+        assert_eq!(config.tick_rate, 40.0);
+
+        let (_, receiver) = mpsc::channel();
+        let app = server_app(ServerSocket::Loopback(Mutex::new(receiver)), config).unwrap();
+        assert_eq!(app.world.resource::<WorldTick>().ticks_per_second, 40.0);
+    }
+
+    #[test]
+    fn edits_within_reach_are_valid() {
+        let inventory = holding_stone();
+        assert!(is_valid(
+            eye(),
+            &inventory,
+            &[],
+            IVec3::new(8, 1, 8),
+            Some(STONE)
+        ));
+        assert!(is_valid(eye(), &inventory, &[], IVec3::new(8, 0, 8), None));
+    }
+
+    #[test]
+    fn edits_out_of_reach_are_rejected() {
+        let inventory = holding_stone();
+        let eye = eye() + Vec3::Z * (REACH_DISTANCE + REACH_TOLERANCE + 2.0);
+        assert!(!is_valid(
+            eye,
+            &inventory,
+            &[],
+            IVec3::new(8, 1, 8),
+            Some(STONE)
+        ));
+        assert!(!is_valid(eye, &inventory, &[], IVec3::new(8, 0, 8), None));
+    }
+
+    #[test]
+    fn edits_in_unloaded_chunks_are_rejected() {
+        let inventory = holding_stone();
+        let eye = Vec3::new(0.5, 1.0 + EYE_HEIGHT, 8.5);
+        assert!(!is_valid(
+            eye,
+            &inventory,
+            &[],
+            IVec3::new(-1, 1, 8),
+            Some(STONE)
+        ));
+        assert!(!is_valid(eye, &inventory, &[], IVec3::new(-1, 0, 8), None));
+    }
+
+    #[test]
+    fn blocks_without_an_item_cannot_be_placed() {
+        let mut inventory = holding_stone();
+        inventory.insert(
+            ItemStack {
+                item: DIRT,
+                count: 1,
+            },
+            64,
+        );
+        assert!(!is_valid(
+            eye(),
+            &inventory,
+            &[],
+            IVec3::new(8, 1, 8),
+            Some(DIRT)
+        ));
+    }
+
+    #[test]
+    fn blocks_not_held_cannot_be_placed() {
+        let inventory = Inventory::default();
+        assert!(!is_valid(
+            eye(),
+            &inventory,
+            &[],
+            IVec3::new(8, 1, 8),
+            Some(STONE)
+        ));
+    }
+
+    #[test]
+    fn occupied_cells_cannot_be_placed_in() {
+        let inventory = holding_stone();
+        assert!(!is_valid(
+            eye(),
+            &inventory,
+            &[],
+            IVec3::new(8, 0, 8),
+            Some(STONE)
+        ));
+    }
+
+    #[test]
+    fn blocks_must_be_placed_against_another() {
+        let inventory = holding_stone();
+        assert!(!is_valid(
+            eye(),
+            &inventory,
+            &[],
+            IVec3::new(8, 2, 8),
+            Some(STONE)
+        ));
+    }
+
+    #[test]
+    fn solid_blocks_cannot_be_placed_inside_a_player() {
+        let inventory = holding_stone();
+        let body = Player::bounding_box(Vec3::new(8.5, 1.0 + EYE_HEIGHT, 8.5));
+        assert!(!is_valid(
+            eye(),
+            &inventory,
+            &[body],
+            IVec3::new(8, 1, 8),
+            Some(STONE)
+        ));
+    }
+
+    #[test]
+    fn placing_takes_the_item_from_the_inventory() {
+        let mut world = bevy::ecs::world::World::new();
+        let registry = registry();
+        world.insert_resource(RegistryIds::new(&registry));
+        world.insert_resource(registry);
+        world.insert_resource(WorldTick::default());
+        world.insert_resource(PendingBlockUpdates::default());
+        world.insert_resource(Events::<BlockChanged>::default());
+        world.insert_resource(Events::<BlockEditRequest>::default());
+        spawn_floor(&mut world);
+
+        let (stream, _) = loopback_pair(&LoopbackClock::default(), 0);
+        let client = world
+            .spawn()
+            .insert(RemoteClient {
+                connection: Connection::from_transport(stream),
+                id: 0,
+                name: Some("Placer".to_string()),
+                sent_chunks: HashSet::default(),
+                last_heard: 0.0,
+                closing: false,
+                pitch: 0.0,
+                yaw: 0.0,
+                moved: false,
+            })
+            .insert(holding_stone())
+            .insert(Transform::from_translation(eye()))
+            .id();
+
+        let mut system = IntoSystem::into_system(apply_block_edits);
+        system.initialize(&mut world);
+        let mut place = |world: &mut bevy::ecs::world::World, sequence, position| {
+            world
+                .resource_mut::<Events<BlockEditRequest>>()
+                .send(BlockEditRequest {
+                    client,
+                    sequence,
+                    position,
+                    block: Some(STONE),
+                });
+            system.run((), world);
+            system.apply_buffers(world);
+            world.resource_mut::<Events<BlockEditRequest>>().update();
+        };
+
+        let first = IVec3::new(8, 1, 8);
+        let second = IVec3::new(9, 1, 8);
+        place(&mut world, 0, first);
+        place(&mut world, 1, second);
+
+        assert_eq!(
+            world.get::<Inventory>(client).unwrap(),
+            &Inventory::default()
+        );
+        let chunk = world
+            .query::<&Chunk<'static>>()
+            .iter(&world)
+            .next()
+            .unwrap();
+        assert_eq!(chunk.block(first), Some(STONE));
+        assert_eq!(chunk.block(second), None);
+    }
+
+    #[test]
+    fn chunks_are_saved_when_unloaded_and_loaded_again() {
+        let dir = temp_dir("unload");
+        let (_, receiver) = mpsc::channel();
+        let config = ServerConfig {
+            world_dir: dir.clone(),
+            seed: Some(7),
+            ..default()
+        };
+        let mut app = server_app(ServerSocket::Loopback(Mutex::new(receiver)), config).unwrap();
+
+        let viewer = app
+            .world
+            .spawn()
+            .insert(ChunkViewer { radius: 1 })
+            .insert_bundle(TransformBundle::from_transform(Transform::from_xyz(
+                8.0, 24.0, 8.0,
+            )))
+            .id();
+        app.update();
+        app.update();
+
+        assert_eq!(app.world.resource::<World>().seed, 7);
+        assert_eq!(app.world.resource::<World>().chunks.len(), 7);
+
+        // Change a block and schedule a tick in the chunk the viewer is in.
+        let position = IVec3::new(3, 30, 3);
+        let arrow = app.world.resource::<Registry>().block_key("defaria:arrow");
+        let tick = app.world.resource::<WorldTick>().tick;
+        let entity = app.world.resource::<World>().chunks[&IVec3::Y];
+        let mut chunk = app.world.get_mut::<Chunk>(entity).unwrap();
+        chunk.blocks[chunk_index(block_to_local(position))] = arrow;
+        chunk.scheduled_ticks.push(Reverse(ScheduledTick {
+            due: tick + 1000,
+            position,
+        }));
+        app.world
+            .resource_mut::<Events<BlockChanged>>()
+            .send(BlockChanged(position));
+        app.update();
+
+        // Walk far enough away for it to be unloaded.
+        app.world.get_mut::<Transform>(viewer).unwrap().translation = Vec3::new(200.0, 24.0, 8.0);
+        app.update();
+        app.update();
+
+        assert!(!app.world.resource::<World>().chunks.contains_key(&IVec3::Y));
+        assert!(chunk_path(&dir, IVec3::Y).exists());
+        assert!(!chunk_path(&dir, IVec3::ZERO).exists());
+
+        // And back again.
+        app.world.get_mut::<Transform>(viewer).unwrap().translation = Vec3::new(8.0, 24.0, 8.0);
+        app.update();
+        app.update();
+
+        let entity = app.world.resource::<World>().chunks[&IVec3::Y];
+        let chunk = app.world.get::<Chunk>(entity).unwrap();
+        assert_eq!(chunk.block(block_to_local(position)), arrow);
+        assert_eq!(
+            chunk
+                .scheduled_ticks
+                .peek()
+                .map(|Reverse(scheduled_tick)| scheduled_tick.position),
+            Some(position)
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,605 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::VecDeque;
+
+use bevy::prelude::*;
+use bevy::utils::{HashMap, HashSet};
+
+use crate::chunk::{chunk_index, Block, Chunk, CHUNK_SIZE};
+use crate::inventory::ItemStack;
+use crate::key::Key;
+use crate::light::LightWorld;
+use crate::registry::Registry;
+use crate::utils::{block_neighbours, block_to_chunk, block_to_local, chunk_to_block, splitmix64};
+use crate::world::{World, WorldTick};
+
+/// How many blocks of every loaded chunk get a random tick each world tick.
+pub const RANDOM_TICKS_PER_CHUNK: u32 = 3;
+
+/// How many block updates can be dispatched in one world tick. Updates past this
+/// wait for the next tick, so blocks that keep changing each other cannot stall
+/// the game.
+pub const MAX_BLOCK_UPDATES_PER_TICK: usize = 4096;
+
+/// Called with the world and the position of the block being ticked.
+pub type TickHandler = fn(world: &mut BlockWorld, position: IVec3);
+
+/// Called with the world, the position of the block being updated and the
+/// position of the neighbour that changed.
+pub type UpdateHandler = fn(world: &mut BlockWorld, position: IVec3, source: IVec3);
+
+/// What a block does over time, registered next to its [`Block`].
+#[derive(Default, Copy, Clone)]
+pub struct BlockBehaviour {
+    /// Runs when a tick scheduled for the block comes due.
+    pub scheduled_tick: Option<TickHandler>,
+    /// Runs when the block is picked for one of its chunk's random ticks.
+    pub random_tick: Option<TickHandler>,
+    /// Runs when one of the six blocks sharing a face with it changes.
+    pub neighbour_update: Option<UpdateHandler>,
+}
+
+/// Sent to each of the six neighbours of a block that changed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BlockUpdate {
+    pub position: IVec3,
+    /// The block that changed.
+    pub source: IVec3,
+}
+
+/// Sent for every block that was replaced, after the tick or edit that replaced
+/// it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BlockChanged(pub IVec3);
+
+/// Block updates left over when a tick ran out of its update budget.
+#[derive(Default)]
+pub struct PendingBlockUpdates(pub VecDeque<BlockUpdate>);
+
+/// A tick waiting in a chunk's queue. Ticks are ordered by due tick, then by
+/// position, so ones due together always run in the same order.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ScheduledTick {
+    pub due: u64,
+    pub position: IVec3,
+}
+
+impl Ord for ScheduledTick {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.due, self.position.to_array()).cmp(&(other.due, other.position.to_array()))
+    }
+}
+
+impl PartialOrd for ScheduledTick {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// The loaded chunks block behaviours run against, keyed by chunk position.
+pub struct BlockWorld<'r, 'w> {
+    pub chunks: HashMap<IVec3, Mut<'w, Chunk<'static>>>,
+    pub registry: &'r Registry<'static>,
+    /// The world tick being run.
+    pub tick: u64,
+    /// The world seed, which random ticks are drawn from.
+    pub seed: u64,
+    /// Every cell replaced so far, in the order it happened.
+    changed: Vec<IVec3>,
+    /// Block updates waiting to be dispatched, oldest first.
+    updates: VecDeque<BlockUpdate>,
+    /// Block updates dispatched so far, in the order they ran.
+    dispatched: Vec<BlockUpdate>,
+    updates_this_tick: usize,
+}
+
+impl<'r, 'w> BlockWorld<'r, 'w> {
+    pub fn new(
+        chunks: HashMap<IVec3, Mut<'w, Chunk<'static>>>,
+        registry: &'r Registry<'static>,
+        tick: u64,
+    ) -> Self {
+        BlockWorld {
+            chunks,
+            registry,
+            tick,
+            seed: 0,
+            changed: Vec::new(),
+            updates: VecDeque::new(),
+            dispatched: Vec::new(),
+            updates_this_tick: 0,
+        }
+    }
+
+    pub fn is_loaded(&self, position: IVec3) -> bool {
+        self.chunks.contains_key(&block_to_chunk(position))
+    }
+
+    pub fn block(&self, position: IVec3) -> Option<Key<'static>> {
+        self.chunks
+            .get(&block_to_chunk(position))?
+            .block(block_to_local(position))
+    }
+
+    pub fn block_data(&self, position: IVec3) -> Option<&'r Block> {
+        self.registry.blocks.get(&self.block(position)?)
+    }
+
+    pub fn state(&self, position: IVec3) -> u8 {
+        self.chunks
+            .get(&block_to_chunk(position))
+            .map_or(0, |chunk| chunk.state(block_to_local(position)))
+    }
+
+    /// Replaces the block at `position`, marks every chunk that can see it for
+    /// remeshing and queues a block update for each of its neighbours. Returns
+    /// whether anything changed.
+    pub fn set_block(&mut self, position: IVec3, block: Option<Key<'static>>, state: u8) -> bool {
+        let chunk_position = block_to_chunk(position);
+        let chunk = match self.chunks.get_mut(&chunk_position) {
+            Some(chunk) => chunk,
+            None => return false,
+        };
+
+        let index = chunk_index(block_to_local(position));
+        if chunk.blocks[index] == block && chunk.states[index] == state {
+            return false;
+        }
+
+        chunk.blocks[index] = block;
+        chunk.states[index] = state;
+        chunk.has_changed = true;
+
+        for neighbour in block_neighbours(position) {
+            let neighbour_chunk = block_to_chunk(neighbour);
+
+            if neighbour_chunk != chunk_position {
+                if let Some(chunk) = self.chunks.get_mut(&neighbour_chunk) {
+                    chunk.has_changed = true;
+                }
+            }
+        }
+
+        self.changed.push(position);
+        for neighbour in block_neighbours(position) {
+            self.updates.push_back(BlockUpdate {
+                position: neighbour,
+                source: position,
+            });
+        }
+
+        true
+    }
+
+    /// Removes the block at `position` and returns what it drops.
+    pub fn break_block(&mut self, position: IVec3) -> Vec<ItemStack> {
+        match self.block(position) {
+            Some(block) if self.set_block(position, None, 0) => self.registry.drops(block),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Schedules a tick for the block at `position`, `delay` ticks from now. The
+    /// tick is kept with the chunk, so nothing is scheduled in unloaded chunks.
+    pub fn schedule(&mut self, position: IVec3, delay: u64) {
+        if let Some(chunk) = self.chunks.get_mut(&block_to_chunk(position)) {
+            chunk.scheduled_ticks.push(Reverse(ScheduledTick {
+                due: self.tick + delay.max(1),
+                position,
+            }));
+        }
+    }
+
+    fn behaviour(&self, position: IVec3) -> Option<&'r BlockBehaviour> {
+        self.registry.behaviours.get(&self.block(position)?)
+    }
+
+    /// Removes and returns the ticks due by now from every chunk, in order.
+    fn take_due_ticks(&mut self) -> Vec<IVec3> {
+        let mut due = Vec::new();
+
+        for chunk in self.chunks.values_mut() {
+            while let Some(Reverse(scheduled_tick)) = chunk.scheduled_ticks.peek() {
+                if scheduled_tick.due > self.tick {
+                    break;
+                }
+
+                due.push(*scheduled_tick);
+                chunk.scheduled_ticks.pop();
+            }
+        }
+
+        due.sort_unstable();
+
+        let mut positions: Vec<IVec3> = due
+            .into_iter()
+            .map(|scheduled_tick| scheduled_tick.position)
+            .collect();
+        let mut seen = HashSet::default();
+        positions.retain(|position| seen.insert(*position));
+
+        positions
+    }
+
+    /// Hands queued block updates to their blocks' handlers, including the ones
+    /// those handlers cause, until the queue is empty or this tick's budget is
+    /// spent.
+    fn dispatch_updates(&mut self) {
+        while self.updates_this_tick < MAX_BLOCK_UPDATES_PER_TICK {
+            let update = match self.updates.pop_front() {
+                Some(update) => update,
+                None => return,
+            };
+
+            self.updates_this_tick += 1;
+            self.dispatched.push(update);
+
+            if let Some(handler) = self
+                .behaviour(update.position)
+                .and_then(|b| b.neighbour_update)
+            {
+                handler(self, update.position, update.source);
+            }
+        }
+    }
+
+    /// Runs one world tick: updates left over from the last one, the scheduled
+    /// ticks that came due, then the random ticks of every chunk. Block updates
+    /// caused by each are dispatched straight after it.
+    pub fn run_tick(&mut self) {
+        self.updates_this_tick = 0;
+        self.dispatch_updates();
+
+        for position in self.take_due_ticks() {
+            if let Some(handler) = self.behaviour(position).and_then(|b| b.scheduled_tick) {
+                handler(self, position);
+                self.dispatch_updates();
+            }
+        }
+
+        let mut chunk_positions: Vec<IVec3> = self.chunks.keys().copied().collect();
+        chunk_positions.sort_unstable_by_key(|position| position.to_array());
+
+        for chunk_position in chunk_positions {
+            for i in 0..RANDOM_TICKS_PER_CHUNK {
+                let position = chunk_to_block(
+                    chunk_position,
+                    random_local_position(self.seed, chunk_position, self.tick, i),
+                );
+
+                if let Some(handler) = self.behaviour(position).and_then(|b| b.random_tick) {
+                    handler(self, position);
+                    self.dispatch_updates();
+                }
+            }
+        }
+    }
+
+    /// Leaves the block updates that were not dispatched for the next tick,
+    /// reports every block that changed and relights around them.
+    pub fn finish(
+        mut self,
+        pending_updates: &mut PendingBlockUpdates,
+        block_changes: &mut EventWriter<BlockChanged>,
+    ) {
+        pending_updates.0.extend(std::mem::take(&mut self.updates));
+        block_changes.send_batch(self.changed.iter().copied().map(BlockChanged));
+        self.relight();
+    }
+
+    /// Relights around every block that changed, marking the chunks whose
+    /// lighting changed for remeshing.
+    pub fn relight(self) {
+        if self.changed.is_empty() {
+            return;
+        }
+
+        let mut light_world = LightWorld::new(self.chunks, self.registry);
+        for position in self.changed {
+            light_world.update_block(position);
+        }
+
+        let changed_chunks: Vec<IVec3> = light_world.changed_chunks().iter().copied().collect();
+        for position in changed_chunks {
+            if let Some(chunk) = light_world.chunks.get_mut(&position) {
+                chunk.has_changed = true;
+            }
+        }
+    }
+}
+
+/// Picks a block inside a chunk from the world seed, chunk position, tick and draw
+/// number, so random ticks are the same every time a world is replayed.
+fn random_local_position(seed: u64, chunk_position: IVec3, tick: u64, draw: u32) -> IVec3 {
+    let mut hash = splitmix64(seed) ^ tick ^ ((draw as u64) << 48);
+    for component in chunk_position.to_array() {
+        hash = splitmix64(hash ^ component as u32 as u64);
+    }
+    hash = splitmix64(hash);
+
+    let size = CHUNK_SIZE as u64;
+    IVec3::new(
+        (hash % size) as i32,
+        (hash / size % size) as i32,
+        (hash / (size * size) % size) as i32,
+    )
+}
+
+/// Runs the world ticks that passed this frame, then sends every block update
+/// they dispatched and every block they changed as events.
+pub fn tick_blocks(
+    world: Res<World>,
+    world_tick: Res<WorldTick>,
+    registry: Res<Registry<'static>>,
+    mut pending_updates: ResMut<PendingBlockUpdates>,
+    mut block_updates: EventWriter<BlockUpdate>,
+    mut block_changes: EventWriter<BlockChanged>,
+    mut chunks: Query<&mut Chunk<'static>>,
+) {
+    if world_tick.elapsed == 0 {
+        return;
+    }
+
+    let mut block_world = BlockWorld::new(
+        chunks
+            .iter_mut()
+            .map(|chunk| (chunk.position, chunk))
+            .collect(),
+        &registry,
+        0,
+    );
+    block_world.seed = world.seed;
+    block_world.updates = std::mem::take(&mut pending_updates.0);
+
+    for tick in world_tick.ticks_this_frame() {
+        block_world.tick = tick;
+        block_world.run_tick();
+    }
+
+    block_updates.send_batch(block_world.dispatched.drain(..));
+    block_world.finish(&mut pending_updates, &mut block_changes);
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::event::Events;
+    use bevy::ecs::system::SystemState;
+
+    use super::*;
+    use crate::light::MAX_LIGHT;
+
+    const STONE: Key<'static> = Key {
+        namespace: "test",
+        name: "stone",
+    };
+
+    /// Flips its state whenever a neighbour changes, which changes its
+    /// neighbours in turn.
+    const RELAY: Key<'static> = Key {
+        namespace: "test",
+        name: "relay",
+    };
+
+    fn flip(world: &mut BlockWorld, position: IVec3, _source: IVec3) {
+        let state = world.state(position);
+        world.set_block(position, Some(RELAY), 1 - state);
+    }
+
+    fn registry() -> Registry<'static> {
+        let mut registry = Registry::default();
+        registry.blocks.insert(
+            STONE,
+            Block {
+                solid: true,
+                light_opacity: MAX_LIGHT,
+                ..default()
+            },
+        );
+        registry.blocks.insert(RELAY, Block::default());
+        registry.behaviours.insert(
+            RELAY,
+            BlockBehaviour {
+                neighbour_update: Some(flip),
+                ..default()
+            },
+        );
+        registry
+    }
+
+    #[test]
+    fn due_ticks_are_taken_in_order() {
+        let registry = registry();
+        let mut world = bevy::ecs::world::World::new();
+        world.spawn().insert(Chunk::new(IVec3::ZERO));
+        world.spawn().insert(Chunk::new(IVec3::X));
+
+        let mut block_world = BlockWorld::new(
+            world
+                .query::<&mut Chunk<'static>>()
+                .iter_mut(&mut world)
+                .map(|chunk| (chunk.position, chunk))
+                .collect(),
+            &registry,
+            10,
+        );
+        let a = IVec3::new(20, 1, 1);
+        let b = IVec3::new(2, 3, 4);
+        let c = IVec3::new(2, 3, 5);
+        let d = IVec3::new(1, 1, 1);
+        block_world.schedule(a, 3);
+        block_world.schedule(b, 2);
+        block_world.schedule(c, 2);
+        block_world.schedule(d, 5);
+        // Ticks in unloaded chunks are dropped.
+        block_world.schedule(IVec3::new(-1, 0, 0), 1);
+
+        block_world.tick = 11;
+        assert!(block_world.take_due_ticks().is_empty());
+
+        block_world.tick = 13;
+        assert_eq!(block_world.take_due_ticks(), vec![b, c, a]);
+        assert!(block_world.take_due_ticks().is_empty());
+
+        block_world.tick = 20;
+        assert_eq!(block_world.take_due_ticks(), vec![d]);
+    }
+
+    #[test]
+    fn due_ticks_run_once_per_position() {
+        let registry = registry();
+        let mut world = bevy::ecs::world::World::new();
+        world.spawn().insert(Chunk::new(IVec3::ZERO));
+
+        let mut block_world = BlockWorld::new(
+            world
+                .query::<&mut Chunk<'static>>()
+                .iter_mut(&mut world)
+                .map(|chunk| (chunk.position, chunk))
+                .collect(),
+            &registry,
+            0,
+        );
+        let a = IVec3::new(1, 2, 3);
+        let b = IVec3::new(4, 5, 6);
+        block_world.schedule(a, 2);
+        block_world.schedule(b, 1);
+        block_world.schedule(a, 1);
+        block_world.schedule(a, 1);
+        block_world.schedule(b, 4);
+
+        block_world.tick = 2;
+        assert_eq!(block_world.take_due_ticks(), vec![a, b]);
+
+        // A tick due later is kept for later, even if the position already ran.
+        block_world.tick = 4;
+        assert_eq!(block_world.take_due_ticks(), vec![b]);
+    }
+
+    #[test]
+    fn finishing_relights_changed_blocks() {
+        let registry = registry();
+        let chunk = Box::new(Chunk::new(IVec3::ZERO));
+        let mut light_world =
+            LightWorld::new(HashMap::from_iter([(IVec3::ZERO, chunk)]), &registry);
+        light_world.light_chunk(IVec3::ZERO);
+
+        let mut world = bevy::ecs::world::World::new();
+        world.init_resource::<Events<BlockChanged>>();
+        world
+            .spawn()
+            .insert(*light_world.chunks.remove(&IVec3::ZERO).unwrap());
+
+        let mut state: SystemState<(Query<&mut Chunk<'static>>, EventWriter<BlockChanged>)> =
+            SystemState::new(&mut world);
+        let (mut chunks, mut block_changes) = state.get_mut(&mut world);
+
+        let mut block_world = BlockWorld::new(
+            chunks
+                .iter_mut()
+                .map(|chunk| (chunk.position, chunk))
+                .collect(),
+            &registry,
+            0,
+        );
+        let roof = IVec3::new(5, 15, 5);
+        block_world.set_block(roof, Some(STONE), 0);
+        block_world.finish(&mut PendingBlockUpdates::default(), &mut block_changes);
+        state.apply(&mut world);
+
+        let chunk = world.query::<&Chunk>().iter(&world).next().unwrap();
+        let sky_light = |position| chunk.sky_light[chunk_index(position)];
+        assert_eq!(sky_light(roof), 0);
+        assert_eq!(sky_light(IVec3::new(5, 3, 5)), MAX_LIGHT - 1);
+        assert_eq!(sky_light(IVec3::new(6, 3, 5)), MAX_LIGHT);
+
+        let events = world.resource::<Events<BlockChanged>>();
+        assert_eq!(
+            events
+                .iter_current_update_events()
+                .copied()
+                .collect::<Vec<_>>(),
+            vec![BlockChanged(roof)]
+        );
+    }
+
+    #[test]
+    fn setting_a_block_updates_each_neighbour_once() {
+        let registry = registry();
+        let mut world = bevy::ecs::world::World::new();
+        world.spawn().insert(Chunk::new(IVec3::ZERO));
+
+        let mut block_world = BlockWorld::new(
+            world
+                .query::<&mut Chunk<'static>>()
+                .iter_mut(&mut world)
+                .map(|chunk| (chunk.position, chunk))
+                .collect(),
+            &registry,
+            0,
+        );
+        let position = IVec3::new(4, 4, 4);
+        block_world.set_block(position, Some(STONE), 0);
+        block_world.run_tick();
+
+        let mut updated: Vec<[i32; 3]> = block_world
+            .dispatched
+            .iter()
+            .map(|update| {
+                assert_eq!(update.source, position);
+                update.position.to_array()
+            })
+            .collect();
+        updated.sort_unstable();
+        assert_eq!(
+            updated,
+            [
+                [3, 4, 4],
+                [4, 3, 4],
+                [4, 4, 3],
+                [4, 4, 5],
+                [4, 5, 4],
+                [5, 4, 4]
+            ]
+        );
+    }
+
+    #[test]
+    fn chain_reactions_stop_at_the_update_budget() {
+        let registry = registry();
+        let mut world = bevy::ecs::world::World::new();
+        world.init_resource::<Events<BlockChanged>>();
+        world.spawn().insert(Chunk::new(IVec3::ZERO));
+
+        let mut state: SystemState<(Query<&mut Chunk<'static>>, EventWriter<BlockChanged>)> =
+            SystemState::new(&mut world);
+        let mut pending_updates = PendingBlockUpdates::default();
+
+        // Two relays next to each other keep flipping each other for ever.
+        for tick in 0..2 {
+            let (mut chunks, mut block_changes) = state.get_mut(&mut world);
+            let mut block_world = BlockWorld::new(
+                chunks
+                    .iter_mut()
+                    .map(|chunk| (chunk.position, chunk))
+                    .collect(),
+                &registry,
+                tick,
+            );
+            block_world.updates = std::mem::take(&mut pending_updates.0);
+            let carried_over = block_world.updates.front().copied();
+            if tick == 0 {
+                block_world.set_block(IVec3::new(4, 4, 4), Some(RELAY), 0);
+                block_world.set_block(IVec3::new(5, 4, 4), Some(RELAY), 0);
+            }
+
+            block_world.run_tick();
+            assert_eq!(block_world.dispatched.len(), MAX_BLOCK_UPDATES_PER_TICK);
+            if let Some(carried_over) = carried_over {
+                // The next tick starts with the updates the last one left.
+                assert_eq!(block_world.dispatched[0], carried_over);
+            }
+
+            block_world.finish(&mut pending_updates, &mut block_changes);
+            assert!(!pending_updates.0.is_empty());
+        }
+    }
+}
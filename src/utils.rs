@@ -9,3 +9,62 @@ pub fn world_to_chunk(position: Vec3) -> IVec3 {
         (position.z / CHUNK_SIZE as f32).floor() as i32,
     )
 }
+
+pub fn block_to_chunk(position: IVec3) -> IVec3 {
+    IVec3::new(
+        position.x.div_euclid(CHUNK_SIZE as i32),
+        position.y.div_euclid(CHUNK_SIZE as i32),
+        position.z.div_euclid(CHUNK_SIZE as i32),
+    )
+}
+
+pub fn block_to_local(position: IVec3) -> IVec3 {
+    IVec3::new(
+        position.x.rem_euclid(CHUNK_SIZE as i32),
+        position.y.rem_euclid(CHUNK_SIZE as i32),
+        position.z.rem_euclid(CHUNK_SIZE as i32),
+    )
+}
+
+/// The six blocks sharing a face with `position`.
+pub fn block_neighbours(position: IVec3) -> [IVec3; 6] {
+    [
+        position + IVec3::X,
+        position - IVec3::X,
+        position + IVec3::Y,
+        position - IVec3::Y,
+        position + IVec3::Z,
+        position - IVec3::Z,
+    ]
+}
+
+pub fn chunk_to_block(chunk: IVec3, local: IVec3) -> IVec3 {
+    chunk * CHUNK_SIZE as i32 + local
+}
+
+/// The chunk local position of a block from its index in the chunk, the inverse
+/// of `chunk_index`.
+pub fn local_position(index: u16) -> IVec3 {
+    let index = index as i32;
+    let size = CHUNK_SIZE as i32;
+
+    IVec3::new(index / (size * size), index / size % size, index % size)
+}
+
+/// Scrambles the bits of `value`, for randomness that is the same every time it
+/// is drawn from the same seed.
+pub fn splitmix64(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// A directory of its own under the system's temporary directory for the test
+/// named `test`, with anything an earlier run left there removed.
+#[cfg(test)]
+pub fn temp_dir(test: &str) -> std::path::PathBuf {
+    let dir = std::env::temp_dir().join(format!("defaria-{}-{}", test, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
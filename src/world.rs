@@ -1,173 +1,468 @@
 use bevy::{prelude::*, utils::HashMap};
 
 use crate::{
-    chunk::{Chunk, CHUNK_SIZE},
-    game::AssetHandles,
+    chunk::{chunk_index, Chunk, CHUNK_SIZE},
     key::Key,
+    utils::{block_neighbours, chunk_to_block, splitmix64},
+};
+#[cfg(feature = "render")]
+use crate::{
+    error::AssetError,
+    game::AssetHandles,
+    material::{BlockMaterial, BlockMaterialMode},
     player::Player,
-    registry::ClientRegistry,
-    render::{aggregate_mesh_fragments, build_mesh, MeshFragment},
-    utils::world_to_chunk,
+    registry::{ClientRegistry, Registry},
+    render::{
+        aggregate_mesh_fragments, build_mesh, sort_back_to_front, ChunkNeighbourhood, MeshContext,
+        MeshFragment, RenderLayer, UNKNOWN_BLOCK_MODEL,
+    },
 };
 
 #[derive(Default)]
 pub struct World {
     pub chunks: HashMap<IVec3, Entity>,
     pub max_size: IVec3,
+    /// Seeds everything random that happens in the world.
+    pub seed: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash, SystemLabel)]
+pub enum WorldSystem {
+    Tick,
+    Light,
+}
+
+pub const TICKS_PER_SECOND: f32 = 20.0;
+
+/// Ticks a single slow frame can catch up on, so a stall does not snowball.
+const MAX_TICKS_PER_FRAME: u64 = 10;
+
+/// Game time counted in fixed length ticks, so simulations run at the same speed
+/// whatever the frame rate.
+pub struct WorldTick {
+    /// The last tick that has passed.
+    pub tick: u64,
+    /// How many ticks passed during this frame.
+    pub elapsed: u64,
+    /// How many ticks pass each second, [`TICKS_PER_SECOND`] unless the server
+    /// was told otherwise.
+    pub ticks_per_second: f32,
+    accumulator: f32,
+}
+
+impl Default for WorldTick {
+    fn default() -> Self {
+        WorldTick::new(TICKS_PER_SECOND)
+    }
+}
+
+impl WorldTick {
+    pub fn new(ticks_per_second: f32) -> Self {
+        WorldTick {
+            tick: 0,
+            elapsed: 0,
+            ticks_per_second,
+            accumulator: 0.0,
+        }
+    }
+
+    /// The ticks that passed during this frame, in order.
+    pub fn ticks_this_frame(&self) -> impl Iterator<Item = u64> {
+        (self.tick + 1 - self.elapsed)..=self.tick
+    }
+}
+
+pub fn advance_world_tick(time: Res<Time>, mut world_tick: ResMut<WorldTick>) {
+    let tick_length = 1.0 / world_tick.ticks_per_second;
+
+    world_tick.accumulator += time.delta_seconds();
+    world_tick.elapsed = 0;
+
+    while world_tick.accumulator >= tick_length {
+        world_tick.accumulator -= tick_length;
+
+        if world_tick.elapsed < MAX_TICKS_PER_FRAME {
+            world_tick.elapsed += 1;
+        }
+    }
+
+    world_tick.tick += world_tick.elapsed;
 }
 
 #[derive(Default)]
 pub struct ChunkGenerator {
+    /// The furthest out, in chunks, that chunks are loaded around a viewer.
     pub radius: u32,
 }
 
+/// Has the chunks around it loaded or generated, out to `radius` chunks or the
+/// [`ChunkGenerator`] radius, whichever is smaller. Chunks a chunk further out
+/// than that from every viewer are unloaded.
+#[derive(Component, Debug, Copy, Clone)]
+pub struct ChunkViewer {
+    pub radius: u32,
+}
+
+/// The mesh entities drawing each render layer of a chunk, parented to the chunk.
+#[cfg(feature = "render")]
+#[derive(Component, Default, Clone)]
+pub struct ChunkMeshes {
+    pub layers: HashMap<RenderLayer, (Entity, Handle<Mesh>)>,
+}
+
+/// Marks an entity drawing one render layer of a chunk.
+#[cfg(feature = "render")]
+#[derive(Component)]
+pub struct ChunkLayer(pub RenderLayer);
+
+#[cfg(feature = "render")]
+#[allow(clippy::too_many_arguments)]
 pub fn build_chunks(
     mut commands: Commands,
     world: Res<World>,
+    registry: Res<Registry<'static>>,
     client_registry: Res<ClientRegistry<'static>>,
     asset_handles: Res<AssetHandles>,
-    asset_server: Res<AssetServer>,
+    material_mode: Res<BlockMaterialMode>,
     texture_atlases: ResMut<Assets<TextureAtlas>>,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
-    mut chunks: Query<(Entity, &mut Chunk<'static>, Option<&Handle<Mesh>>)>,
+    mut block_materials: ResMut<Assets<BlockMaterial>>,
+    players: Query<&Transform, With<Player>>,
+    mut error_events: EventWriter<AssetError>,
+    mut chunks: Query<(Entity, &mut Chunk<'static>, Option<&ChunkMeshes>)>,
 ) {
-    let texture_atlas = texture_atlases
-        .get(asset_handles.block_texture_atlas.clone())
-        .unwrap();
-
-    for (entity, mut chunk, mesh_handle) in chunks.iter_mut() {
-        if !chunk.has_changed {
-            continue;
+    let texture_atlas = match texture_atlases.get(asset_handles.block_texture_atlas.clone()) {
+        Some(texture_atlas) => texture_atlas,
+        None => {
+            error_events.send(AssetError::NoAtlas);
+            return;
         }
+    };
+
+    let eye = players
+        .iter()
+        .next()
+        .map_or(Vec3::ZERO, |transform| transform.translation);
+
+    let changed_chunks: Vec<Entity> = chunks
+        .iter()
+        .filter(|(_, chunk, _)| chunk.has_changed)
+        .map(|(entity, _, _)| entity)
+        .collect();
+
+    for entity in changed_chunks {
+        let (_, chunk, chunk_meshes) = chunks.get(entity).unwrap();
+        let chunk_position = chunk.position;
+        let mut chunk_meshes = chunk_meshes.cloned().unwrap_or_default();
 
-        let mut mesh_fragments: Vec<MeshFragment> = vec![];
+        let neighbourhood = ChunkNeighbourhood::new(chunk_position, |position| {
+            let entity = world.chunks.get(&position)?;
+            chunks.get(*entity).ok().map(|(_, chunk, _)| chunk)
+        });
+        let context = MeshContext {
+            registry: &registry,
+            client_registry: &client_registry,
+            neighbourhood: &neighbourhood,
+        };
+
+        let mut mesh_fragments: HashMap<RenderLayer, Vec<MeshFragment>> = HashMap::default();
 
         for x in 0..CHUNK_SIZE {
             for y in 0..CHUNK_SIZE {
                 for z in 0..CHUNK_SIZE {
-                    let i = x * CHUNK_SIZE * CHUNK_SIZE + y * CHUNK_SIZE + z;
+                    let position = IVec3::new(x as i32, y as i32, z as i32);
 
-                    if let Some(block) = chunk.blocks[i] {
+                    if let Some(block) = neighbourhood.block(position) {
+                        let appearance = match client_registry.appearances.get(&block) {
+                            Some(appearance) => appearance,
+                            None => {
+                                error_events.send(AssetError::UnregisteredBlock { block });
+                                continue;
+                            }
+                        };
                         let block_model = client_registry
                             .block_models
                             .get(&block)
-                            .expect("Unregistered block model.");
+                            .unwrap_or(&UNKNOWN_BLOCK_MODEL);
 
-                        let vendor_handle = asset_server.get_handle(block_model.texture);
-                        let vendor_index = texture_atlas.get_texture_index(&vendor_handle).unwrap();
-                        let texture_position = texture_atlas.textures[vendor_index];
-
-                        let texture = Rect {
-                            top: texture_position.min.y / texture_atlas.size.y,
-                            left: texture_position.min.x / texture_atlas.size.x,
-                            right: texture_position.max.x / texture_atlas.size.x,
-                            bottom: texture_position.max.y / texture_atlas.size.y,
-                        };
-
-                        mesh_fragments.push((block_model.generate_mesh)(
-                            &world,
-                            IVec3::new(x as i32, y as i32, z as i32),
-                            texture,
-                        ));
+                        mesh_fragments
+                            .entry(appearance.layer)
+                            .or_default()
+                            .push((block_model.generate_mesh)(
+                                &context,
+                                position,
+                                appearance.texture,
+                            ));
                     }
                 }
             }
         }
 
-        let new_mesh = build_mesh(aggregate_mesh_fragments(mesh_fragments));
+        let origin = chunk_position.as_vec3() * CHUNK_SIZE as f32;
+
+        for layer in RenderLayer::ALL {
+            let mesh_fragment =
+                aggregate_mesh_fragments(mesh_fragments.remove(&layer).unwrap_or_default());
+
+            if mesh_fragment.indices.is_empty() {
+                if let Some((layer_entity, _)) = chunk_meshes.layers.remove(&layer) {
+                    commands.entity(layer_entity).despawn();
+                }
+                continue;
+            }
 
-        if let Some(mesh_handle) = mesh_handle {
-            if let Some(mesh) = meshes.get_mut(mesh_handle) {
-                *mesh = new_mesh;
+            let mut new_mesh = build_mesh(mesh_fragment);
+            if layer == RenderLayer::Translucent {
+                sort_back_to_front(&mut new_mesh, eye - origin);
             }
-        } else {
-            commands.entity(entity).insert_bundle(PbrBundle {
-                mesh: meshes.add(new_mesh),
-                material: materials.add(StandardMaterial {
-                    base_color_texture: Some(texture_atlas.texture.clone()),
-                    ..default()
-                }),
-                transform: Transform::from_xyz(
-                    chunk.position.x as f32 * CHUNK_SIZE as f32,
-                    chunk.position.y as f32 * CHUNK_SIZE as f32,
-                    chunk.position.z as f32 * CHUNK_SIZE as f32,
-                ),
-                ..default()
-            });
+
+            if let Some((_, mesh_handle)) = chunk_meshes.layers.get(&layer) {
+                if let Some(mesh) = meshes.get_mut(mesh_handle) {
+                    *mesh = new_mesh;
+                }
+                continue;
+            }
+
+            let mesh_handle = meshes.add(new_mesh);
+            let layer_entity = match *material_mode {
+                BlockMaterialMode::Atlas => commands
+                    .spawn_bundle(PbrBundle {
+                        mesh: mesh_handle.clone(),
+                        material: materials.add(StandardMaterial {
+                            base_color_texture: Some(texture_atlas.texture.clone()),
+                            alpha_mode: layer.alpha_mode(),
+                            ..default()
+                        }),
+                        ..default()
+                    })
+                    .insert(ChunkLayer(layer))
+                    .id(),
+                BlockMaterialMode::TextureArray => commands
+                    .spawn_bundle(MaterialMeshBundle {
+                        mesh: mesh_handle.clone(),
+                        material: block_materials.add(BlockMaterial {
+                            texture_array: asset_handles.block_texture_array.clone(),
+                            alpha_mode: layer.alpha_mode(),
+                        }),
+                        ..default()
+                    })
+                    .insert(ChunkLayer(layer))
+                    .id(),
+            };
+
+            commands.entity(entity).add_child(layer_entity);
+            chunk_meshes
+                .layers
+                .insert(layer, (layer_entity, mesh_handle));
         }
 
-        chunk.has_changed = false;
+        commands.entity(entity).insert(chunk_meshes);
+
+        if let Ok((_, mut chunk, _)) = chunks.get_mut(entity) {
+            chunk.has_changed = false;
+        }
     }
 }
 
-pub fn generate_chunks(
-    mut commands: Commands,
-    mut world: ResMut<World>,
-    chunk_generator: Res<ChunkGenerator>,
+/// Keeps translucent chunk layers sorted back to front as the player moves between
+/// blocks.
+#[cfg(feature = "render")]
+pub fn sort_translucent_chunks(
+    mut last_eye: Local<Option<IVec3>>,
     players: Query<&Transform, With<Player>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    layers: Query<(&ChunkLayer, &Handle<Mesh>, &GlobalTransform)>,
 ) {
-    for player_transform in players.iter() {
-        let chunk_position = world_to_chunk(player_transform.translation);
+    let eye = match players.iter().next() {
+        Some(transform) => transform.translation,
+        None => return,
+    };
 
-        let radius = chunk_generator.radius as i32 - 1;
+    let eye_block = eye.floor().as_ivec3();
+    if *last_eye == Some(eye_block) {
+        return;
+    }
+    *last_eye = Some(eye_block);
 
-        for x in (chunk_position.x - radius)..(chunk_position.x + radius + 1) {
-            for y in (chunk_position.y - radius)..(chunk_position.y + radius + 1) {
-                for z in (chunk_position.z - radius)..(chunk_position.z + radius + 1) {
-                    let position = IVec3::new(x, y, z);
+    for (layer, mesh_handle, transform) in layers.iter() {
+        if layer.0 != RenderLayer::Translucent {
+            continue;
+        }
 
-                    if world.chunks.contains_key(&position) {
-                        continue;
-                    }
+        if let Some(mesh) = meshes.get_mut(mesh_handle) {
+            sort_back_to_front(mesh, eye - transform.translation);
+        }
+    }
+}
 
-                    let distance = (((x - chunk_position.x).pow(2)
-                        + (y - chunk_position.y).pow(2)
-                        + (z - chunk_position.z).pow(2))
-                        as f32)
-                        .sqrt();
+/// Remeshes the chunks around newly generated ones, since faces on their borders
+/// may now be hidden.
+pub fn remesh_chunk_neighbours(world: Res<World>, mut chunks: Query<&mut Chunk<'static>>) {
+    let added: Vec<IVec3> = chunks
+        .iter_mut()
+        .filter(|chunk| chunk.is_added())
+        .map(|chunk| chunk.position)
+        .collect();
 
-                    if distance > radius as f32 {
-                        continue;
-                    }
+    for position in added {
+        for neighbour in block_neighbours(position) {
+            if let Some(entity) = world.chunks.get(&neighbour) {
+                if let Ok(mut chunk) = chunks.get_mut(*entity) {
+                    chunk.has_changed = true;
+                }
+            }
+        }
+    }
+}
 
-                    let mut chunk = Chunk {
-                        position,
-                        blocks: [None; CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE],
-                        has_changed: true,
-                    };
-
-                    for x in 0..CHUNK_SIZE {
-                        for y in 0..CHUNK_SIZE {
-                            for z in 0..CHUNK_SIZE {
-                                let world_position = IVec3::new(
-                                    position.x * CHUNK_SIZE as i32 + x as i32,
-                                    position.y * CHUNK_SIZE as i32 + y as i32,
-                                    position.z * CHUNK_SIZE as i32 + z as i32,
-                                );
-
-                                if world_position.y < 16 {
-                                    chunk.blocks[x * 256 + y * 16 + z] = Some(Key {
-                                        namespace: "defaria",
-                                        name: "arrow",
-                                    });
-                                }
-                            }
-                        }
-                    }
+/// The height the ground rolls around.
+const GROUND_LEVEL: i32 = 16;
+/// How far the ground reaches above and below [`GROUND_LEVEL`].
+const HILL_HEIGHT: f32 = 6.0;
+/// No ground is higher than this, so anything placed above it starts out in the
+/// open.
+pub const HIGHEST_SURFACE: i32 = GROUND_LEVEL + HILL_HEIGHT as i32;
+/// Blocks between hilltops, give or take.
+const HILL_SPACING: i32 = 32;
+
+/// A random height from `-1.0` to `1.0` for the point `x`, `z` of the grid the
+/// hills are laid out on.
+fn hill(seed: u64, x: i32, z: i32) -> f32 {
+    let hash = splitmix64(splitmix64(seed ^ x as u32 as u64) ^ ((z as u32 as u64) << 32));
+    (hash >> 40) as f32 / (1 << 23) as f32 - 1.0
+}
+
+/// The height of the first block above the ground at `x`, `z` in the world
+/// with `seed`.
+pub fn surface_height(seed: u64, x: i32, z: i32) -> i32 {
+    let (cell_x, cell_z) = (x.div_euclid(HILL_SPACING), z.div_euclid(HILL_SPACING));
+    let smooth = |t: f32| t * t * (3.0 - 2.0 * t);
+    let tx = smooth(x.rem_euclid(HILL_SPACING) as f32 / HILL_SPACING as f32);
+    let tz = smooth(z.rem_euclid(HILL_SPACING) as f32 / HILL_SPACING as f32);
+
+    let near = hill(seed, cell_x, cell_z) * (1.0 - tx) + hill(seed, cell_x + 1, cell_z) * tx;
+    let far = hill(seed, cell_x, cell_z + 1) * (1.0 - tx) + hill(seed, cell_x + 1, cell_z + 1) * tx;
+
+    GROUND_LEVEL + ((near * (1.0 - tz) + far * tz) * HILL_HEIGHT).round() as i32
+}
+
+/// Generates the chunk at `position` of the world with `seed`.
+pub fn generate_chunk(seed: u64, position: IVec3) -> Chunk<'static> {
+    let mut chunk = Chunk::new(position);
+
+    for x in 0..CHUNK_SIZE as i32 {
+        for z in 0..CHUNK_SIZE as i32 {
+            let column = chunk_to_block(position, IVec3::new(x, 0, z));
+            let surface = surface_height(seed, column.x, column.z);
+
+            for y in 0..CHUNK_SIZE as i32 {
+                if column.y + y < surface {
+                    chunk.blocks[chunk_index(IVec3::new(x, y, z))] = Some(Key {
+                        namespace: "defaria",
+                        name: "arrow",
+                    });
+                }
+            }
+        }
+    }
+
+    chunk
+}
+
+/// The chunks within `radius` chunks of `centre`, nearest first.
+pub fn chunks_in_view(centre: IVec3, radius: u32) -> Vec<IVec3> {
+    let radius = radius as i32;
+    let mut positions = Vec::new();
 
-                    let entity = commands.spawn().insert(chunk).id();
+    for x in -radius..=radius {
+        for y in -radius..=radius {
+            for z in -radius..=radius {
+                let offset = IVec3::new(x, y, z);
 
-                    world.chunks.insert(position, entity);
+                if offset.dot(offset) <= radius * radius {
+                    positions.push(centre + offset);
                 }
             }
         }
     }
+    positions.sort_by_key(|position| (*position - centre).dot(*position - centre));
+
+    positions
 }
 
+/// Spawns the entity holding `chunk` and adds it to the world.
+pub fn spawn_chunk(commands: &mut Commands, world: &mut World, chunk: Chunk<'static>) -> Entity {
+    let position = chunk.position;
+    let entity = commands
+        .spawn()
+        .insert(chunk)
+        .insert_bundle(TransformBundle::from_transform(
+            Transform::from_translation(position.as_vec3() * CHUNK_SIZE as f32),
+        ))
+        .id();
+
+    world.chunks.insert(position, entity);
+
+    entity
+}
+
+#[cfg(feature = "render")]
 pub fn create_world(mut commands: Commands) {
     commands.insert_resource(AmbientLight {
         color: Color::WHITE,
         brightness: 0.7,
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks_in_view_fill_a_sphere() {
+        assert_eq!(chunks_in_view(IVec3::ZERO, 0), vec![IVec3::ZERO]);
+        assert_eq!(chunks_in_view(IVec3::ZERO, 1).len(), 7);
+
+        let centre = IVec3::new(5, -3, 2);
+        let chunks = chunks_in_view(centre, 2);
+        assert_eq!(chunks.len(), 33);
+        assert_eq!(chunks[0], centre);
+        assert!(chunks.contains(&(centre + IVec3::new(0, -2, 0))));
+        assert!(chunks.contains(&(centre + IVec3::new(1, 1, 1))));
+        assert!(!chunks.contains(&(centre + IVec3::new(2, 1, 0))));
+    }
+
+    #[test]
+    fn terrain_comes_from_the_seed() {
+        let heights = |seed| {
+            (0..64)
+                .map(|x| surface_height(seed, x * 5, x * 3))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(heights(1), heights(1));
+        assert_ne!(heights(1), heights(2));
+        assert!(heights(1)
+            .iter()
+            .all(|height| (GROUND_LEVEL - 6..=HIGHEST_SURFACE).contains(height)));
+    }
+
+    #[test]
+    fn chunks_are_solid_below_the_surface() {
+        let position = IVec3::new(2, 1, -1);
+        let chunk = generate_chunk(3, position);
+
+        for x in 0..CHUNK_SIZE as i32 {
+            for z in 0..CHUNK_SIZE as i32 {
+                let column = chunk_to_block(position, IVec3::new(x, 0, z));
+                let surface = surface_height(3, column.x, column.z);
+
+                for y in 0..CHUNK_SIZE as i32 {
+                    let block = chunk.block(IVec3::new(x, y, z));
+                    assert_eq!(block.is_some(), column.y + y < surface);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,97 @@
+use std::cmp::Reverse;
+use std::thread;
+use std::time::Duration;
+
+use bevy::prelude::*;
+use defaria::chunk::{chunk_index, Chunk, CHUNK_SIZE};
+use defaria::plugins::CorePlugin;
+use defaria::registry::Registry;
+use defaria::tick::ScheduledTick;
+use defaria::world::{World, WorldTick};
+
+fn app(simulate: bool) -> App {
+    let mut app = App::new();
+    app.add_plugins(MinimalPlugins).add_plugin(CorePlugin {
+        builtin_content: true,
+        simulate,
+    });
+    app
+}
+
+#[test]
+fn startup_registers_the_builtin_content() {
+    let mut app = app(false);
+    app.update();
+
+    let registry = app.world.resource::<Registry<'static>>();
+    assert!(registry.contributions.errors.is_empty());
+    for name in ["arrow", "lamp", "glass", "leaves", "water", "lava"] {
+        let key = registry.block_key(&format!("defaria:{}", name));
+        assert!(key.is_some(), "no block {}", name);
+    }
+    for name in ["arrow", "lamp", "glass", "leaves"] {
+        let key = registry.item_key(&format!("defaria:{}", name));
+        assert!(key.is_some(), "no item {}", name);
+    }
+    // Fluids cannot be placed, so they get no item.
+    assert!(registry.item_key("defaria:water").is_none());
+    // Glass breaks into nothing, where other blocks drop themselves.
+    let glass = registry.block_key("defaria:glass").unwrap();
+    assert!(registry.drops(glass).is_empty());
+    let lamp = registry.block_key("defaria:lamp").unwrap();
+    assert_eq!(registry.drops(lamp).len(), 1);
+
+    let world = app.world.resource::<World>();
+    assert!(world.chunks.is_empty());
+    assert!(app.world.get_resource::<WorldTick>().is_none());
+}
+
+#[test]
+fn simulated_worlds_run_block_ticks() {
+    let mut app = app(true);
+    app.update();
+
+    let (arrow, water) = {
+        let registry = app.world.resource::<Registry<'static>>();
+        (
+            registry.block_key("defaria:arrow").unwrap(),
+            registry.block_key("defaria:water").unwrap(),
+        )
+    };
+
+    // A floor with a spring on it, due to start flowing on the first tick.
+    let spring = IVec3::new(8, 1, 8);
+    let mut chunk = Chunk::new(IVec3::ZERO);
+    for x in 0..CHUNK_SIZE as i32 {
+        for z in 0..CHUNK_SIZE as i32 {
+            chunk.blocks[chunk_index(IVec3::new(x, 0, z))] = Some(arrow);
+        }
+    }
+    chunk.blocks[chunk_index(spring)] = Some(water);
+    chunk.scheduled_ticks.push(Reverse(ScheduledTick {
+        due: 1,
+        position: spring,
+    }));
+    let entity = app.world.spawn().insert(chunk).id();
+    app.world
+        .resource_mut::<World>()
+        .chunks
+        .insert(IVec3::ZERO, entity);
+
+    // Ticks follow the clock, so let real time pass between frames.
+    for _ in 0..100 {
+        if app.world.resource::<WorldTick>().tick >= 2 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+        app.update();
+    }
+    assert!(app.world.resource::<WorldTick>().tick >= 2);
+
+    let chunk = app.world.get::<Chunk<'static>>(entity).unwrap();
+    for neighbour in [IVec3::X, -IVec3::X, IVec3::Z, -IVec3::Z] {
+        let index = chunk_index(spring + neighbour);
+        assert_eq!(chunk.blocks[index], Some(water));
+        assert_eq!(chunk.states[index], 1);
+    }
+}